@@ -0,0 +1,464 @@
+use crate::bridge::Bridge;
+use crate::color::kelvin_to_mired;
+use crate::lightstructs::*;
+use crate::scheduler::{solar_elevation, solar_time, SolarEvent};
+use crate::watcher::{Change, Kind, Watcher};
+use chrono::{DateTime, Utc};
+use serde_json::value::Value;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// How far below the horizon (in degrees) the sun has to be for the lights to be
+/// at their warmest and dimmest
+const NIGHT: f64 = -6.0;
+
+/// How much a light's reported values may differ from what was sent before it
+/// counts as someone changing it by hand
+const TOLERANCE: i64 = 2;
+
+/// Colour temperature and brightness over the day, following the sun: warmest and
+/// dimmest from dusk to dawn, coolest and brightest at solar noon.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Curve {
+    /// Where to follow the sun, in degrees (north and east are positive)
+    pub latitude: f64,
+    pub longitude: f64,
+    pub warmest: u32,
+    pub coolest: u32,
+    /// Brightness range in percent
+    pub dimmest: u8,
+    pub brightest: u8,
+}
+
+impl Curve {
+    /// A curve from 2200K at 40% at night to 5500K at full brightness at noon
+    pub fn new(latitude: f64, longitude: f64) -> Self {
+        Curve {
+            latitude,
+            longitude,
+            warmest: 2200,
+            coolest: 5500,
+            dimmest: 40,
+            brightest: 100,
+        }
+    }
+
+    /// How far through the day the sun is at `time`, from 0 at night to 1 at noon
+    pub fn progress(&self, time: DateTime<Utc>) -> f64 {
+        let elevation = solar_elevation(time, self.latitude, self.longitude);
+        let highest = solar_time(
+            SolarEvent::Noon,
+            time.date_naive(),
+            self.latitude,
+            self.longitude,
+        )
+        .map_or(NIGHT, |noon| {
+            solar_elevation(noon, self.latitude, self.longitude)
+        });
+        if highest <= NIGHT {
+            return 0.0;
+        }
+        let t = ((elevation - NIGHT) / (highest - NIGHT)).clamp(0.0, 1.0);
+        // ease in and out so the changes around dawn and dusk are gentle
+        t * t * (3.0 - 2.0 * t)
+    }
+
+    /// Colour temperature (in Kelvin) and brightness (in percent) at `time`
+    pub fn at(&self, time: DateTime<Utc>) -> (u32, u8) {
+        let t = self.progress(time);
+        let kelvin =
+            f64::from(self.warmest) + (f64::from(self.coolest) - f64::from(self.warmest)) * t;
+        let brightness =
+            f64::from(self.dimmest) + (f64::from(self.brightest) - f64::from(self.dimmest)) * t;
+        (kelvin.round() as u32, brightness.round() as u8)
+    }
+
+    /// The state to send to a light at `time`, fading in over `transition`
+    pub fn state(&self, time: DateTime<Utc>, transition: Duration) -> SendableState {
+        let (kelvin, brightness) = self.at(time);
+        let bri = (u32::from(brightness.min(100)) * 254 / 100).max(1) as u8;
+        let transition = (transition.as_millis() / 100).min(u128::from(u16::MAX)) as u16;
+        state!(ct: kelvin_to_mired(kelvin), bri: bri, transitiontime: transition)
+    }
+}
+
+/// Keeps lights that are on following a `Curve`. Lights someone changes by hand are
+/// left alone until they are turned off or `backoff` has passed.
+///
+/// ```no_run
+/// # use huemanity::bridge::Bridge;
+/// # use huemanity::adaptive::*;
+/// let bridge = Bridge::link();
+/// let mut adaptive = Adaptive::new(&bridge, Curve::new(51.48, -0.01), vec![1, 2]);
+/// adaptive.run(|light, change| println!("light {}: {}", light, change)).unwrap();
+/// ```
+pub struct Adaptive<'a> {
+    bridge: &'a Bridge,
+    pub curve: Curve,
+    lights: Vec<u8>,
+    /// How often the lights get nudged along the curve
+    pub interval: Duration,
+    /// How long each nudge takes, long enough for the change to go unnoticed
+    pub transition: Duration,
+    /// How long to leave a light alone after it was changed by hand
+    pub backoff: Duration,
+    watcher: Watcher<'a>,
+    /// What was last sent to each light, to tell it apart from changes by hand
+    sent: BTreeMap<u8, SendableState>,
+    /// Lights changed by hand and when they were
+    manual: BTreeMap<u8, Instant>,
+}
+
+impl<'a> Adaptive<'a> {
+    pub fn new(bridge: &'a Bridge, curve: Curve, lights: Vec<u8>) -> Self {
+        let mut watcher = Watcher::new(bridge);
+        watcher.interval = Duration::from_secs(2);
+        Adaptive {
+            bridge,
+            curve,
+            lights,
+            interval: Duration::from_secs(60),
+            transition: Duration::from_secs(30),
+            backoff: Duration::from_secs(3600),
+            watcher,
+            sent: BTreeMap::new(),
+            manual: BTreeMap::new(),
+        }
+    }
+
+    /// Whether the light is being left alone after a change by hand
+    pub fn is_backed_off(&self, light: u8) -> bool {
+        self.manual
+            .get(&light)
+            .is_some_and(|since| since.elapsed() < self.backoff)
+    }
+
+    /// Sends every light that is on (and not backed off) where it should be now,
+    /// unless it is there already
+    pub fn nudge(&mut self) -> Result<(), Box<dyn Error>> {
+        let lights: BTreeMap<u8, Value> = self.bridge.fetch("lights")?;
+        let target = self.curve.state(Utc::now(), self.transition);
+        for light in self.lights.clone() {
+            let state = match lights.get(&light) {
+                Some(light) => &light["state"],
+                None => continue,
+            };
+            if state["on"] != Value::Bool(true) || self.is_backed_off(light) {
+                continue;
+            }
+            self.send(light, state, target.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Handles a change the watcher saw, returns whether it was made by hand
+    fn observe(&mut self, change: &Change) -> Result<bool, Box<dyn Error>> {
+        let (id, field, old, new) = match change {
+            Change::Field {
+                kind: Kind::Light,
+                id,
+                field,
+                old,
+                new,
+            } if self.lights.contains(id) => (*id, field.as_str(), old, new),
+            _ => return Ok(false),
+        };
+        match (field, new) {
+            // turning a light off hands it back, turning it on gets it to the curve quickly
+            ("state.on", Value::Bool(false)) => {
+                self.manual.remove(&id);
+                self.sent.remove(&id);
+                Ok(false)
+            }
+            ("state.on", Value::Bool(true)) if !self.is_backed_off(id) => {
+                let target = self.curve.state(Utc::now(), Duration::from_millis(400));
+                let state = self.bridge.fetch::<Value>(&format!("lights/{}", id))?;
+                self.send(id, &state["state"], target)?;
+                Ok(false)
+            }
+            // the bridge works out a colour for every colour temperature it is sent, so
+            // only a light leaving ct mode shows that someone picked a colour
+            ("state.xy", _) | ("state.hue", _) | ("state.sat", _) if self.sent_ct(id) => Ok(false),
+            ("state.colormode", mode) if self.sent_ct(id) => Ok(mode != "ct" && self.back_off(id)),
+            ("state.bri", _)
+            | ("state.ct", _)
+            | ("state.xy", _)
+            | ("state.hue", _)
+            | ("state.sat", _) => {
+                let expected = self.sent.get(&id).and_then(|sent| match field {
+                    "state.bri" => sent.bri.map(i64::from),
+                    "state.ct" => sent.ct.map(i64::from),
+                    _ => None,
+                });
+                // during a transition the light reports values on the way to the target
+                let ours = match (expected, old.as_i64(), new.as_i64()) {
+                    (Some(expected), Some(old), Some(new)) => {
+                        (expected - new).abs() <= TOLERANCE
+                            || (old.min(expected)..=old.max(expected)).contains(&new)
+                    }
+                    _ => false,
+                };
+                Ok(!ours && self.back_off(id))
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Whether the last thing sent to the light was a colour temperature
+    fn sent_ct(&self, light: u8) -> bool {
+        self.sent.get(&light).is_some_and(|sent| sent.ct.is_some())
+    }
+
+    /// Leaves a light changed by hand alone, returns false if it already was
+    fn back_off(&mut self, light: u8) -> bool {
+        if self.is_backed_off(light) {
+            return false;
+        }
+        self.manual.insert(light, Instant::now());
+        true
+    }
+
+    fn send(
+        &mut self,
+        light: u8,
+        current: &Value,
+        mut target: SendableState,
+    ) -> Result<(), Box<dyn Error>> {
+        // lights that can't change colour temperature only get dimmed
+        if current.get("ct").is_none() {
+            target.ct = None;
+        }
+        let close = |field: &str, wanted: Option<i64>| match (current[field].as_i64(), wanted) {
+            (Some(now), Some(wanted)) => (now - wanted).abs() <= TOLERANCE,
+            (_, None) => true,
+            _ => false,
+        };
+        let in_ct_mode = target.ct.is_none() || current["colormode"] == "ct";
+        if in_ct_mode
+            && close("bri", target.bri.map(i64::from))
+            && close("ct", target.ct.map(i64::from))
+        {
+            return Ok(());
+        }
+        self.bridge.state(light, &target)?;
+        self.sent.insert(light, target);
+        Ok(())
+    }
+
+    /// Keeps the lights on the curve, telling `on_manual` about every light that
+    /// gets backed off. Only returns if the bridge stops answering.
+    pub fn run<F: FnMut(u8, &Change)>(&mut self, mut on_manual: F) -> Result<(), Box<dyn Error>> {
+        let mut failures = 0;
+        let mut nudged: Option<Instant> = None;
+        loop {
+            let result = self.watcher.poll().and_then(|changes| {
+                // lights come back on with their power-on state, which isn't a change by hand
+                let switched = switched_on(&changes);
+                for change in changes {
+                    if let Change::Field {
+                        kind: Kind::Light,
+                        id,
+                        field,
+                        ..
+                    } = &change
+                    {
+                        if field != "state.on" && switched.contains(id) {
+                            continue;
+                        }
+                    }
+                    if self.observe(&change)? {
+                        if let Change::Field { id, .. } = &change {
+                            on_manual(*id, &change);
+                        }
+                    }
+                }
+                if nudged.is_none_or(|at| at.elapsed() >= self.interval) {
+                    self.nudge()?;
+                    nudged = Some(Instant::now());
+                }
+                Ok(())
+            });
+            match result {
+                Ok(()) => failures = 0,
+                Err(e) => {
+                    failures += 1;
+                    if failures >= self.watcher.max_failures {
+                        return Err(e);
+                    }
+                }
+            }
+            sleep(self.watcher.interval);
+        }
+    }
+}
+
+/// The lights that were turned on in a poll
+fn switched_on(changes: &[Change]) -> Vec<u8> {
+    changes
+        .iter()
+        .filter_map(|change| match change {
+            Change::Field {
+                kind: Kind::Light,
+                id,
+                field,
+                new: Value::Bool(true),
+                ..
+            } if field == "state.on" => Some(*id),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::Topology;
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    fn at(month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, month, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn follows_the_sun() {
+        let greenwich = Curve::new(51.48, -0.01);
+        assert!(greenwich.progress(at(6, 21, 12, 2)) > 0.99);
+        assert_eq!(greenwich.progress(at(6, 21, 0, 0)), 0.0);
+        let morning = greenwich.progress(at(6, 21, 7, 0));
+        assert!(morning > 0.0 && morning < 1.0);
+
+        assert_eq!(greenwich.at(at(6, 21, 12, 2)), (5500, 100));
+        assert_eq!(greenwich.at(at(6, 21, 0, 0)), (2200, 40));
+        let (kelvin, brightness) = greenwich.at(at(6, 21, 7, 0));
+        assert!(kelvin > 2200 && kelvin < 5500);
+        assert!(brightness > 40 && brightness < 100);
+
+        // the sun doesn't come up in Tromsø in December
+        let tromso = Curve::new(69.65, 18.96);
+        assert_eq!(tromso.progress(at(12, 21, 11, 0)), 0.0);
+
+        let state = greenwich.state(at(6, 21, 0, 0), Duration::from_secs(30));
+        assert_eq!(state.ct, Some(kelvin_to_mired(2200)));
+        assert_eq!(state.bri, Some(101));
+        assert_eq!(state.transitiontime, Some(300));
+    }
+
+    fn bridge() -> Bridge {
+        Bridge::rehearsal(Topology {
+            bridge: "127.0.0.1".to_owned(),
+            lights: [(
+                1,
+                json!({
+                    "name": "Desk",
+                    "type": "Extended color light",
+                    "state": { "on": true, "bri": 254, "ct": 200, "colormode": "xy", "xy": [0.5, 0.4] },
+                }),
+            )]
+            .into(),
+            ..Topology::default()
+        })
+    }
+
+    fn change(field: &str, old: Value, new: Value) -> Change {
+        Change::Field {
+            kind: Kind::Light,
+            id: 1,
+            field: field.to_owned(),
+            old,
+            new,
+        }
+    }
+
+    #[test]
+    fn tells_nudges_from_changes_by_hand() {
+        let bridge = bridge();
+        let mut adaptive = Adaptive::new(&bridge, Curve::new(51.48, -0.01), vec![1]);
+        let current = json!({ "on": true, "bri": 254, "ct": 200, "colormode": "xy" });
+        adaptive
+            .send(1, &current, state!(ct: 400, bri: 100))
+            .unwrap();
+
+        // what the bridge reports on the way to the ct that was sent
+        let ours = [
+            change("state.colormode", json!("xy"), json!("ct")),
+            change("state.xy", json!([0.5, 0.4]), json!([0.49, 0.41])),
+            change("state.ct", json!(200), json!(300)),
+            change("state.bri", json!(254), json!(101)),
+        ];
+        for change in &ours {
+            assert!(!adaptive.observe(change).unwrap(), "{}", change);
+        }
+        assert!(!adaptive.is_backed_off(1));
+
+        // someone picks a colour
+        let colour = change("state.colormode", json!("ct"), json!("xy"));
+        assert!(adaptive.observe(&colour).unwrap());
+        assert!(adaptive.is_backed_off(1));
+        // and is only reported once
+        assert!(!adaptive.observe(&colour).unwrap());
+
+        // turning it off hands it back
+        adaptive
+            .observe(&change("state.on", json!(true), json!(false)))
+            .unwrap();
+        assert!(!adaptive.is_backed_off(1));
+    }
+
+    #[test]
+    fn backs_off_lights_dimmed_by_hand() {
+        let (untouched, nudged) = (bridge(), bridge());
+        let mut adaptive = Adaptive::new(&untouched, Curve::new(51.48, -0.01), vec![1]);
+        // nothing was sent, so any change is someone else's
+        assert!(adaptive
+            .observe(&change("state.xy", json!([0.5, 0.4]), json!([0.3, 0.3])))
+            .unwrap());
+
+        let mut adaptive = Adaptive::new(&nudged, Curve::new(51.48, -0.01), vec![1]);
+        let current = json!({ "on": true, "bri": 254, "ct": 200, "colormode": "ct" });
+        adaptive
+            .send(1, &current, state!(ct: 400, bri: 100))
+            .unwrap();
+        assert!(!adaptive
+            .observe(&change("state.bri", json!(100), json!(102)))
+            .unwrap());
+        assert!(adaptive
+            .observe(&change("state.bri", json!(100), json!(30)))
+            .unwrap());
+        // lights that aren't followed are none of its business
+        let other = Change::Field {
+            kind: Kind::Light,
+            id: 2,
+            field: "state.bri".to_owned(),
+            old: json!(1),
+            new: json!(254),
+        };
+        assert!(!adaptive.observe(&other).unwrap());
+    }
+
+    #[test]
+    fn only_counts_lights_switched_on() {
+        let changes = [
+            change("state.on", json!(false), json!(true)),
+            Change::Field {
+                kind: Kind::Light,
+                id: 2,
+                field: "state.on".to_owned(),
+                old: json!(true),
+                new: json!(false),
+            },
+            Change::Field {
+                kind: Kind::Group,
+                id: 3,
+                field: "state.on".to_owned(),
+                old: json!(false),
+                new: json!(true),
+            },
+            change("state.bri", json!(1), json!(254)),
+        ];
+        assert_eq!(switched_on(&changes), [1]);
+    }
+}
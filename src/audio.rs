@@ -0,0 +1,573 @@
+use crate::bridge::Bridge;
+use crate::color::Rgb;
+use crate::lightstructs::*;
+use serde::*;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::f32::consts::PI;
+use std::fs;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Samples per analysis window (has to be a power of two for the FFT)
+const WINDOW: usize = 1024;
+
+/// Samples between the starts of two windows
+const HOP: usize = 512;
+
+/// How many past windows the onset threshold is averaged over
+const FLUX_HISTORY: usize = 43;
+
+/// Onsets closer together than this are counted once
+const MIN_ONSET_GAP: f32 = 0.1;
+
+/// Upper edges (Hz) of the low and mid bands, the high band is everything above
+const BANDS: (f32, f32) = (250.0, 4000.0);
+
+/// What was heard in a single window of audio
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Analysis {
+    /// Seconds since the start of the audio
+    pub time: f32,
+    /// Loudness (root mean square of the samples)
+    pub rms: f32,
+    /// Whether a beat or other sudden onset starts here
+    pub onset: bool,
+    /// Energy in the low, mid and high frequency bands
+    pub bands: [f32; 3],
+}
+
+/// Analyses a stream of mono PCM samples: loudness, spectral band energy and onset
+/// detection (through spectral flux against an adaptive threshold).
+pub struct Analyser {
+    sample_rate: u32,
+    buffer: Vec<f32>,
+    consumed: usize,
+    window: Vec<f32>,
+    previous: Vec<f32>,
+    flux: VecDeque<f32>,
+    last_onset: f32,
+    /// How far above the recent average the flux has to jump to count as an onset
+    pub sensitivity: f32,
+}
+
+impl Analyser {
+    pub fn new(sample_rate: u32) -> Self {
+        // Hann window
+        let window = (0..WINDOW)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / (WINDOW - 1) as f32).cos())
+            .collect();
+        Analyser {
+            sample_rate,
+            buffer: Vec::new(),
+            consumed: 0,
+            window,
+            previous: vec![0.0; WINDOW / 2],
+            flux: VecDeque::with_capacity(FLUX_HISTORY),
+            last_onset: f32::NEG_INFINITY,
+            sensitivity: 1.5,
+        }
+    }
+
+    /// Feeds samples (between -1 and 1) to the analyser and returns the analysis of
+    /// every window that got completed by them
+    pub fn push(&mut self, samples: &[f32]) -> Vec<Analysis> {
+        self.buffer.extend_from_slice(samples);
+        let mut analyses = Vec::new();
+        while self.buffer.len() >= WINDOW {
+            analyses.push(self.analyse());
+            self.buffer.drain(..HOP);
+            self.consumed += HOP;
+        }
+        analyses
+    }
+
+    fn analyse(&mut self) -> Analysis {
+        let frame = &self.buffer[..WINDOW];
+        let time = self.consumed as f32 / self.sample_rate as f32;
+        let rms = (frame.iter().map(|s| s * s).sum::<f32>() / WINDOW as f32).sqrt();
+
+        let mut spectrum: Vec<(f32, f32)> = frame
+            .iter()
+            .zip(&self.window)
+            .map(|(s, w)| (s * w, 0.0))
+            .collect();
+        fft(&mut spectrum);
+        let magnitudes: Vec<f32> = spectrum[..WINDOW / 2]
+            .iter()
+            .map(|(re, im)| (re * re + im * im).sqrt() / WINDOW as f32)
+            .collect();
+
+        let bin_width = self.sample_rate as f32 / WINDOW as f32;
+        let mut bands = [0.0; 3];
+        for (bin, magnitude) in magnitudes.iter().enumerate() {
+            let frequency = bin as f32 * bin_width;
+            let band = if frequency < BANDS.0 {
+                0
+            } else if frequency < BANDS.1 {
+                1
+            } else {
+                2
+            };
+            bands[band] += magnitude;
+        }
+
+        // spectral flux only counts energy that appeared, not energy that went away
+        let flux: f32 = magnitudes
+            .iter()
+            .zip(&self.previous)
+            .map(|(now, before)| (now - before).max(0.0))
+            .sum();
+        let average = if self.flux.is_empty() {
+            0.0
+        } else {
+            self.flux.iter().sum::<f32>() / self.flux.len() as f32
+        };
+        let onset = self.flux.len() == FLUX_HISTORY
+            && flux > average * self.sensitivity + 1e-4
+            && time - self.last_onset >= MIN_ONSET_GAP;
+        if onset {
+            self.last_onset = time;
+        }
+        if self.flux.len() == FLUX_HISTORY {
+            self.flux.pop_front();
+        }
+        self.flux.push_back(flux);
+        self.previous = magnitudes;
+
+        Analysis {
+            time,
+            rms,
+            onset,
+            bands,
+        }
+    }
+}
+
+/// In place radix-2 FFT on (real, imaginary) pairs
+fn fft(data: &mut [(f32, f32)]) {
+    let n = data.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let (re, im) = data[start + k + len / 2];
+                let twiddled = (re * cos - im * sin, re * sin + im * cos);
+                let even = data[start + k];
+                data[start + k] = (even.0 + twiddled.0, even.1 + twiddled.1);
+                data[start + k + len / 2] = (even.0 - twiddled.0, even.1 - twiddled.1);
+            }
+        }
+        len <<= 1;
+    }
+}
+
+/// What drives the brightness of the lights
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Rms,
+    Low,
+    Mid,
+    High,
+    /// Leave the brightness alone
+    None,
+}
+
+/// How the lights get their colour
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Colors {
+    /// Low, mid and high band energy become red, green and blue
+    Bands,
+    /// The hue moves on by `step` degrees on every onset
+    Cycle { step: f32 },
+    /// Always this colour (name, `#hex` or `rgb(r, g, b)`)
+    Fixed(String),
+    /// Leave the colour alone
+    None,
+}
+
+/// How the analysis drives the lights, usually loaded from a JSON file:
+/// ```json
+/// {
+///   "lights": [1, 2],
+///   "groups": [],
+///   "brightness": "rms",
+///   "color": { "cycle": { "step": 40 } },
+///   "rate": 10
+/// }
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AudioMapping {
+    #[serde(default)]
+    pub lights: Vec<u8>,
+    #[serde(default)]
+    pub groups: Vec<u8>,
+    #[serde(default = "default_level")]
+    pub brightness: Level,
+    #[serde(default = "default_colors")]
+    pub color: Colors,
+    /// Requests sent per second, shared by the lights and groups of every cue.
+    /// The bridge takes about 10 light or 1 group update per second before it
+    /// starts dropping them.
+    #[serde(default = "default_rate")]
+    pub rate: f32,
+}
+
+fn default_level() -> Level {
+    Level::Rms
+}
+
+fn default_colors() -> Colors {
+    Colors::Bands
+}
+
+fn default_rate() -> f32 {
+    10.0
+}
+
+impl AudioMapping {
+    /// Loads the mapping from a JSON file
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let mapping: AudioMapping = serde_json::from_str(&fs::read_to_string(path)?)?;
+        if let Colors::Fixed(color) = &mapping.color {
+            color.parse::<Rgb>()?;
+        }
+        Ok(mapping)
+    }
+}
+
+/// A state to send at a point in time
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Cue {
+    pub time: f32,
+    pub lights: Vec<u8>,
+    pub groups: Vec<u8>,
+    pub state: SendableState,
+}
+
+/// Turns analyses into cues according to a mapping. Levels are normalised against
+/// a slowly decaying peak, so quiet and loud recordings both use the full range.
+pub struct Renderer {
+    mapping: AudioMapping,
+    /// The colour of `Colors::Fixed`
+    fixed: Option<[f32; 2]>,
+    peaks: [f32; 4],
+    hue: f32,
+    onset: bool,
+    last_cue: f32,
+}
+
+impl Renderer {
+    pub fn new(mapping: AudioMapping) -> Result<Self, Box<dyn Error>> {
+        let fixed = match &mapping.color {
+            Colors::Fixed(color) => Some(color.parse::<Rgb>()?.xy()),
+            _ => None,
+        };
+        Ok(Renderer {
+            mapping,
+            fixed,
+            peaks: [1e-6; 4],
+            hue: 0.0,
+            onset: false,
+            last_cue: f32::NEG_INFINITY,
+        })
+    }
+
+    /// Scales a level to 0..1 against its (decaying) peak
+    fn normalise(&mut self, which: usize, value: f32) -> f32 {
+        self.peaks[which] = (self.peaks[which] * 0.995).max(value).max(1e-6);
+        value / self.peaks[which]
+    }
+
+    /// Takes the next analysis and returns a cue if one is due
+    pub fn cue(&mut self, analysis: &Analysis) -> Option<Cue> {
+        let rms = self.normalise(0, analysis.rms);
+        let bands = [
+            self.normalise(1, analysis.bands[0]),
+            self.normalise(2, analysis.bands[1]),
+            self.normalise(3, analysis.bands[2]),
+        ];
+        if analysis.onset {
+            self.onset = true;
+            if let Colors::Cycle { step } = self.mapping.color {
+                self.hue = (self.hue + step) % 360.0;
+            }
+        }
+
+        // every request counts against the rate, an onset that comes too soon
+        // goes out with the next cue
+        let requests = (self.mapping.lights.len() + self.mapping.groups.len()).max(1);
+        let interval = requests as f32 / self.mapping.rate.max(0.1);
+        if analysis.time - self.last_cue < interval {
+            return None;
+        }
+        self.last_cue = analysis.time;
+
+        let mut state = SendableState {
+            on: Some(true),
+            // the update rate is the transition, so the lights glide between cues
+            transitiontime: Some((interval * 10.0) as u16),
+            ..SendableState::default()
+        };
+        let level = match self.mapping.brightness {
+            Level::Rms => Some(rms),
+            Level::Low => Some(bands[0]),
+            Level::Mid => Some(bands[1]),
+            Level::High => Some(bands[2]),
+            Level::None => None,
+        };
+        if let Some(level) = level {
+            state.bri = Some((level.clamp(0.0, 1.0) * 253.0) as u8 + 1);
+        }
+        state.xy = match &self.mapping.color {
+            Colors::Bands => Some(
+                Rgb::new(
+                    (bands[0].min(1.0) * 255.0) as u8,
+                    (bands[1].min(1.0) * 255.0) as u8,
+                    (bands[2].min(1.0) * 255.0) as u8,
+                )
+                .xy(),
+            ),
+            Colors::Cycle { .. } => Some(Rgb::from_hue(self.hue).xy()),
+            Colors::Fixed(_) => self.fixed,
+            Colors::None => None,
+        };
+        if std::mem::replace(&mut self.onset, false) {
+            state.transitiontime = Some(0);
+        }
+
+        Some(Cue {
+            time: analysis.time,
+            lights: self.mapping.lights.clone(),
+            groups: self.mapping.groups.clone(),
+            state,
+        })
+    }
+}
+
+/// Renders the cues for already decoded mono audio
+pub fn render(
+    samples: &[f32],
+    sample_rate: u32,
+    mapping: AudioMapping,
+) -> Result<Vec<Cue>, Box<dyn Error>> {
+    let mut analyser = Analyser::new(sample_rate);
+    let mut renderer = Renderer::new(mapping)?;
+    Ok(analyser
+        .push(samples)
+        .iter()
+        .filter_map(|analysis| renderer.cue(analysis))
+        .collect())
+}
+
+/// Sends a cue to the bridge
+pub fn apply(bridge: &Bridge, cue: &Cue) -> Result<(), Box<dyn Error>> {
+    for light in &cue.lights {
+        bridge.state(*light, &cue.state)?;
+    }
+    for group in &cue.groups {
+        bridge.group_state(*group, &cue.state)?;
+    }
+    Ok(())
+}
+
+/// Sends the cues to the bridge at the times they are due
+pub fn play(bridge: &Bridge, cues: &[Cue]) -> Result<(), Box<dyn Error>> {
+    let start = Instant::now();
+    for cue in cues {
+        let due = Duration::from_secs_f32(cue.time.max(0.0));
+        if let Some(wait) = due.checked_sub(start.elapsed()) {
+            sleep(wait);
+        }
+        apply(bridge, cue)?;
+    }
+    Ok(())
+}
+
+/// Reads a WAV file and mixes it down to mono samples between -1 and 1.
+/// Returns the samples and the sample rate.
+#[cfg(feature = "audio")]
+pub fn read_wav(path: &str) -> Result<(Vec<f32>, u32), Box<dyn Error>> {
+    use hound::{SampleFormat, WavReader};
+
+    let mut reader = WavReader::open(path)?;
+    let spec = reader.spec();
+    let interleaved: Vec<f32> = match spec.sample_format {
+        SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+        SampleFormat::Int => {
+            let scale = (1_i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|s| s.map(|s| s as f32 / scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+    let channels = usize::from(spec.channels.max(1));
+    let mono = interleaved
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+        .collect();
+    Ok((mono, spec.sample_rate))
+}
+
+/// Listens on an audio input (the first one whose name contains `device`, or the
+/// default one) and drives the lights live. Only returns if something goes wrong.
+#[cfg(feature = "audio")]
+pub fn listen(
+    bridge: &Bridge,
+    device: Option<&str>,
+    mapping: AudioMapping,
+) -> Result<(), Box<dyn Error>> {
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+    use cpal::SampleFormat;
+    use std::sync::mpsc::channel;
+
+    let host = cpal::default_host();
+    let input = match device {
+        Some(wanted) => host
+            .input_devices()?
+            .find(|d| d.name().map(|n| n.contains(wanted)).unwrap_or(false)),
+        None => host.default_input_device(),
+    }
+    .ok_or("no matching audio input found")?;
+    let config = input.default_input_config()?;
+    let channels = usize::from(config.channels().max(1));
+    let sample_rate = config.sample_rate().0;
+
+    // the audio callback runs on its own thread, so the samples get passed over here
+    let (sender, receiver) = channel::<Vec<f32>>();
+    let mono = move |data: Vec<f32>| {
+        let _ = sender.send(
+            data.chunks(channels)
+                .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+                .collect(),
+        );
+    };
+    let on_error = |e| tracing::error!("Audio input error: {}", e);
+    let stream = match config.sample_format() {
+        SampleFormat::F32 => input.build_input_stream(
+            &config.into(),
+            move |data: &[f32], _: &_| mono(data.to_vec()),
+            on_error,
+            None,
+        )?,
+        SampleFormat::I16 => input.build_input_stream(
+            &config.into(),
+            move |data: &[i16], _: &_| mono(data.iter().map(|s| f32::from(*s) / 32768.0).collect()),
+            on_error,
+            None,
+        )?,
+        format => return Err(format!("unsupported sample format {:?}", format).into()),
+    };
+    stream.play()?;
+
+    let mut analyser = Analyser::new(sample_rate);
+    let mut renderer = Renderer::new(mapping)?;
+    for samples in receiver {
+        for analysis in analyser.push(&samples) {
+            if let Some(cue) = renderer.cue(&analysis) {
+                apply(bridge, &cue)?;
+            }
+        }
+    }
+    Err("audio input closed".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 44100;
+
+    fn mapping(json: &str) -> AudioMapping {
+        serde_json::from_str(json).unwrap()
+    }
+
+    /// A quiet hum with a loud click on every beat
+    fn beats(seconds: f32, bpm: f32) -> Vec<f32> {
+        let beat = (60.0 / bpm * SAMPLE_RATE as f32) as usize;
+        (0..(seconds * SAMPLE_RATE as f32) as usize)
+            .map(|i| {
+                let hum = 0.01 * (2.0 * PI * 110.0 * i as f32 / SAMPLE_RATE as f32).sin();
+                let click = if i % beat < 200 {
+                    (i * 7919 % 200) as f32 / 100.0 - 1.0
+                } else {
+                    0.0
+                };
+                hum + click
+            })
+            .collect()
+    }
+
+    fn requests(cues: &[Cue]) -> usize {
+        cues.iter()
+            .map(|cue| cue.lights.len() + cue.groups.len())
+            .sum()
+    }
+
+    #[test]
+    fn keeps_every_request_within_the_rate() {
+        // beats faster than the rate allows for three lights
+        let samples = beats(10.0, 300.0);
+        let cues = render(
+            &samples,
+            SAMPLE_RATE,
+            mapping(r#"{"lights": [1, 2, 3], "color": {"cycle": {"step": 40}}, "rate": 6}"#),
+        )
+        .unwrap();
+        assert!(!cues.is_empty());
+        assert!(requests(&cues) as f32 <= 6.0 * 10.0 + 3.0);
+        for pair in cues.windows(2) {
+            assert!(pair[1].time - pair[0].time >= 0.5 - 1e-3);
+        }
+        // the onsets still show up as cuts
+        assert!(cues.iter().any(|cue| cue.state.transitiontime == Some(0)));
+    }
+
+    #[test]
+    fn renders_fixed_colours() {
+        let cues = render(
+            &beats(2.0, 120.0),
+            SAMPLE_RATE,
+            mapping(r#"{"groups": [1], "color": {"fixed": "red"}, "rate": 2}"#),
+        )
+        .unwrap();
+        let red = Rgb::new(255, 0, 0).xy();
+        assert!(cues.iter().all(|cue| cue.state.xy == Some(red)));
+        assert!(cues
+            .iter()
+            .all(|cue| cue.groups == [1] && cue.lights.is_empty()));
+        assert!(cues.iter().all(|cue| cue.state.bri.is_some()));
+    }
+
+    #[test]
+    fn refuses_unknown_colours() {
+        let mapping = mapping(r#"{"lights": [1], "color": {"fixed": "nope"}}"#);
+        assert!(render(&beats(1.0, 120.0), SAMPLE_RATE, mapping).is_err());
+    }
+
+    #[test]
+    fn renders_nothing_from_silence() {
+        let silence = vec![0.0; SAMPLE_RATE as usize];
+        let cues = render(&silence, SAMPLE_RATE, mapping(r#"{"lights": [1]}"#)).unwrap();
+        assert!(cues.iter().all(|cue| cue.state.transitiontime != Some(0)));
+        assert_eq!(render(&[], SAMPLE_RATE, mapping("{}")).unwrap().len(), 0);
+    }
+}
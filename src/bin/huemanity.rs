@@ -1,93 +1,1778 @@
 #[macro_use]
 extern crate clap;
+#[macro_use]
+extern crate huemanity;
 extern crate serde_json;
-use huemanity::{bridge::*, lightstructs::*};
+use clap::{App, ArgMatches};
+use huemanity::cache::{self, Names, Topology};
+use huemanity::error::HueError;
+use huemanity::output::{self, Format};
+use huemanity::{bridge::*, color::Rgb, effects::Effect, lightstructs::*, watcher::Watcher};
+use serde::Serialize;
+use std::error::Error;
+use std::fmt::{Display, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::time::Duration;
+use tracing::level_filters::LevelFilter;
+use tracing::{info, warn};
 
 // ssdp
 extern crate ssdp;
 
+// Exit codes, other than 0 for success
+/// Anything not covered below
+const FAILED: i32 = 1;
+/// Bad arguments, states, colours, names or malformed files
+const INVALID: i32 = 2;
+/// The bridge could not be reached
+const UNREACHABLE: i32 = 3;
+/// The bridge refused the request
+const REFUSED: i32 = 4;
+
 fn main() {
-    let matches = clap_app!(huemanity =>
-             (version: "0.1.0")
-             (author: "Art Eidukas <iwiivi@gmail.com>")
-             (about: "Given HUE bridge credentials, allows control over your HUE lights")
-             (@subcommand info =>
-                 (about: "Prints out the state of the lights that the bridge can detect")
-             )
-             (@subcommand state =>
-                 (about: "Takes a string input (json, escaped quotes) of a new state and sends it to a given light")
-                 (@arg STATE: +required "Takes a string input representing a new state to send to ALL lights")
-                 (@arg LIGHT: +required "You need to provide the numerical ID of the light")
-
-             )
-             (@subcommand all =>
-                 (about: "Sends commands to all lights")
-                 (@arg STATE: +required "Takes a string input (json, escaped quotes) of a new state and sends it to all lights")
-             )
-             (@subcommand debug =>
-                 (about: "Send a get request to the bridge and return the raw response")
-             )
-             (@subcommand search =>
-                 (about: "Search for a bridge and print out the IP of the bridge")
-             )
-             (@subcommand discover =>
-                 (about: "Discover the bridges on the network (NOTE: EXPERIMENTAL)")
-             )
-             (@subcommand clean =>
-                 (about: "Cleanup the `~/.huemanity` file")
-             )
-         )
-         .get_matches();
-
-    // resolve commands
-    if let Some(matches) = matches.subcommand_matches("all") {
-        if let Some(jsn) = matches.value_of("STATE") {
-            let parsed = serde_json::from_str::<SendableState>(jsn);
-            match parsed {
-                Ok(state) => {
-                    let bridge = Bridge::link();
-                    if let Err(e) = bridge.state_all(&state) {
-                        println!("Could not send state: {}", e);
-                    };
+    let matches = match app().get_matches_safe() {
+        Ok(matches) => matches,
+        Err(e) if e.use_stderr() => {
+            eprintln!("{}", e.message);
+            std::process::exit(INVALID);
+        }
+        // --help and --version
+        Err(e) => {
+            println!("{}", e.message);
+            return;
+        }
+    };
+    logging(&matches);
+    let mut output = Output::new(&matches, Format::Plain);
+    if let Err(e) = run(&matches, &mut Session::default(), &mut output) {
+        output.error(&*e);
+        std::process::exit(classify(&*e).1);
+    }
+}
+
+/// Sends what the library and the commands have to say to stderr, with more or less
+/// of it depending on `-v` and `-q`
+fn logging(matches: &ArgMatches) {
+    // global flags are copied into the subcommands, so they can't be added up
+    let count = |name| {
+        levels(matches)
+            .map(|matches| matches.occurrences_of(name))
+            .max()
+            .unwrap_or_default() as i64
+    };
+    let verbosity = count("verbose") - count("quiet");
+    let level = match verbosity {
+        i64::MIN..=-2 => LevelFilter::ERROR,
+        -1 => LevelFilter::WARN,
+        0 => LevelFilter::INFO,
+        1 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    };
+    // levels and targets only get in the way until there is more than usual
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_max_level(level)
+        .with_level(verbosity > 0)
+        .with_target(verbosity > 0)
+        .without_time()
+        .init();
+}
+
+/// Prints results in the format picked with `--output`
+struct Output {
+    format: Format,
+    /// Header of the CSV lines printed last, so it is only repeated when it changes
+    columns: Option<Vec<String>>,
+}
+
+impl Output {
+    /// Takes the format from the innermost command that was given one
+    fn new(matches: &ArgMatches, default: Format) -> Self {
+        let format = levels(matches).filter_map(|m| m.value_of("output")).last();
+        Output {
+            format: format.and_then(|f| f.parse().ok()).unwrap_or(default),
+            columns: None,
+        }
+    }
+
+    /// Prints what a command came up with, `plain` being how it reads for people
+    fn result<T: Serialize>(&mut self, data: &T, plain: &str) -> Result<(), Box<dyn Error>> {
+        let data = serde_json::to_value(data)?;
+        match self.format {
+            Format::Json => println!("{}", serde_json::to_string_pretty(&data)?),
+            Format::Table => print!("{}", output::table(&data)),
+            Format::Csv => print!("{}", output::csv(&data)),
+            Format::Plain if !plain.is_empty() => println!("{}", plain),
+            Format::Plain => (),
+        }
+        Ok(())
+    }
+
+    /// Prints one of the things a command that keeps running reports, as it happens.
+    /// Tables can't be lined up before they are complete, so they get the plain text.
+    fn event<T: Serialize, P: Display>(&mut self, data: &T, plain: P) {
+        let data = serde_json::to_value(data).unwrap_or_default();
+        match self.format {
+            Format::Json => println!("{}", data),
+            Format::Csv => {
+                let (header, rows) = output::rows(&data);
+                if self.columns.as_ref() != Some(&header) {
+                    println!("{}", output::csv_row(&header));
+                    self.columns = Some(header);
                 }
-                Err(e) => println!("Error in parsing state: {}", e),
-            }
-        }
-    } else if let Some(_) = matches.subcommand_matches("info") {
-        let bridge = Bridge::link();
-        bridge.light_info();
-    } else if let Some(matches) = matches.subcommand_matches("state") {
-        let state = matches.value_of("STATE");
-        let light = matches.value_of("LIGHT");
-        // TODO: Can we tidy this up?
-        match (state, light) {
-            (Some(state), Some(light)) => {
-                match (
-                    serde_json::from_str::<SendableState>(state),
-                    light.parse::<u8>(),
-                ) {
-                    (Ok(sendablestate), Ok(lightid)) => {
-                        let bridge = Bridge::link();
-                        if let Err(e) = bridge.state(lightid, &sendablestate) {
-                            println!("Could not send state to light: {}", e);
-                        }
+                for row in rows {
+                    println!("{}", output::csv_row(&row));
+                }
+            }
+            Format::Table | Format::Plain => println!("{}", plain),
+        }
+    }
+
+    /// Tells the user what is going on, on stderr so it doesn't mix with the results
+    fn note(&self, message: &str) {
+        info!("{}", message);
+    }
+
+    fn error(&self, e: &(dyn Error + 'static)) {
+        // clap's messages come with their own prefix
+        let message = e.to_string();
+        let message = message.trim_start_matches("error: ").trim_end();
+        match self.format {
+            Format::Json => eprintln!(
+                "{}",
+                serde_json::json!({ "error": { "kind": classify(e).0, "message": message } })
+            ),
+            _ => eprintln!("error: {}", message),
+        }
+    }
+}
+
+/// Input the user got wrong, rather than anything going wrong with the bridge
+#[derive(Debug)]
+struct Invalid(String);
+
+impl Display for Invalid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for Invalid {}
+
+fn invalid<E: Display>(e: E) -> Box<dyn Error> {
+    Box::new(Invalid(e.to_string()))
+}
+
+/// The kind of an error and the exit code it gives
+fn classify(e: &(dyn Error + 'static)) -> (&'static str, i32) {
+    if let Some(e) = e.downcast_ref::<HueError>() {
+        let code = match e {
+            HueError::UnknownName { .. } => INVALID,
+            HueError::Api { .. } => REFUSED,
+            _ => UNREACHABLE,
+        };
+        return (e.kind(), code);
+    }
+    if e.is::<Invalid>()
+        || e.is::<clap::Error>()
+        || e.is::<serde_json::Error>()
+        || e.is::<serde_yaml::Error>()
+        || e.is::<std::num::ParseIntError>()
+        || e.is::<std::num::ParseFloatError>()
+    {
+        return ("invalid_input", INVALID);
+    }
+    if e.is::<std::io::Error>() {
+        return ("io", FAILED);
+    }
+    ("failed", FAILED)
+}
+
+/// The matches of the command and each subcommand under it, outermost first
+fn levels<'a>(matches: &'a ArgMatches<'a>) -> impl Iterator<Item = &'a ArgMatches<'a>> {
+    std::iter::successors(Some(matches), |matches| matches.subcommand().1)
+}
+
+/// Links to the bridge the first time a command needs it, and keeps it for the
+/// commands after that in the shell
+#[derive(Default)]
+struct Session {
+    bridge: Option<Bridge>,
+    /// Whether the next command fetches the topology instead of using the cached one
+    refresh: bool,
+    /// Whether the command logs its requests instead of sending them
+    dry_run: bool,
+}
+
+impl Session {
+    fn bridge(&mut self) -> &Bridge {
+        self.link()
+    }
+
+    /// Hands the bridge over to a command that keeps it
+    fn take(&mut self) -> Bridge {
+        self.link();
+        self.bridge.take().unwrap()
+    }
+
+    fn link(&mut self) -> &mut Bridge {
+        let transport = if self.dry_run {
+            Transport::DryRun
+        } else {
+            Transport::Http
+        };
+        // in the shell, lines with and without --dry-run can follow each other
+        if self.bridge.as_ref().map(Bridge::transport) != Some(transport) {
+            self.bridge = None;
+        }
+        let refresh = std::mem::take(&mut self.refresh);
+        if let (Some(bridge), true) = (&mut self.bridge, refresh) {
+            if let Err(e) = bridge.refresh() {
+                warn!("Could not collect lights: {}", e);
+            }
+        }
+        self.bridge
+            .get_or_insert_with(|| Bridge::link_with((!refresh).then_some(cache::TTL), transport))
+    }
+
+    /// Light, group and scene names for completion in the shell
+    fn names(&mut self) -> Vec<String> {
+        let topology = self.link().topology();
+        topology
+            .map(|topology| topology.names().all())
+            .unwrap_or_default()
+    }
+}
+
+/// The command line interface
+fn app() -> App<'static, 'static> {
+    let app = clap_app!(huemanity =>
+        (version: "0.1.0")
+        (author: "Art Eidukas <iwiivi@gmail.com>")
+        (about: "Given HUE bridge credentials, allows control over your HUE lights")
+        (@arg output: -o --output +global +takes_value possible_value[json table csv plain] "How to print results (default: plain)")
+        (@arg verbose: -v --verbose +global +multiple "Say more about what is going on, like every request sent to the bridge")
+        (@arg quiet: -q --quiet +global +multiple conflicts_with[verbose] "Only say something when it goes wrong, twice for errors only")
+        (@arg dry_run: --("dry-run") +global "Print the requests that would be sent to the bridge instead of sending them")
+        (@arg refresh: --refresh +global "Ask the bridge for its lights, groups and scenes instead of using the ones cached for 15 minutes")
+        (@subcommand info =>
+            (about: "Prints out the state of the lights that the bridge can detect")
+        )
+        (@subcommand debug =>
+            (about: "Send a get request to the bridge and return the raw response")
+        )
+        (@subcommand search =>
+            (about: "Search for a bridge and print out the IP of the bridge")
+        )
+        (@subcommand discover =>
+            (about: "Discover the bridges on the network (NOTE: EXPERIMENTAL)")
+        )
+        (@subcommand clean =>
+            (about: "Cleanup the `~/.huemanity` file")
+        )
+        (@subcommand entertainment =>
+            (about: "Manage entertainment areas used for streaming")
+            (@subcommand list =>
+                (about: "Lists the entertainment areas with their light locations")
+            )
+            (@subcommand create =>
+                (about: "Creates an entertainment area out of the given lights")
+                (@arg NAME: +required "Name of the area")
+                (@arg LIGHTS: +required +multiple "Numerical IDs of the lights in the area")
+                (@arg class: --class +takes_value "What the area is set up around: TV or Free (default: Free)")
+            )
+            (@subcommand locations =>
+                (about: "Sets the light locations of an area from a JSON file like {\"1\": [-0.5, 0.8, 0.0]}")
+                (@arg GROUP: +required "ID or name of the area")
+                (@arg FILE: +required "File with the locations")
+            )
+            (@subcommand stream =>
+                (about: "Takes or gives up streaming ownership of an area")
+                (@arg GROUP: +required "ID or name of the area")
+                (@arg ACTION: +required possible_value[start stop] "Whether to start or stop streaming")
+            )
+            (@subcommand delete =>
+                (about: "Deletes an entertainment area")
+                (@arg GROUP: +required "ID or name of the area")
+            )
+        )
+        (@subcommand effect =>
+            (about: "Runs an animation on some lights until it is over or Ctrl-C is pressed")
+            (@arg NAME: +required possible_value[fade breathe rainbow strobe candle police sunrise] "The effect to run")
+            (@arg lights: --lights +required +takes_value +multiple "IDs or names of the lights")
+            (@arg color: --color +takes_value "Colour to breathe, strobe or fade in (name, #hex or rgb(r, g, b))")
+            (@arg from: --from +takes_value "State (json) or colour a fade starts from")
+            (@arg to: --to +takes_value "State (json) or colour a fade ends on")
+            (@arg seconds: --seconds +takes_value "Length of a fade or sunrise, one breath, one rainbow cycle or one strobe flash")
+            (@arg duration: --duration +takes_value "Stop after this many seconds")
+        )
+        (@subcommand play =>
+            (about: "Plays a light show from a YAML or JSON file")
+            (@arg FILE: +required "The show to play")
+            (@arg start: --start +takes_value "Seconds into the show to start from")
+        )
+        (@subcommand snapshot =>
+            (about: "Saves the state of all lights to a file, or puts them back from one")
+            (@arg ACTION: +required possible_value[save restore] "Whether to save or restore the lights")
+            (@arg FILE: +required "File holding the snapshot")
+        )
+        (@subcommand daemon =>
+            (about: "Runs jobs from a schedule file at set times or around sunrise and sunset")
+            (@arg FILE: "Schedule file (default: ~/.huemanity_schedule.yaml)")
+            (@arg list: --list "Only list when the jobs are due next, without touching the lights")
+            (@arg count: --count +takes_value "How many upcoming jobs to list (default: 10)")
+        )
+        (@subcommand adaptive =>
+            (about: "Keeps lights following the colour and brightness of daylight through the day")
+            (@arg lights: --lights +takes_value +multiple required_unless[curve] "IDs or names of the lights")
+            (@arg latitude: --latitude +takes_value +required +allow_hyphen_values "Where to follow the sun (north is positive)")
+            (@arg longitude: --longitude +takes_value +required +allow_hyphen_values "Where to follow the sun (east is positive)")
+            (@arg warmest: --warmest +takes_value "Colour temperature at night in Kelvin (default: 2200)")
+            (@arg coolest: --coolest +takes_value "Colour temperature at noon in Kelvin (default: 5500)")
+            (@arg dimmest: --dimmest +takes_value "Brightness at night in percent (default: 40)")
+            (@arg brightest: --brightest +takes_value "Brightness at noon in percent (default: 100)")
+            (@arg backoff: --backoff +takes_value "Minutes to leave a light alone after it is changed by hand (default: 60)")
+            (@arg curve: --curve "Print today's curve instead of changing any lights")
+        )
+        (@subcommand serve =>
+            (about: "Serves a simpler HTTP API for the lights (documented at /openapi.json)")
+            (@arg listen: --listen +takes_value "Address to listen on (default: 127.0.0.1:8080)")
+            (@arg token: --token +takes_value "Bearer token clients have to send (default: HUE_SERVE_TOKEN, or a new one)")
+            (@arg poll: --poll "Poll the bridge for the websocket's events instead of using the event stream (for v1 bridges)")
+        )
+        (@subcommand exporter =>
+            (about: "Exposes lights, sensors and bridge health as Prometheus metrics on /metrics")
+            (@arg listen: --listen +takes_value "Address to listen on, `:PORT` for all interfaces (default: :9184)")
+            (@arg interval: --interval +takes_value "Seconds between polls of the bridge (default: 15)")
+        )
+        (@subcommand tui =>
+            (about: "Full screen interface to browse and control the rooms and lights")
+            (@arg interval: --interval +takes_value "Seconds between refreshes from the bridge (default: 2)")
+        )
+        (@subcommand watch =>
+            (about: "Prints changes to lights and sensors live as they happen")
+            (@arg poll: --poll "Poll the bridge for changes instead of using the event stream (for v1 bridges)")
+            (@arg interval: --interval +takes_value "Seconds between polls (default: 1)")
+            (@arg failures: --failures +takes_value "Give up after this many failed polls in a row (default: 5)")
+        )
+        (@subcommand shell =>
+            (about: "Takes commands one after the other with the bridge linked once, with completion of names and history")
+        )
+        (@subcommand completions =>
+            (about: "Prints a script completing commands, options and names in your shell")
+            (@arg SHELL: +required possible_value[bash zsh fish powershell] "The shell to complete in")
+            (after_help: "Names come from ~/.huemanity_cache.json, which is updated whenever the bridge is asked \
+                          for its lights and by `huemanity names --refresh`.\n\n\
+                          EXAMPLES:\n    \
+                          huemanity completions bash > /etc/bash_completion.d/huemanity\n    \
+                          huemanity completions zsh > ~/.zfunc/_huemanity\n    \
+                          huemanity completions fish > ~/.config/fish/completions/huemanity.fish")
+        )
+        (@subcommand man =>
+            (about: "Prints the manual page, to read with `man -l` or install as huemanity.1")
+        )
+        (@subcommand names =>
+            (about: "Prints the light, group and scene names kept for completion, without asking the bridge unless given --refresh")
+            (@arg KIND: +multiple possible_value[lights groups scenes] "Only print these kinds of names")
+        )
+    );
+
+    let app = app
+        .subcommand(state_flags(clap_app!(@subcommand state =>
+            (about: "Sends a new state to a given light, as flags or a json string (escaped quotes)")
+            (@arg STATE: "The new state as json, or the light when the state is given with flags")
+            (@arg LIGHT: "ID or name of the light")
+            (after_help: "EXAMPLES:\n    huemanity state Desk --on --bri 50% --color red\n    huemanity state '{\"on\": true}' 1")
+        )))
+        .subcommand(state_flags(clap_app!(@subcommand all =>
+            (about: "Sends commands to all lights")
+            (@arg STATE: "The new state as json (escaped quotes), if not given with flags")
+        )))
+        .subcommand(target_args(clap_app!(@subcommand toggle =>
+            (about: "Turns a light or group off if it is on, and on if it is off")
+        )))
+        .subcommand(target_args(clap_app!(@subcommand dim =>
+            (about: "Makes a light or group dimmer")
+            (@arg by: --by [PERCENT] "How much dimmer, in percent of the full range (default: 10%)")
+        )))
+        .subcommand(target_args(clap_app!(@subcommand brighten =>
+            (about: "Makes a light or group brighter")
+            (@arg by: --by [PERCENT] "How much brighter, in percent of the full range (default: 10%)")
+        )))
+        .subcommand(target_args(clap_app!(@subcommand warmer =>
+            (about: "Makes the colour temperature of a light or group warmer")
+            (@arg by: --by [MIRED] "How much warmer, in mired (default: 50, lights go from 153 to 500)")
+        )))
+        .subcommand(target_args(clap_app!(@subcommand cooler =>
+            (about: "Makes the colour temperature of a light or group cooler")
+            (@arg by: --by [MIRED] "How much cooler, in mired (default: 50, lights go from 153 to 500)")
+        )));
+
+    // optional subcommands
+    #[cfg(feature = "midi")]
+    let app = app.subcommand(clap_app!(@subcommand midi =>
+        (about: "Triggers light states from a MIDI input such as a drumkit")
+        (@arg MAPPING: "JSON file mapping notes and controllers to light states")
+        (@arg port: --port +takes_value "Use the first input port whose name contains this (default: the first port)")
+        (@arg stream: --stream +takes_value "Stream to this entertainment area instead of sending requests")
+        (@arg ports: --ports "List the MIDI input ports")
+    ));
+
+    #[cfg(feature = "audio")]
+    let app = app.subcommand(clap_app!(@subcommand audio =>
+        (about: "Makes lights react to music from an audio input or a WAV file")
+        (@arg MAPPING: +required "JSON file saying which lights react and how")
+        (@arg wav: --wav +takes_value "Analyse this WAV file instead of listening to an input")
+        (@arg render: --render requires[wav] "Print the light timeline of the WAV file as JSON instead of playing it")
+        (@arg device: --device +takes_value "Use the first input whose name contains this (default: the default input)")
+    ));
+
+    #[cfg(feature = "mqtt")]
+    let app = app.subcommand(clap_app!(@subcommand mqtt =>
+        (about: "Publishes lights, groups and sensors to an MQTT broker, with Home Assistant discovery")
+        (@arg broker: --broker +takes_value "Broker to connect to, as HOST or HOST:PORT (default: localhost:1883)")
+        (@arg username: --username +takes_value "Username for the broker")
+        (@arg password: --password +takes_value "Password for the broker (default: HUE_MQTT_PASSWORD)")
+        (@arg base: --base +takes_value "Topic to publish under (default: huemanity/<bridge>)")
+        (@arg discovery: --("discovery-prefix") +takes_value "Topic Home Assistant looks for discovery messages under (default: homeassistant)")
+        (@arg no_discovery: --("no-discovery") "Don't send Home Assistant discovery messages")
+        (@arg interval: --interval +takes_value "Seconds between polls of the bridge (default: 5)")
+    ));
+
+    app
+}
+
+/// Adds the flags that build up a state to `state` and `all`
+fn state_flags(command: App<'static, 'static>) -> App<'static, 'static> {
+    clap_app!(@app (command)
+        (@group power =>
+            (@arg on: --on "Turns the lights on, which --bri, --color and --kelvin do too unless --off is given")
+            (@arg off: --off "Turns the lights off")
+            (@arg toggle: --toggle "Turns the lights off if they are on and on if they are off")
+        )
+        (@arg bri: --bri [PERCENT] "Brightness in percent, like 50%")
+        (@arg color: --color [COLOR] "Colour name, #hex or rgb(r, g, b)")
+        (@arg kelvin: --kelvin [KELVIN] "Colour temperature, like 2700K")
+        (@arg transition: --transition [TIME] "Time to get to the new state, like 2s or 500ms")
+        (@arg effect: --effect [EFFECT] possible_value[colorloop none] "Cycles through the colours until set to none")
+        (@arg alert: --alert [ALERT] possible_value[select lselect none] "Blinks once (select) or for 15 seconds (lselect)")
+    )
+}
+
+/// Adds the light or group `toggle`, `dim`, `brighten`, `warmer` and `cooler` act on
+fn target_args(command: App<'static, 'static>) -> App<'static, 'static> {
+    clap_app!(@app (command)
+        (@arg TARGET: +required "ID or name of the light, or name of the group")
+        (@arg group: --group "TARGET is a group, even if it is an ID (0 is all the lights)")
+    )
+}
+
+/// Builds the state from the json and flags of `state` or `all`, the flags taking
+/// precedence. `--toggle` is left to the caller, which knows what is on.
+fn state_from(json: Option<&str>, matches: &ArgMatches) -> Result<SendableState, Box<dyn Error>> {
+    use huemanity::server::StateRequest;
+
+    let state = match json {
+        Some(json) => serde_json::from_str(json)
+            .map_err(|e| invalid(format!("the state is not valid json: {}", e)))?,
+        None => SendableState::default(),
+    };
+    let flags = [
+        "on",
+        "off",
+        "toggle",
+        "bri",
+        "color",
+        "kelvin",
+        "transition",
+        "effect",
+        "alert",
+    ];
+    if json.is_none() && !flags.iter().any(|flag| matches.is_present(flag)) {
+        return Err(invalid(
+            "no state given, try flags like --on --bri 50% or json like '{\"on\": true}'",
+        ));
+    }
+
+    // lights that are off don't take a colour or brightness
+    let lit = ["bri", "color", "kelvin"]
+        .iter()
+        .any(|flag| matches.is_present(flag));
+    let on = if matches.is_present("on") || matches.is_present("off") {
+        Some(matches.is_present("on"))
+    } else if lit && state.on.is_none() {
+        Some(true)
+    } else {
+        None
+    };
+    let request = StateRequest {
+        on,
+        brightness: matches.value_of("bri").map(parse_percent).transpose()?,
+        color: matches.value_of("color").map(str::to_owned),
+        kelvin: matches.value_of("kelvin").map(parse_kelvin).transpose()?,
+        transition: matches
+            .value_of("transition")
+            .map(parse_seconds)
+            .transpose()?,
+        alert: matches.value_of("alert").map(str::to_owned),
+        effect: matches.value_of("effect").map(str::to_owned),
+    };
+    request.apply(state).map_err(invalid)
+}
+
+/// Parses a percentage like `50%`, the sign being optional
+fn parse_percent(value: &str) -> Result<f32, Box<dyn Error>> {
+    value
+        .trim()
+        .trim_end_matches('%')
+        .parse()
+        .map_err(|_| invalid(format!("`{}` is not a percentage like 50%", value)))
+}
+
+/// Parses a colour temperature like `2700K`, the unit being optional
+fn parse_kelvin(value: &str) -> Result<u32, Box<dyn Error>> {
+    value
+        .trim()
+        .trim_end_matches(['K', 'k'])
+        .parse()
+        .map_err(|_| {
+            invalid(format!(
+                "`{}` is not a colour temperature like 2700K",
+                value
+            ))
+        })
+}
+
+/// Parses a time like `2s`, `500ms` or `1m` into seconds, seconds being the default
+fn parse_seconds(value: &str) -> Result<f32, Box<dyn Error>> {
+    let value = value.trim();
+    let (number, unit) = match value.find(|c: char| c.is_alphabetic()) {
+        Some(i) => value.split_at(i),
+        None => (value, "s"),
+    };
+    let scale = match unit {
+        "ms" => 0.001,
+        "s" => 1.0,
+        "m" | "min" => 60.0,
+        _ => 0.0,
+    };
+    match number.trim().parse::<f32>() {
+        Ok(number) if scale > 0.0 && number >= 0.0 && number.is_finite() => Ok(number * scale),
+        _ => Err(invalid(format!(
+            "`{}` is not a time like 2s or 500ms",
+            value
+        ))),
+    }
+}
+
+/// The value of an option as a `T`, `None` if the option isn't given
+fn parse_option<T>(matches: &ArgMatches, name: &str) -> Result<Option<T>, Box<dyn Error>>
+where
+    T: std::str::FromStr,
+    T::Err: Display,
+{
+    matches
+        .value_of(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|e| invalid(format!("`{}` is not a valid --{}: {}", value, name, e)))
+        })
+        .transpose()
+}
+
+/// An option that takes a number of seconds, which can't be negative or endless
+fn seconds_option(matches: &ArgMatches, name: &str) -> Result<Option<Duration>, Box<dyn Error>> {
+    parse_option::<f32>(matches, name)?
+        .map(|seconds| {
+            Duration::try_from_secs_f32(seconds).map_err(|_| {
+                invalid(format!(
+                    "--{} takes a number of seconds, not {}",
+                    name, seconds
+                ))
+            })
+        })
+        .transpose()
+}
+
+/// Resolves a command
+fn run(
+    matches: &ArgMatches,
+    session: &mut Session,
+    output: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    session.refresh |= levels(matches).any(|matches| matches.is_present("refresh"));
+    session.dry_run |= levels(matches).any(|matches| matches.is_present("dry_run"));
+    match matches.subcommand() {
+        ("all", Some(matches)) => {
+            let mut state = state_from(matches.value_of("STATE"), matches)?;
+            let bridge = session.bridge();
+            // toggling turns everything off if any light is on
+            let toggled = if matches.is_present("toggle") {
+                bridge
+                    .fetch::<std::collections::BTreeMap<u8, serde_json::Value>>("lights")
+                    .map(|lights| {
+                        state.on = Some(!lights.values().any(|light| light["state"]["on"] == true))
+                    })
+            } else {
+                Ok(())
+            };
+            let result = toggled.and_then(|_| bridge.state_all(&state));
+            report(bridge);
+            result?;
+            output.result(
+                &serde_json::json!({ "lights": bridge.light_ids, "state": state }),
+                "",
+            )?;
+        }
+        ("info", _) => {
+            use huemanity::server::LightView;
+
+            // the cached lights have the states they had when they were cached, and
+            // are taken as they come so one the crate doesn't understand is still listed
+            let lights: std::collections::BTreeMap<u8, serde_json::Value> =
+                session.bridge().fetch("lights")?;
+            let views: Vec<LightView> = lights
+                .iter()
+                .map(|(id, light)| LightView::new(*id, light))
+                .collect();
+            let mut plain = "--------------------------------\n\
+                             Lights available on your bridge:\n\
+                             --------------------------------"
+                .to_owned();
+            for (id, light) in &lights {
+                match serde_json::from_value::<Light>(light.clone()) {
+                    Ok(parsed) => plain += &format!("\n{}:{}", id, parsed),
+                    Err(_) => {
+                        let name = light["name"].as_str().unwrap_or_default();
+                        plain += &format!("\n{}:\nName: {}\n", id, name);
                     }
-                    _ => (),
                 }
             }
-            _ => (),
+            output.result(&views, &plain)?;
+        }
+        ("state", Some(matches)) => {
+            // with the state in flags, the only word is the light
+            let (json, light) = match (matches.value_of("STATE"), matches.value_of("LIGHT")) {
+                (json, Some(light)) => (json, light),
+                (Some(light), None) if !light.trim_start().starts_with('{') => (None, light),
+                _ => return Err(invalid("which light? try `state Desk --on`")),
+            };
+            let mut state = state_from(json, matches)?;
+            let bridge = session.bridge();
+            let result = bridge.resolve("lights", light).and_then(|light| {
+                if matches.is_present("toggle") {
+                    let current: serde_json::Value = bridge.fetch(&format!("lights/{}", light))?;
+                    state.on = Some(current["state"]["on"] != true);
+                }
+                bridge.state(light, &state).map(|_| light)
+            });
+            report(bridge);
+            let light = result?;
+            output.result(&serde_json::json!({ "light": light, "state": state }), "")?;
+        }
+        ("debug", _) => {
+            let bridge = session.bridge();
+            let result = bridge.fetch::<serde_json::Value>("lights");
+            report(bridge);
+            let lights = result?;
+            output.result(&lights, &serde_json::to_string_pretty(&lights)?)?;
+        }
+        ("entertainment", Some(matches)) => {
+            let bridge = session.bridge();
+            let result = entertainment(bridge, matches, output);
+            report(bridge);
+            result?;
+        }
+        ("effect", Some(matches)) => {
+            let bridge = session.bridge();
+            let result = effect(bridge, matches, output);
+            report(bridge);
+            result?;
+        }
+        ("play", Some(matches)) => {
+            let bridge = session.bridge();
+            let result = play(bridge, matches, output);
+            report(bridge);
+            result?;
+        }
+        ("snapshot", Some(matches)) => {
+            let bridge = session.bridge();
+            let result = snapshot(bridge, matches, output);
+            report(bridge);
+            result?;
+        }
+        (command @ ("toggle" | "dim" | "brighten" | "warmer" | "cooler"), Some(matches)) => {
+            adjust(session, command, matches, output)?
+        }
+        ("daemon", Some(matches)) => daemon(session, matches, output)?,
+        ("adaptive", Some(matches)) => adaptive(session, matches, output)?,
+        ("serve", Some(matches)) => {
+            use huemanity::server::Server;
+
+            let bridge = session.take();
+            let token = match matches.value_of("token") {
+                Some(token) => token.to_owned(),
+                None => std::env::var("HUE_SERVE_TOKEN").unwrap_or_else(|_| {
+                    let token = Server::generate_token();
+                    output.note(&format!(
+                        "No token configured, clients have to send `Authorization: Bearer {}`",
+                        token
+                    ));
+                    token
+                }),
+            };
+            let listen = matches.value_of("listen").unwrap_or("127.0.0.1:8080");
+            output.note(&format!("Serving on http://{}", listen));
+            let mut server = Server::new(bridge, token);
+            server.poll = matches.is_present("poll");
+            server.serve(listen)?;
+        }
+        ("tui", Some(matches)) => {
+            use huemanity::tui::Tui;
+
+            let mut tui = Tui::new(session.bridge());
+            if let Some(interval) = seconds_option(matches, "interval")? {
+                tui.interval = interval;
+            }
+            tui.run()?;
         }
-    } else if let Some(_) = matches.subcommand_matches("debug") {
-        let bridge = Bridge::link();
-        bridge.debug();
+        ("exporter", Some(matches)) => {
+            use huemanity::exporter::Exporter;
 
-    // NOTE: The following subcommands don't need a bridge
-    } else if let Some(_) = matches.subcommand_matches("discover") {
-        println!("Discovered bridges on the following IPs: {:?}", discover());
-    } else if let Some(_) = matches.subcommand_matches("clean") {
-        match cleanup() {
-            Ok(_) => println!("Cleaned up!"),
-            Err(e) => println!("Could not clean up because: {}", e),
+            let mut exporter = Exporter::new(session.bridge());
+            if let Some(interval) = seconds_option(matches, "interval")? {
+                exporter.interval = interval;
+            }
+            let listen = matches.value_of("listen").unwrap_or(":9184");
+            let listen = match listen.strip_prefix(':') {
+                Some(port) => format!("0.0.0.0:{}", port),
+                None => listen.to_owned(),
+            };
+            output.note(&format!("Serving metrics on http://{}/metrics", listen));
+            exporter.serve(&listen)?;
+        }
+        #[cfg(feature = "midi")]
+        ("midi", Some(matches)) => midi(session, matches, output)?,
+        #[cfg(feature = "audio")]
+        ("audio", Some(matches)) => audio(session, matches, output)?,
+        #[cfg(feature = "mqtt")]
+        ("mqtt", Some(matches)) => mqtt(session, matches, output)?,
+        ("watch", Some(matches)) if matches.is_present("poll") => {
+            let mut watcher = Watcher::new(session.bridge());
+            if let Some(interval) = seconds_option(matches, "interval")? {
+                watcher.interval = interval;
+            }
+            if let Some(failures) = parse_option(matches, "failures")? {
+                watcher.max_failures = failures;
+            }
+            watcher.run(|change| output.event(&change, &change))?;
+        }
+        ("watch", _) => {
+            for message in session.bridge().clip()?.events()? {
+                output.event(&message, &message);
+            }
+        }
+        ("shell", _) if session.bridge.is_some() => output.note("Already in the shell"),
+        ("shell", _) => shell(session, output)?,
+
+        ("names", Some(matches)) if session.refresh => {
+            let bridge = session.bridge();
+            // asking again gives the reason the refresh failed
+            let result = bridge
+                .topology()
+                .map_or_else(|| Topology::fetch(bridge), Ok);
+            report(bridge);
+            print_names(&result?.names(), matches, output)?;
+        }
+
+        // NOTE: The following subcommands don't need a bridge
+        ("names", Some(matches)) => {
+            let topology = Topology::load(&Topology::default_path()).unwrap_or_default();
+            print_names(&topology.names(), matches, output)?;
+        }
+        ("completions", Some(matches)) => {
+            let shell = matches.value_of("SHELL").unwrap_or_default();
+            print!("{}", completions(shell)?);
+        }
+        ("man", _) => print!("{}", man()?),
+        ("discover", _) | ("search", _) => {
+            let bridges = discover()?;
+            let records: Vec<serde_json::Value> = bridges
+                .iter()
+                .map(|ip| serde_json::json!({ "ip": ip }))
+                .collect();
+            output.result(
+                &records,
+                &format!("Discovered bridges on the following IPs: {:?}", bridges),
+            )?;
+        }
+        ("clean", _) => {
+            cleanup()?;
+            output.result(&serde_json::json!({ "cleaned": true }), "Cleaned up!")?;
+        }
+        _ => {
+            return Err(invalid(format!(
+                "a subcommand is needed\n\n{}",
+                matches.usage()
+            )))
+        }
+    }
+    Ok(())
+}
+
+/// Resolves the `toggle`, `dim`, `brighten`, `warmer` and `cooler` subcommands
+fn adjust(
+    session: &mut Session,
+    command: &str,
+    matches: &ArgMatches,
+    output: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    // what changes, by how much, in percent for brightness and mired for warmth
+    let by = matches.value_of("by");
+    let change = match command {
+        "dim" | "brighten" => {
+            let percent = parse_percent(by.unwrap_or("10"))?;
+            let sign = if command == "dim" { -1.0 } else { 1.0 };
+            Some(("brightness", sign * percent))
+        }
+        "warmer" | "cooler" => {
+            let mired: f32 = by.unwrap_or("50").trim().parse().map_err(|_| {
+                invalid(format!(
+                    "`{}` is not a number of mired",
+                    by.unwrap_or_default()
+                ))
+            })?;
+            let sign = if command == "cooler" { -1.0 } else { 1.0 };
+            Some(("mired", sign * mired.round()))
+        }
+        _ => None,
+    };
+
+    let bridge = session.bridge();
+    let name = matches.value_of("TARGET").unwrap_or_default();
+    let result = if matches.is_present("group") {
+        bridge.resolve("groups", name).map(Selector::Group)
+    } else {
+        bridge.select(name)
+    }
+    .and_then(|selector| {
+        let on = match change {
+            Some(("brightness", percent)) => bridge.brighten(selector, percent).map(|_| None),
+            Some((_, mired)) => bridge.warmer(selector, mired as i32).map(|_| None),
+            None => bridge.toggle(selector).map(Some),
+        };
+        Ok((selector, on?))
+    });
+    report(bridge);
+    match (result?, change) {
+        ((selector, Some(on)), _) => output.result(
+            &serde_json::json!({ "target": selector, "on": on }),
+            &format!("Turned {} {}", selector, if on { "on" } else { "off" }),
+        ),
+        ((selector, None), Some((what, by))) => {
+            output.result(&serde_json::json!({ "target": selector, what: by }), "")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Resolves the `entertainment` subcommands
+fn entertainment(
+    bridge: &Bridge,
+    matches: &ArgMatches,
+    output: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    match matches.subcommand() {
+        ("list", _) => {
+            let groups = bridge.entertainment_groups()?;
+            let mut plain = Vec::new();
+            for (id, group) in &groups {
+                let streaming = match &group.stream {
+                    Some(stream) if stream.active => {
+                        format!("streaming to {}", stream.owner.as_deref().unwrap_or("?"))
+                    }
+                    _ => "not streaming".to_owned(),
+                };
+                plain.push(format!(
+                    "{}: {} ({}, {})",
+                    id,
+                    group.name,
+                    group.class.as_deref().unwrap_or("?"),
+                    streaming
+                ));
+                for (light, location) in &group.locations {
+                    plain.push(format!("    light {} at {:?}", light, location));
+                }
+            }
+            output.result(&groups, &plain.join("\n"))?;
+        }
+        ("create", Some(matches)) => {
+            let class = matches
+                .value_of("class")
+                .unwrap_or("Free")
+                .parse::<EntertainmentClass>()
+                .map_err(invalid)?;
+            let lights = matches
+                .values_of("LIGHTS")
+                .into_iter()
+                .flatten()
+                .map(str::parse)
+                .collect::<Result<Vec<u8>, _>>()?;
+            let id = bridge.create_entertainment_group(
+                matches.value_of("NAME").unwrap_or_default(),
+                class,
+                &lights,
+            )?;
+            output.result(
+                &serde_json::json!({ "id": id }),
+                &format!("Created entertainment area {}", id),
+            )?;
+        }
+        ("locations", Some(matches)) => {
+            let group = bridge.resolve("groups", matches.value_of("GROUP").unwrap_or_default())?;
+            let file = std::fs::read_to_string(matches.value_of("FILE").unwrap_or_default())?;
+            let locations: Locations = serde_json::from_str(&file)?;
+            bridge.set_locations(group, &locations)?;
+            output.result(
+                &serde_json::json!({ "group": group, "updated": locations.len() }),
+                &format!("Updated {} light locations", locations.len()),
+            )?;
+        }
+        ("stream", Some(matches)) => {
+            let group = bridge.resolve("groups", matches.value_of("GROUP").unwrap_or_default())?;
+            let active = matches.value_of("ACTION") == Some("start");
+            bridge.set_streaming(group, active)?;
+            output.result(
+                &serde_json::json!({ "group": group, "streaming": active }),
+                "",
+            )?;
+        }
+        ("delete", Some(matches)) => {
+            let group = bridge.resolve("groups", matches.value_of("GROUP").unwrap_or_default())?;
+            bridge.delete_group(group)?;
+            output.result(&serde_json::json!({ "deleted": group }), "")?;
+        }
+        _ => {
+            return Err(invalid(format!(
+                "a subcommand is needed\n\n{}",
+                matches.usage()
+            )))
+        }
+    }
+    Ok(())
+}
+
+/// Resolves the `effect` subcommand
+fn effect(
+    bridge: &Bridge,
+    matches: &ArgMatches,
+    output: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    let lights = matches
+        .values_of("lights")
+        .into_iter()
+        .flatten()
+        .map(|light| bridge.resolve("lights", light))
+        .collect::<Result<Vec<u8>, _>>()?;
+    let color = matches
+        .value_of("color")
+        .map(str::parse::<Rgb>)
+        .transpose()
+        .map_err(invalid)?;
+    let seconds = parse_option(matches, "seconds")?;
+    let name = matches.value_of("NAME").unwrap_or_default();
+    let mut effect = Effect::named(name, color, seconds).map_err(invalid)?;
+    if let Effect::Fade { from, to, .. } = &mut effect {
+        if let Some(state) = matches.value_of("from") {
+            *from = parse_state(state)?;
+        }
+        if let Some(state) = matches.value_of("to") {
+            *to = parse_state(state)?;
+        }
+    }
+
+    // Ctrl-C and --duration stop the effect between two requests
+    let stop = interrupt()?;
+    if let Some(duration) = seconds_option(matches, "duration")? {
+        let timer = stop.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(duration);
+            timer.store(true, Ordering::Relaxed);
+        });
+    }
+    let result = effect.run(bridge, &lights, &stop);
+    uninterrupt();
+    result?;
+    output.result(&serde_json::json!({ "effect": name, "lights": lights }), "")
+}
+
+/// The command Ctrl-C stops, instead of quitting
+static INTERRUPTED: Mutex<Option<Arc<AtomicBool>>> = Mutex::new(None);
+
+/// Makes Ctrl-C set the returned flag rather than quit, until `uninterrupt`.
+/// There can only be one handler, so the shell shares it between commands.
+fn interrupt() -> Result<Arc<AtomicBool>, Box<dyn Error>> {
+    static HANDLER: Once = Once::new();
+    let mut result = Ok(());
+    HANDLER.call_once(|| {
+        result = ctrlc::set_handler(|| match &*INTERRUPTED.lock().unwrap() {
+            Some(flag) => flag.store(true, Ordering::Relaxed),
+            None => std::process::exit(130),
+        });
+    });
+    result?;
+    let flag = Arc::new(AtomicBool::new(false));
+    *INTERRUPTED.lock().unwrap() = Some(flag.clone());
+    Ok(flag)
+}
+
+/// Makes Ctrl-C quit again
+fn uninterrupt() {
+    *INTERRUPTED.lock().unwrap() = None;
+}
+
+/// Parses a state given either as json or as a colour to turn the lights on in
+fn parse_state(state: &str) -> Result<SendableState, Box<dyn Error>> {
+    if state.trim_start().starts_with('{') {
+        return Ok(serde_json::from_str(state)?);
+    }
+    let color = state.parse::<Rgb>().map_err(invalid)?;
+    Ok(state!(on: true, bri: 254, xy: color.xy()))
+}
+
+/// Resolves the `play` subcommand, steering the show with lines typed on stdin
+fn play(bridge: &Bridge, matches: &ArgMatches, output: &mut Output) -> Result<(), Box<dyn Error>> {
+    use huemanity::sequence::*;
+    use std::io::BufRead;
+
+    let file = matches.value_of("FILE").unwrap_or_default();
+    let timeline = Show::load(file)?.timeline(bridge)?;
+    let mut player = Player::new(bridge, timeline);
+    if let Some(start) = seconds_option(matches, "start")? {
+        player.seek(start.as_secs_f32())?;
+    }
+
+    let (controls, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines().map_while(Result::ok) {
+            let mut words = line.split_whitespace();
+            let control = match (words.next(), words.next().map(str::parse)) {
+                (None, _) => Control::Key,
+                (Some("p"), _) => Control::Toggle,
+                (Some("s"), Some(Ok(time))) => Control::Seek(time),
+                (Some("q"), _) => Control::Stop,
+                _ => {
+                    eprintln!("Enter continues, p pauses, s <seconds> seeks and q quits");
+                    continue;
+                }
+            };
+            if controls.send(control).is_err() {
+                break;
+            }
+        }
+    });
+    output.note(&format!(
+        "Playing {} (Enter continues, p pauses, s <seconds> seeks and q quits)",
+        file
+    ));
+    player.run(&receiver)
+}
+
+/// Resolves the `snapshot` subcommand
+fn snapshot(
+    bridge: &Bridge,
+    matches: &ArgMatches,
+    output: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    let file = matches.value_of("FILE").unwrap_or_default();
+    if matches.value_of("ACTION") == Some("save") {
+        let snapshot = bridge.snapshot()?;
+        std::fs::write(file, serde_json::to_string_pretty(&snapshot)?)?;
+        output.result(
+            &serde_json::json!({ "saved": snapshot.len(), "file": file }),
+            &format!("Saved {} lights to {}", snapshot.len(), file),
+        )
+    } else {
+        let snapshot: Snapshot = serde_json::from_str(&std::fs::read_to_string(file)?)?;
+        bridge.restore(&snapshot)?;
+        output.result(
+            &serde_json::json!({ "restored": snapshot.len(), "file": file }),
+            &format!("Restored {} lights from {}", snapshot.len(), file),
+        )
+    }
+}
+
+/// Resolves the `daemon` subcommand
+fn daemon(
+    session: &mut Session,
+    matches: &ArgMatches,
+    output: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    use huemanity::scheduler::Schedule;
+
+    let path = matches
+        .value_of("FILE")
+        .map_or_else(Schedule::default_path, str::to_owned);
+    let schedule = Schedule::load(&path)?;
+    if matches.is_present("list") {
+        let count = parse_option(matches, "count")?.unwrap_or(10);
+        let mut records = Vec::new();
+        let mut plain = Vec::new();
+        for (time, job) in schedule.upcoming(chrono::Local::now(), count) {
+            records.push(serde_json::json!({
+                "time": time.to_rfc3339(),
+                "job": job.name,
+                "trigger": job.trigger().to_string(),
+            }));
+            plain.push(format!(
+                "{}  {} ({})",
+                time.format("%a %Y-%m-%d %H:%M:%S"),
+                job.name,
+                job.trigger()
+            ));
+        }
+        return output.result(&records, &plain.join("\n"));
+    }
+
+    let bridge = session.bridge();
+    output.note(&format!(
+        "Running {} jobs from {}",
+        schedule.jobs.len(),
+        path
+    ));
+    schedule.run(bridge, &Schedule::runs_path(&path), |job, result| {
+        match result {
+            Ok(()) => output.event(
+                &serde_json::json!({ "job": job.name, "ok": true }),
+                format!("Ran `{}`", job.name),
+            ),
+            Err(e) => output.event(
+                &serde_json::json!({ "job": job.name, "ok": false, "error": e.to_string() }),
+                format!("Job `{}` failed: {}", job.name, e),
+            ),
+        }
+        report(bridge);
+    })
+}
+
+/// Resolves the `adaptive` subcommand
+fn adaptive(
+    session: &mut Session,
+    matches: &ArgMatches,
+    output: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    use huemanity::adaptive::*;
+
+    let mut curve = Curve::new(
+        value_t!(matches, "latitude", f64)?,
+        value_t!(matches, "longitude", f64)?,
+    );
+    if let Some(warmest) = parse_option(matches, "warmest")? {
+        curve.warmest = warmest;
+    }
+    if let Some(coolest) = parse_option(matches, "coolest")? {
+        curve.coolest = coolest;
+    }
+    if let Some(dimmest) = parse_option(matches, "dimmest")? {
+        curve.dimmest = dimmest;
+    }
+    if let Some(brightest) = parse_option(matches, "brightest")? {
+        curve.brightest = brightest;
+    }
+
+    if matches.is_present("curve") {
+        let midnight = chrono::Local::now()
+            .date_naive()
+            .and_hms_opt(0, 0, 0)
+            .and_then(|t| t.and_local_timezone(chrono::Local).earliest())
+            .ok_or("today has no midnight")?;
+        let mut records = Vec::new();
+        let mut plain = Vec::new();
+        for hour in 0..24 {
+            let time = midnight + chrono::Duration::hours(hour);
+            let (kelvin, brightness) = curve.at(time.with_timezone(&chrono::Utc));
+            records.push(serde_json::json!({
+                "time": time.format("%H:%M").to_string(),
+                "kelvin": kelvin,
+                "brightness": brightness,
+            }));
+            plain.push(format!(
+                "{}  {}K {}%",
+                time.format("%H:%M"),
+                kelvin,
+                brightness
+            ));
+        }
+        return output.result(&records, &plain.join("\n"));
+    }
+
+    let bridge = session.bridge();
+    let lights = matches
+        .values_of("lights")
+        .into_iter()
+        .flatten()
+        .map(|light| bridge.resolve("lights", light))
+        .collect::<Result<Vec<u8>, _>>()?;
+    let mut adaptive = Adaptive::new(bridge, curve, lights);
+    if let Some(backoff) = parse_option::<u64>(matches, "backoff")? {
+        adaptive.backoff = Duration::from_secs(backoff.saturating_mul(60));
+    }
+    adaptive.run(|light, change| {
+        output.event(
+            &serde_json::json!({ "light": light, "change": change }),
+            format!("Leaving light {} alone after {}", light, change),
+        )
+    })
+}
+
+/// Resolves the `midi` subcommand
+#[cfg(feature = "midi")]
+fn midi(
+    session: &mut Session,
+    matches: &ArgMatches,
+    output: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    use huemanity::midi::{self, listen, ports, Mapper, Mapping};
+
+    if matches.is_present("ports") {
+        let ports = ports()?;
+        return output.result(&ports, &ports.join("\n"));
+    }
+
+    let mapping = Mapping::load(
+        matches
+            .value_of("MAPPING")
+            .ok_or("a mapping file is needed")?,
+    )?;
+    let bridge = session.bridge();
+    let sink = match matches.value_of("stream") {
+        Some(group) => midi::Output::Stream(bridge.stream(bridge.resolve("groups", group)?)?),
+        None => midi::Output::Rest(bridge),
+    };
+    listen(matches.value_of("port"), Mapper::new(mapping, sink))
+}
+
+/// Resolves the `audio` subcommand
+#[cfg(feature = "audio")]
+fn audio(
+    session: &mut Session,
+    matches: &ArgMatches,
+    output: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    use huemanity::audio::*;
+
+    let mapping = AudioMapping::load(matches.value_of("MAPPING").unwrap_or_default())?;
+    match matches.value_of("wav") {
+        Some(wav) => {
+            let (samples, sample_rate) = read_wav(wav)?;
+            let cues = render(&samples, sample_rate, mapping)?;
+            if matches.is_present("render") {
+                output.result(&cues, &serde_json::to_string_pretty(&cues)?)
+            } else {
+                play(session.bridge(), &cues)
+            }
+        }
+        None => listen(session.bridge(), matches.value_of("device"), mapping),
+    }
+}
+
+/// Resolves the `mqtt` subcommand
+#[cfg(feature = "mqtt")]
+fn mqtt(
+    session: &mut Session,
+    matches: &ArgMatches,
+    output: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    use huemanity::mqtt::Gateway;
+
+    let broker = matches.value_of("broker").unwrap_or("localhost");
+    let (host, port) = match broker.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(invalid)?),
+        None => (broker, 1883),
+    };
+    let password = matches
+        .value_of("password")
+        .map(str::to_owned)
+        .or_else(|| std::env::var("HUE_MQTT_PASSWORD").ok());
+    let credentials = matches
+        .value_of("username")
+        .map(|username| (username, password.as_deref().unwrap_or_default()));
+
+    let mut gateway = Gateway::new(session.bridge());
+    if let Some(base) = matches.value_of("base") {
+        gateway.base = base.trim_end_matches('/').to_owned();
+    }
+    if matches.is_present("no_discovery") {
+        gateway.discovery_prefix = None;
+    } else if let Some(prefix) = matches.value_of("discovery") {
+        gateway.discovery_prefix = Some(prefix.to_owned());
+    }
+    if let Some(interval) = seconds_option(matches, "interval")? {
+        gateway.interval = interval;
+    }
+    output.note(&format!("Publishing under {}/", gateway.base));
+    gateway.run(host, port, credentials, |event| {
+        output.event(&serde_json::json!({ "event": event }), event)
+    })
+}
+
+/// Resolves the `shell` subcommand: reads commands until `exit` or Ctrl-D and runs
+/// them with the same bridge
+fn shell(session: &mut Session, output: &Output) -> Result<(), Box<dyn Error>> {
+    use rustyline::error::ReadlineError;
+
+    // `huemanity --dry-run shell` rehearses every line, otherwise only the lines asking for it
+    let dry_run = session.dry_run;
+    let names = session.names();
+    let history = dirs::home_dir().map(|home| home.join(".huemanity_history"));
+    let mut editor = rustyline::Editor::new()?;
+    editor.set_helper(Some(ShellHelper {
+        // clap 2 has no other way to list the subcommands
+        commands: app()
+            .p
+            .subcommands
+            .iter()
+            .map(|command| command.get_name().to_owned())
+            .chain(["refresh", "exit"].iter().map(|c| (*c).to_owned()))
+            .collect(),
+        names,
+    }));
+    if let Some(history) = &history {
+        // there is no history the first time
+        let _ = editor.load_history(history);
+    }
+    output.note("Type a command (like `state '{\"on\": true}' Desk`), `help`, `refresh` or `exit`");
+
+    loop {
+        let line = match editor.readline("hue> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let words = match split_words(&line) {
+            Ok(words) => words,
+            Err(e) => {
+                output.note(&e);
+                continue;
+            }
+        };
+        if words.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line.as_str())?;
+        match words[0].as_str() {
+            "exit" | "quit" => break,
+            "refresh" => {
+                session.refresh = true;
+                let names = session.names();
+                output.note(&format!("Found {} names", names.len()));
+                if let Some(helper) = editor.helper_mut() {
+                    helper.names = names;
+                }
+            }
+            _ => match app()
+                .get_matches_from_safe(std::iter::once("huemanity".to_owned()).chain(words))
+            {
+                // each line can pick its own format, the shell's being the default
+                Ok(matches) => {
+                    let mut line = Output::new(&matches, output.format);
+                    session.dry_run = dry_run;
+                    if let Err(e) = run(&matches, session, &mut line) {
+                        line.error(&*e);
+                    }
+                }
+                // help and usage errors both come through here, only help is output
+                Err(e) if e.use_stderr() => eprintln!("{}", e.message),
+                Err(e) => println!("{}", e.message),
+            },
+        }
+    }
+    if let Some(history) = &history {
+        editor.save_history(history)?;
+    }
+    Ok(())
+}
+
+/// Splits a shell line into words, keeping text in single or double quotes together
+fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote: Option<char> = None;
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '"') | (None, '\'') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err("a quote is not closed".to_owned());
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Completes commands at the start of a line and light, group and scene names
+/// after that
+struct ShellHelper {
+    commands: Vec<String>,
+    names: Vec<String>,
+}
+
+impl rustyline::completion::Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let before = &line[..pos];
+        // a name being typed in quotes can have spaces in it
+        let start = match before.matches('"').count() % 2 {
+            1 => before.rfind('"').unwrap_or(0),
+            _ => before.rfind(char::is_whitespace).map_or(0, |i| i + 1),
+        };
+        let typed = before[start..].trim_start_matches('"').to_lowercase();
+        let first = before[..start].trim().is_empty();
+        let options = if first { &self.commands } else { &self.names };
+        let candidates = options
+            .iter()
+            .filter(|option| option.to_lowercase().starts_with(&typed))
+            .map(|option| {
+                if option.contains(' ') {
+                    format!("\"{}\"", option)
+                } else {
+                    option.clone()
+                }
+            })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl rustyline::hint::Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl rustyline::highlight::Highlighter for ShellHelper {}
+
+impl rustyline::validate::Validator for ShellHelper {}
+
+impl rustyline::Helper for ShellHelper {}
+
+/// Prints the names of the kinds asked for, or all of them
+fn print_names(
+    names: &Names,
+    matches: &ArgMatches,
+    output: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    let kinds: Vec<&str> = matches.values_of("KIND").into_iter().flatten().collect();
+    let names = if kinds.is_empty() {
+        names.all()
+    } else {
+        let mut picked = Names::default();
+        for kind in kinds {
+            match kind {
+                "lights" => picked.lights = names.lights.clone(),
+                "groups" => picked.groups = names.groups.clone(),
+                _ => picked.scenes = names.scenes.clone(),
+            }
+        }
+        picked.all()
+    };
+    output.result(&names, &names.join("\n"))
+}
+
+/// Where the completion scripts offer names: the command (with the subcommand of
+/// `entertainment`), the argument or option taking the name, and the kinds of names
+const NAME_ARGS: &[(&str, &str, &str)] = &[
+    ("state", "STATE", "lights"),
+    ("state", "LIGHT", "lights"),
+    ("toggle", "TARGET", "lights groups"),
+    ("dim", "TARGET", "lights groups"),
+    ("brighten", "TARGET", "lights groups"),
+    ("warmer", "TARGET", "lights groups"),
+    ("cooler", "TARGET", "lights groups"),
+    ("effect", "lights", "lights"),
+    ("adaptive", "lights", "lights"),
+    ("entertainment locations", "GROUP", "groups"),
+    ("entertainment stream", "GROUP", "groups"),
+    ("entertainment delete", "GROUP", "groups"),
+    ("midi", "stream", "groups"),
+];
+
+/// The completion script for a shell: what clap generates for the commands and
+/// options, with names from `huemanity names` added where they go
+fn completions(shell: &str) -> Result<String, Box<dyn Error>> {
+    let mut script = Vec::new();
+    app().gen_completions_to("huemanity", shell.parse().map_err(invalid)?, &mut script);
+    let script = String::from_utf8(script)?;
+    let is_option = |arg: &str| arg.chars().all(|c| c.is_lowercase());
+    // shells that don't tell positional arguments apart only need a command once
+    let mut commands = NAME_ARGS.to_vec();
+    commands.dedup_by_key(|(command, arg, _)| (*command, is_option(arg)));
+
+    Ok(match shell {
+        "bash" => {
+            let mut cases = String::new();
+            for (command, arg, kinds) in &commands {
+                let option = if is_option(arg) {
+                    format!("--{}", arg)
+                } else {
+                    String::new()
+                };
+                let pattern = format!("\"{0}:{1}\"|\"{0}:{1} \"*", option, command);
+                let _ = writeln!(cases, "        {}) kinds=\"{}\" ;;", pattern, kinds);
+            }
+            let script = script.replace("complete -F _huemanity ", "complete -F _huemanity_names ");
+            format!(
+                r#"{}
+# names of lights and groups where they go, the rest is left to _huemanity
+_huemanity_names() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}" prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    local i word command="" option="" kinds="" names
+    # the words typed so far, without options
+    for (( i = 1; i < COMP_CWORD; i++ )); do
+        word="${{COMP_WORDS[i]}}"
+        case "${{word}}" in
+            -o|--output) (( i++ )) ;;
+            -*) ;;
+            *) command="${{command:+${{command}} }}${{word}}" ;;
+        esac
+    done
+    # the option whose value is being typed
+    if [[ ${{prev}} == -* ]]; then
+        option="${{prev}}"
+    fi
+    case "${{option}}:${{command}}" in
+{}    esac
+    if [[ -n ${{kinds}} && ${{cur}} != -* ]]; then
+        names="$(huemanity names ${{kinds}} 2>/dev/null)"
+        local IFS=$'\n'
+        COMPREPLY=( $(compgen -W "${{names}}" -- "${{cur}}") )
+        COMPREPLY=( "${{COMPREPLY[@]// /\\ }}" )
+        return 0
+    fi
+    _huemanity "$@"
+}}
+"#,
+                script.trim_end(),
+                cases
+            )
+        }
+        "zsh" => {
+            // clap has no way to set the action of an argument, so it is swapped in
+            let mut lines = Vec::new();
+            let mut command = "";
+            for line in script.lines() {
+                if let Some(label) = line.strip_prefix('(').and_then(|l| l.strip_suffix(')')) {
+                    command = label;
+                }
+                let named = NAME_ARGS.iter().find(|(c, arg, _)| {
+                    c.rsplit(' ').next() == Some(command)
+                        && if is_option(arg) {
+                            line.starts_with(&format!("'*--{}=[", arg))
+                                || line.starts_with(&format!("'--{}=[", arg))
+                        } else {
+                            line.starts_with(&format!("':{} -- ", arg))
+                        }
+                });
+                lines.push(match named {
+                    Some((_, arg, kinds)) if is_option(arg) => {
+                        line.replacen("]' \\", &format!("]: :_huemanity_names {}' \\", kinds), 1)
+                    }
+                    Some((_, _, kinds)) => {
+                        line.replacen(":_files'", &format!(":_huemanity_names {}'", kinds), 1)
+                    }
+                    None => line.to_owned(),
+                });
+            }
+            let script = lines.join("\n");
+            let (script, call) = script
+                .rsplit_once("\n_huemanity \"$@\"")
+                .unwrap_or((&script, ""));
+            format!(
+                r#"{}
+(( $+functions[_huemanity_names] )) ||
+_huemanity_names() {{
+    local -a names
+    names=(${{(f)"$(huemanity names "$@" 2>/dev/null)"}})
+    compadd -a names
+}}
+
+_huemanity "$@"{}
+"#,
+                script,
+                call.trim_end()
+            )
+        }
+        "fish" => {
+            let mut script = script;
+            for (command, arg, kinds) in &commands {
+                let command = command.rsplit(' ').next().unwrap_or_default();
+                let option = if is_option(arg) {
+                    format!(" -l {} -r", arg)
+                } else {
+                    String::new()
+                };
+                let _ = writeln!(
+                    script,
+                    "complete -c huemanity -n \"__fish_seen_subcommand_from {}\"{} -f -a \"(huemanity names {})\"",
+                    command, option, kinds
+                );
+            }
+            script
+        }
+        _ => {
+            let mut script = script;
+            for (command, _, kinds) in &commands {
+                let case = format!("'huemanity;{}' {{\n", command.replace(' ', ";"));
+                let names = format!(
+                    "{}            huemanity names {} 2>$null | ForEach-Object {{ \
+                     [CompletionResult]::new($(if ($_ -match ' ') {{ \"'$_'\" }} else {{ $_ }}), $_, \
+                     [CompletionResultType]::ParameterValue, $_) }}\n",
+                    case, kinds
+                );
+                script = script.replacen(&case, &names, 1);
+            }
+            script
+        }
+    })
+}
+
+/// The manual page, made of the help of every command
+fn man() -> Result<String, Box<dyn Error>> {
+    fn help(command: &App<'static, 'static>, name: &str) -> Result<String, Box<dyn Error>> {
+        let mut text = Vec::new();
+        command
+            .clone()
+            .bin_name(name)
+            .set_term_width(78)
+            .write_long_help(&mut text)?;
+        Ok(String::from_utf8(text)?)
+    }
+    fn escape(text: &str) -> String {
+        text.lines()
+            .map(|line| {
+                let line = line.trim_end().replace('\\', "\\e").replace('-', "\\-");
+                if line.starts_with('.') || line.starts_with('\'') {
+                    format!("\\&{}", line)
+                } else {
+                    line
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+    fn commands(
+        page: &mut String,
+        command: &App<'static, 'static>,
+        name: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        // clap 2 has no other way to list the subcommands
+        let mut subcommands: Vec<&App> = command.p.subcommands.iter().collect();
+        subcommands.sort_by_key(|command| command.get_name());
+        for subcommand in subcommands {
+            let name = format!("{} {}", name, subcommand.get_name());
+            let _ = writeln!(page, ".SS {}", escape(&name));
+            let _ = writeln!(
+                page,
+                ".nf\n{}\n.fi",
+                escape(help(subcommand, &name)?.trim_end())
+            );
+            commands(page, subcommand, &name)?;
+        }
+        Ok(())
+    }
+
+    let app = app();
+    let mut page = format!(
+        ".TH HUEMANITY 1 \"\" \"huemanity {}\" \"User Commands\"\n",
+        app.p.meta.version.unwrap_or_default()
+    );
+    let _ = writeln!(
+        page,
+        ".SH NAME\nhuemanity \\- {}",
+        escape(app.p.meta.about.unwrap_or_default())
+    );
+    let _ = writeln!(
+        page,
+        ".SH SYNOPSIS\n.B huemanity\n[\\fIOPTIONS\\fR] \\fICOMMAND\\fR [\\fIARGS\\fR]"
+    );
+    let _ = writeln!(
+        page,
+        ".SH DESCRIPTION\n.nf\n{}\n.fi",
+        escape(help(&app, "huemanity")?.trim_end())
+    );
+    page += ".SH COMMANDS\n";
+    commands(&mut page, &app, "huemanity")?;
+    page += r#".SH EXIT STATUS
+.TP
+.B 0
+Success
+.TP
+.B 1
+Any other failure
+.TP
+.B 2
+Bad input: arguments, states, colours, names or malformed files
+.TP
+.B 3
+The bridge could not be reached
+.TP
+.B 4
+The bridge refused the request
+.SH ENVIRONMENT
+.TP
+.B HUE_IP, HUE_KEY
+Address of the bridge and the key registered with it, overriding ~/.huemanity
+.TP
+.B HUE_SERVE_TOKEN
+Token clients of \fBhuemanity serve\fR have to send
+.TP
+.B HUE_MQTT_PASSWORD
+Password for the broker of \fBhuemanity mqtt\fR
+.SH FILES
+.TP
+.I ~/.huemanity
+Bridge address and key, written when registering
+.TP
+.I ~/.huemanity_cache.json
+Lights, groups and scenes kept so commands can skip asking the bridge
+.TP
+.I ~/.huemanity_history
+History of \fBhuemanity shell\fR
+.TP
+.I ~/.huemanity_schedule.yaml
+Jobs \fBhuemanity daemon\fR runs
+.TP
+.I ~/.huemanity_schedule.runs.json
+When each job last ran, so \fBhuemanity daemon\fR can catch up on jobs missed while it was down
+"#;
+    Ok(page)
+}
+
+/// Tells the user about anything that happened to the bridge connection
+/// while the command was running
+fn report(bridge: &Bridge) {
+    for event in bridge.events() {
+        match event {
+            BridgeEvent::Relocated { id, from, to } => {
+                warn!("Bridge {} moved from {} to {}", id, from, to)
+            }
+            BridgeEvent::ConfigNotSaved { reason } => {
+                warn!("Could not save the new bridge address: {}", reason)
+            }
         }
     }
 }
@@ -102,6 +1787,5 @@ fn main() {
 // TODO: add a nice way to print out information about the system or lights and maybe dump it
 // TODO: add usage of structs for state
 // TODO: remove nasty unwraps
-// TODO: add blink function
 // TODO: Refactor CLI
 // TODO: implement more serialisation things like XY vs HS etc.
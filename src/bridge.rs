@@ -1,6 +1,11 @@
+use crate::cache::{self, Topology};
+use crate::clip::ClipBridge;
+use crate::entertainment::{decode_hex, Streamer, STREAMING_PORT};
+use crate::error::HueError;
 use crate::lightstructs::*;
-use dotenv;
 use reqwest::Client;
+use serde::de::DeserializeOwned;
+use serde::*;
 use serde_json::value::Value;
 use std::collections::BTreeMap;
 use std::env;
@@ -8,11 +13,82 @@ use std::error::Error;
 use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
+use std::sync::{Mutex, RwLock};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tracing::{debug, debug_span, info, warn};
 
 type Lights = BTreeMap<u8, Light>;
 
+/// Something that happened to the bridge connection behind the scenes while
+/// carrying out a request. Drain these with `Bridge.events`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BridgeEvent {
+    /// The bridge stopped answering on its stored address and was found again
+    /// on a new one through discovery (usually after a new DHCP lease).
+    Relocated {
+        id: String,
+        from: String,
+        to: String,
+    },
+    /// The new address could not be written back to the `.huemanity` file,
+    /// so the next `link` will have to rediscover the bridge again.
+    ConfigNotSaved { reason: String },
+}
+
+/// Contents of the `.huemanity` file
+struct Config {
+    ip: String,
+    key: String,
+    id: Option<String>,
+    cert: Option<String>,
+    clientkey: Option<String>,
+}
+
+impl Config {
+    /// Detects if a `HUE_IP` and `HUE_KEY` are available in the environment.
+    /// `HUE_ID`, `HUE_CERT` and `HUE_CLIENTKEY` are optional as older config files don't have them.
+    fn detect(filename: &str) -> Result<Self, Box<dyn Error>> {
+        dotenv::from_filename(filename)?;
+        Ok(Config {
+            ip: env::var("HUE_IP")?,
+            key: env::var("HUE_KEY")?,
+            id: env::var("HUE_ID").ok(),
+            cert: env::var("HUE_CERT").ok(),
+            clientkey: env::var("HUE_CLIENTKEY").ok(),
+        })
+    }
+
+    /// Adds the bridge ID to configs written before it was stored, saving them to
+    /// `filename` again. Nothing changes if the bridge doesn't tell its ID.
+    fn upgrade(&mut self, filename: &str, identify: Identify) {
+        if self.id.is_some() {
+            return;
+        }
+        if let Ok(id) = identify(&self.ip) {
+            self.id = Some(id);
+            if let Err(e) = self.save(filename) {
+                warn!("Could not store the bridge ID: {}", e);
+            }
+        }
+    }
+
+    /// Writes the config out to the `.huemanity` file
+    fn save(&self, filename: &str) -> std::io::Result<()> {
+        let mut contents = format!("HUE_IP=\"{}\"\nHUE_KEY=\"{}\"\n", self.ip, self.key);
+        if let Some(id) = &self.id {
+            contents.push_str(&format!("HUE_ID=\"{}\"\n", id));
+        }
+        if let Some(cert) = &self.cert {
+            contents.push_str(&format!("HUE_CERT=\"{}\"\n", cert));
+        }
+        if let Some(clientkey) = &self.clientkey {
+            contents.push_str(&format!("HUE_CLIENTKEY=\"{}\"\n", clientkey));
+        }
+        File::create(filename)?.write_all(contents.as_ref())
+    }
+}
+
 /// The bridge struct represents a HUE bridge.
 /// The constructor for this struct `link`, tries to
 /// detect the lights and is able to send new state to either
@@ -25,32 +101,85 @@ type Lights = BTreeMap<u8, Light>;
 /// If you don't have the key registered yet, the link function will guide you through the
 /// process to register the key and save it to the `.huemanity` file that will be loaded by the CLI
 /// everytime.
+///
+/// The bridge ID is stored alongside the IP, so if the bridge stops answering (e.g. it got
+/// a new DHCP lease) it is rediscovered on the network and the stored address is updated.
 #[derive(Debug)]
 pub struct Bridge {
-    ip: String,
+    ip: RwLock<String>,
     key: String,
+    id: Option<String>,
+    cert: Mutex<Option<String>>,
+    clientkey: Option<String>,
     client: Client,
-    base_url: String,
+    config: String,
+    events: Mutex<Vec<BridgeEvent>>,
+    relocation: Mutex<Relocation>,
     pub light_ids: Vec<u8>,
     pub n_lights: u8,
+    /// The lights as of the last time the topology was fetched
     pub lights: Option<Lights>,
+    topology: RwLock<Option<Topology>>,
+    /// Where the topology is kept on disk
+    cache: String,
+    transport: Transport,
 }
 
-impl Bridge {
-    /// Detects if a `HUE_IP` and `HUE_KEY` are available in the environment
-    fn detect(filename: &str) -> Result<(String, String), Box<dyn Error>> {
-        dotenv::from_filename(filename)?;
-        let ip = env::var("HUE_IP")?;
-        let key = env::var("HUE_KEY")?;
-        Ok((ip, key))
+/// Tries at a request to the configured address before the bridge is looked for on
+/// the network. Only requests that can be sent twice without harm are tried again.
+const RELOCATE_AFTER: u32 = 3;
+/// Time between two searches for the bridge, as each one takes a few seconds
+const RELOCATE_INTERVAL: Duration = Duration::from_secs(60);
+/// Pause before trying the configured address again
+const RETRY_PAUSE: Duration = Duration::from_secs(1);
+
+/// Why a dry run can't go ahead without a topology to answer from
+const NOTHING_TO_REHEARSE: &str =
+    "nothing is cached to rehearse with, run a command without a dry run first";
+
+/// Asks the bridge at an address for its ID
+type Identify = fn(&str) -> Result<String, Box<dyn Error>>;
+
+/// How the bridge gets looked for on the network
+#[derive(Debug)]
+struct Relocation {
+    /// When the bridge was last looked for
+    searched: Option<Instant>,
+    /// Lists the bridges on the network
+    discover: fn() -> Result<Vec<String>, HueError>,
+    identify: Identify,
+}
+
+impl Default for Relocation {
+    fn default() -> Self {
+        Relocation {
+            searched: None,
+            discover,
+            identify: bridge_id,
+        }
     }
-    /// Waits for a button to be pressed on a given bridge or several bridges
+}
+
+/// How a bridge sends its requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// To the bridge, over HTTP
+    Http,
+    /// Nowhere: every request is logged with its method, path and body and gets a
+    /// made up successful answer, so whole automations can be rehearsed. Reads are
+    /// answered from the cached topology.
+    DryRun,
+}
+
+impl Bridge {
+    /// Waits for a button to be pressed on a given bridge or several bridges.
+    /// Returns the IP of the bridge and the `success` part of its response.
     fn wait_for_button(
         body: Value,
         ip: Option<&String>,
         ips: Option<Vec<String>>,
         client: Client,
-    ) -> (String, String) {
+    ) -> (String, Value) {
         // needed to avoid repetition of code
         let ping_it = |i: &String| -> Value {
             client
@@ -67,34 +196,31 @@ impl Bridge {
         // otherwise it will try to loop through the ips
         match (ip, ips) {
             (Some(bridge_ip), _) => {
-                let mut response = ping_it(&bridge_ip);
+                let mut response = ping_it(bridge_ip);
 
                 loop {
                     if response[0]["error"]["type"] == 101 {
-                        println!("Please press the hub button!");
+                        info!("Please press the hub button!");
                         sleep(Duration::from_secs(5));
-                        response = ping_it(&bridge_ip);
+                        response = ping_it(bridge_ip);
                     } else {
                         break;
                     }
                 }
 
-                (
-                    bridge_ip.to_string(),
-                    response[0]["success"]["username"].to_string(),
-                )
+                (bridge_ip.to_string(), response[0]["success"].clone())
             }
             (None, Some(ips)) => {
                 let mut response: Value = Value::Bool(true);
                 let mut bridge_ip = String::new();
                 loop {
-                    println!("Please press the hub button!");
+                    info!("Please press the hub button!");
                     sleep(Duration::from_secs(5));
                     // this chunk of code basically will loop through
                     // all the ips and check if any of them have the button
                     // pressed
                     for ip in &ips {
-                        response = ping_it(&ip);
+                        response = ping_it(ip);
                         if response[0]["error"]["type"] == 101 {
                             continue;
                         } else {
@@ -107,7 +233,7 @@ impl Bridge {
                     }
                 }
 
-                (bridge_ip, response[0]["success"]["username"].to_string())
+                (bridge_ip, response[0]["success"].clone())
             }
             (None, None) => panic!("No ips provided in order to wait for a button press!"),
         }
@@ -115,55 +241,67 @@ impl Bridge {
     /// Register the Bridge and save credentials to `~/.huemanity` file
     /// Can be used as a standalone function to get a key registered
     /// but the main use of this is through the `link` method.
-    fn register(configpath: &str) -> Result<(String, String), Box<dyn Error>> {
+    fn register(configpath: &str) -> Result<Config, Box<dyn Error>> {
         // TODO: currently uses file writting rather than some more clever serialisation and checking
         // TODO: could also take an optional setting string or config path ?
 
         // TODO: write a serialisation (serde) so one can load the bridge from config
         // Get user IP input and name for the app
-        println!("NOTE! Registration will create the `~/.huemanity` containing IP and KEY info");
+        info!("Registration will create the `~/.huemanity` containing IP and KEY info");
         let client = Client::new();
         let mut ip = String::new();
         let mut name = String::new();
 
-        // Try to find bridges through ssdp
-        let bridges = discover();
+        // Try to find bridges through ssdp, asking for the IP if that can't be done
+        let bridges = discover().unwrap_or_else(|e| {
+            warn!("{}", e);
+            Vec::new()
+        });
 
-        println!("Enter the desired app name (default: huemanity):");
+        // questions are asked whatever the verbosity, as the answer is waited for
+        eprintln!("Enter the desired app name (default: huemanity):");
         std::io::stdin().read_line(&mut name)?;
-        if name == "" {
+        if name.trim().is_empty() {
             name = "huemanity".to_owned();
         } else {
             name = name.trim().to_string();
         }
 
         // only use json! here because its a one of and writing serialisation for it is pointless
-        let body = serde_json::json!({ "devicetype": format!("{}", name) });
+        // the client key is the PSK used for entertainment streaming
+        let body = serde_json::json!({ "devicetype": name, "generateclientkey": true });
 
         // Deal with the cases where:
         // - bridge ip is not found
         // - mutliple bridges found
         // - one bridge found
-        let (ip, key) = if bridges.len() == 0 {
-            println!("No bridges automatically detected.\nEnter the IP of your HUE bridge (default: huemanity):");
+        let (ip, success) = if bridges.is_empty() {
+            eprintln!("No bridges automatically detected.\nEnter the IP of your HUE bridge (default: huemanity):");
             std::io::stdin().read_line(&mut ip)?;
             // TODO: use IP struct form net::sockaddr
             ip = ip.trim().to_string();
             Self::wait_for_button(body, Some(&ip), None, client)
         } else {
-            println!(
+            info!(
                 "Bridge(s) found: {:?} Will try to connect to all of them sequentially...",
-                &bridges
+                bridges
             );
             Self::wait_for_button(body, None, Some(bridges), client)
         };
 
-        let mut file = File::create(configpath)?;
-        file.write_all(format!("HUE_IP=\"{}\"\nHUE_KEY={}", &ip, key).as_ref())?;
-        println!(".huemanity File successfully saved!");
+        // the ID is what lets us find the bridge again if its IP changes
+        let id = bridge_id(&ip).ok();
+        let config = Config {
+            ip,
+            key: success["username"].as_str().unwrap_or_default().to_owned(),
+            id,
+            cert: None,
+            clientkey: success["clientkey"].as_str().map(str::to_owned),
+        };
+        config.save(configpath)?;
+        info!(".huemanity File successfully saved!");
 
-        // TODO: hacky replace
-        Ok((ip, key.to_string().replace("\"", "")))
+        Ok(config)
     }
 
     /// Struct constructor that sets up the required interactions
@@ -181,7 +319,41 @@ impl Bridge {
     /// As part of the registration process it will also ask you for an app name. It is not
     /// really important what it is as it is used as an application identifier when you are
     /// trying to see which apps have been registered on your bridge.
+    ///
+    /// The lights, groups and scenes are always fetched, and kept on disk for
+    /// `link_cached`.
     pub fn link() -> Self {
+        Self::link_cached(None)
+    }
+
+    /// Like `link`, but takes the lights, groups and scenes from the topology kept on
+    /// disk if it was fetched from the same bridge no longer than `ttl` ago, instead of
+    /// asking the bridge. Anything that needs the current state of the lights has to
+    /// fetch it.
+    pub fn link_cached(ttl: Option<Duration>) -> Self {
+        Self::link_with(ttl, Transport::Http)
+    }
+
+    /// Like `link_cached`, with the requests going through `transport`.
+    ///
+    /// With `Transport::DryRun` the topology kept on disk is used however old it is,
+    /// since nothing is asked of the bridge (registering aside).
+    ///
+    /// ```no_run
+    /// # #[macro_use] extern crate huemanity;
+    /// # use huemanity::bridge::{Bridge, Transport};
+    /// # use huemanity::lightstructs::*;
+    /// # fn main() {
+    /// let bridge = Bridge::link_with(None, Transport::DryRun);
+    /// // logs `PUT /lights/1/state {"on":false}` for every light instead
+    /// bridge.state_all(&state!(on: false)).unwrap();
+    /// # }
+    /// ```
+    pub fn link_with(ttl: Option<Duration>, transport: Transport) -> Self {
+        let ttl = match transport {
+            Transport::Http => ttl,
+            Transport::DryRun => Some(Duration::MAX),
+        };
         let mut filename = dirs::home_dir().unwrap();
         filename.push(".huemanity");
         let path = filename.to_str().unwrap();
@@ -189,75 +361,432 @@ impl Bridge {
         let client = Client::new();
 
         // discovery of IP and registration logic
-        let (ip, key) = match Self::detect(path) {
-            Ok(tupl) => tupl,
+        let mut config = match Config::detect(path) {
+            Ok(config) => config,
             _ => {
-                println!("Unable to find required `HUE_KEY` and `HUE_IP` in environment!");
-                let result = match Self::register(path) {
-                    Ok(tupl) => {
-                        println!("Registration successful");
-                        tupl
+                warn!("Unable to find required `HUE_KEY` and `HUE_IP` in environment!");
+                match Self::register(path) {
+                    Ok(config) => {
+                        info!("Registration successful");
+                        config
                     }
                     Err(e) => panic!("Could not register due to: {}", e),
-                };
-                result
+                }
             }
         };
 
-        let base_url = format!("http://{}/api/{}/", ip, key);
+        // config files written before the ID was stored get upgraded in place
+        if transport == Transport::Http {
+            config.upgrade(path, bridge_id);
+        }
+
         let mut bridge = Bridge {
-            ip,
-            key,
+            ip: RwLock::new(config.ip),
+            key: config.key,
+            id: config.id,
+            cert: Mutex::new(config.cert),
+            clientkey: config.clientkey,
             client,
-            base_url,
+            config: path.to_owned(),
+            events: Mutex::new(Vec::new()),
+            relocation: Mutex::new(Relocation::default()),
             light_ids: Vec::new(),
             n_lights: 0,
             lights: None,
+            topology: RwLock::new(None),
+            cache: Topology::default_path(),
+            transport,
         };
 
-        // inform user we're connected
-        println!("Connected to:\n{}", bridge);
-
         // collect the lights into the bridge
-        match bridge.collect_lights() {
-            Ok(_) => println!("Collected lights sucessfully!"),
-            Err(e) => println!("Could not collect lights: {}", e),
+        let cached = ttl.and_then(|ttl| {
+            Topology::load(&bridge.cache)
+                .ok()
+                .filter(|topology| topology.is_fresh(&bridge.ip(), ttl))
+        });
+        // a cache written by another version may not make sense any more
+        let collected = match cached.map(|topology| bridge.collect_lights(topology)) {
+            Some(Ok(())) => Ok(()),
+            Some(Err(e)) if transport == Transport::DryRun => Err(e),
+            None if transport == Transport::DryRun => Err(NOTHING_TO_REHEARSE.into()),
+            _ => {
+                debug!(bridge = %bridge.ip(), "Connected");
+                bridge
+                    .refresh()
+                    .map(|_| debug!("Found {} lights", bridge.n_lights))
+            }
+        };
+        if let Err(e) = collected {
+            warn!("Could not collect lights: {}", e);
         }
-
-        println!("Found {} lights", bridge.n_lights);
         bridge
     }
 
+    /// A bridge that rehearses everything against `topology`, without looking at
+    /// the `.huemanity` file or the cache
+    #[cfg(test)]
+    pub(crate) fn rehearsal(topology: Topology) -> Self {
+        Bridge {
+            ip: RwLock::new(topology.bridge.clone()),
+            key: "key".to_owned(),
+            id: None,
+            cert: Mutex::new(None),
+            clientkey: None,
+            client: Client::new(),
+            config: String::new(),
+            events: Mutex::new(Vec::new()),
+            relocation: Mutex::new(Relocation::default()),
+            light_ids: topology.lights.keys().cloned().collect(),
+            n_lights: topology.lights.len() as u8,
+            lights: None,
+            topology: RwLock::new(Some(topology)),
+            cache: String::new(),
+            transport: Transport::DryRun,
+        }
+    }
+
+    /// Changes what a rehearsal answers with, as if the lights had changed
+    #[cfg(test)]
+    pub(crate) fn rehearse_with(&self, topology: Topology) {
+        *self.topology.write().unwrap() = Some(topology);
+    }
+
+    /// The IP the bridge is currently reached on
+    pub fn ip(&self) -> String {
+        self.ip.read().unwrap().clone()
+    }
+
+    /// The unique ID of the bridge, if it is known
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    /// How the requests are sent
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    /// Drains the events that happened to the bridge connection since the last call
+    pub fn events(&self) -> Vec<BridgeEvent> {
+        self.events.lock().unwrap().drain(..).collect()
+    }
+
+    /// Opens a CLIP API v2 client to the same bridge, reusing the application key.
+    ///
+    /// The bridge certificate is pinned the first time this is called (and stored in the
+    /// `.huemanity` file as `HUE_CERT`), every later connection has to present the same one.
+    pub fn clip(&self) -> Result<ClipBridge, Box<dyn Error>> {
+        if self.transport == Transport::DryRun {
+            return Err("the CLIP API v2 can't be used in a dry run".into());
+        }
+        let mut cert = self.cert.lock().unwrap();
+        let clip = ClipBridge::connect(&self.ip(), &self.key, cert.as_deref())?;
+        if cert.is_none() {
+            *cert = Some(clip.fingerprint().to_owned());
+            drop(cert);
+            self.stored_config().save(&self.config)?;
+        }
+        Ok(clip)
+    }
+
+    /// What would currently be written to the `.huemanity` file
+    fn stored_config(&self) -> Config {
+        Config {
+            ip: self.ip(),
+            key: self.key.clone(),
+            id: self.id.clone(),
+            cert: self.cert.lock().unwrap().clone(),
+            clientkey: self.clientkey.clone(),
+        }
+    }
+
+    fn base_url(&self) -> String {
+        format!("http://{}/api/{}/", self.ip(), self.key)
+    }
+
     /// Sends the a request with set parameters to the HUE API endpoint
     /// This is a lower level function used primarily to send state.
     /// For more useful functions to look at: `Bridge.state` , `Bridge.state_all`
+    ///
+    /// If a GET, PUT or DELETE can't reach the bridge `RELOCATE_AFTER` times in a row,
+    /// the bridge gets rediscovered by its ID (at most once every `RELOCATE_INTERVAL`)
+    /// and the request is sent once more to the new address. A POST is only sent
+    /// once, as a reply that is late doesn't mean it didn't create anything.
     fn send(
         &self,
         endpoint: &str,
         req_type: RequestType,
-        params: Option<&SendableState>,
+        params: Option<&Value>,
     ) -> Result<reqwest::Response, Box<dyn std::error::Error>> {
+        self.check_rehearsal()?;
+        let retry = req_type != RequestType::Post;
+        let mut failures = 0;
+        loop {
+            match self.dispatch(endpoint, &req_type, params) {
+                Err(ref e) if retry && (e.is_http() || e.is_timeout()) => {
+                    failures += 1;
+                    if failures < RELOCATE_AFTER {
+                        sleep(RETRY_PAUSE);
+                        continue;
+                    }
+                    // whatever was known about the bridge may not hold any more
+                    self.invalidate();
+                    self.relocate()?;
+                    return Ok(self.dispatch(endpoint, &req_type, params)?);
+                }
+                response => {
+                    if response.is_err() {
+                        self.invalidate();
+                    }
+                    return Ok(response?);
+                }
+            }
+        }
+    }
+
+    fn dispatch(
+        &self,
+        endpoint: &str,
+        req_type: &RequestType,
+        params: Option<&Value>,
+    ) -> reqwest::Result<reqwest::Response> {
+        if self.transport == Transport::DryRun {
+            return Ok(self.rehearse(endpoint, req_type, params));
+        }
+
         // TODO: make it so it takes the state, and fills in the values from the same light
-        let target = format!("{}{}", self.base_url, endpoint);
+        let target = format!("{}{}", self.base_url(), endpoint);
+        let span = debug_span!(
+            "request",
+            method = %req_type.method(),
+            url = %format!("http://{}/api/{}/{}", self.ip(), redact(&self.key), endpoint),
+        );
+        let _entered = span.enter();
+        let started = Instant::now();
         let response = match req_type {
-            RequestType::Post => self.client.post(&target).json(&params).send()?,
-            RequestType::Get => self.client.get(&target).send()?,
-            RequestType::Put => self.client.put(&target).json(&params).send()?,
+            RequestType::Post => self.client.post(&target).json(&params).send(),
+            RequestType::Get => self.client.get(&target).send(),
+            RequestType::Put => self.client.put(&target).json(&params).send(),
+            RequestType::Delete => self.client.delete(&target).send(),
         };
-        Ok(response)
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+        match &response {
+            Ok(response) => debug!(status = response.status().as_u16(), elapsed_ms, "Response"),
+            Err(e) => {
+                // reqwest puts the address in its errors
+                let error = e.to_string().replace(&self.key, &redact(&self.key));
+                debug!(%error, elapsed_ms, "No response")
+            }
+        }
+        response
+    }
+
+    /// Fails a dry run that has no topology, as the answers would all be made up
+    /// and the lights unknown
+    fn check_rehearsal(&self) -> Result<(), Box<dyn Error>> {
+        if self.transport == Transport::DryRun && self.topology.read().unwrap().is_none() {
+            return Err(NOTHING_TO_REHEARSE.into());
+        }
+        Ok(())
     }
 
-    /// Gets the raw response to the user
-    pub fn debug(&self) {
-        match self.send("lights", RequestType::Get, None) {
-            Ok(mut resp) => {
-                let r: Value = resp.json().unwrap();
-                println!("{}", serde_json::to_string_pretty(&r).unwrap());
+    /// Logs a request instead of sending it and makes up the answer a bridge would give
+    /// if it went well
+    fn rehearse(
+        &self,
+        endpoint: &str,
+        req_type: &RequestType,
+        params: Option<&Value>,
+    ) -> reqwest::Response {
+        match params {
+            Some(params) => info!("{} /{} {}", req_type.method(), endpoint, params),
+            None => info!("{} /{}", req_type.method(), endpoint),
+        }
+
+        let topology = self.topology().unwrap_or_default();
+        let path: Vec<&str> = endpoint.trim_matches('/').split('/').collect();
+        let body = match (req_type, path.as_slice()) {
+            (RequestType::Get, [kind, rest @ ..]) => {
+                let resources = match *kind {
+                    "lights" => serde_json::to_value(&topology.lights),
+                    "groups" => serde_json::to_value(&topology.groups),
+                    "scenes" => serde_json::to_value(&topology.scenes),
+                    _ => Ok(Value::Null),
+                }
+                .unwrap_or_default();
+                match rest {
+                    [] if resources.is_object() => resources,
+                    [id] if resources[id].is_object() => resources[id].clone(),
+                    _ => serde_json::json!({}),
+                }
             }
-            Err(e) => {
-                println!("Could not send the get request: {}", e);
+            (RequestType::Post, [kind]) => {
+                // the first ID that isn't taken
+                let taken: Vec<u8> = match *kind {
+                    "lights" => topology.lights.keys().cloned().collect(),
+                    "groups" => topology.groups.keys().cloned().collect(),
+                    _ => Vec::new(),
+                };
+                let id = (1..=u8::MAX).find(|id| !taken.contains(id)).unwrap_or(1);
+                serde_json::json!([{ "success": { "id": id.to_string() } }])
+            }
+            (RequestType::Delete, _) => {
+                serde_json::json!([{ "success": format!("/{} deleted", endpoint) }])
+            }
+            // the bridge answers a change with every attribute it set
+            _ => Value::Array(
+                params
+                    .and_then(Value::as_object)
+                    .into_iter()
+                    .flatten()
+                    .map(|(key, value)| {
+                        serde_json::json!({
+                            "success": { format!("/{}/{}", endpoint, key): value }
+                        })
+                    })
+                    .collect(),
+            ),
+        };
+        http::Response::new(body.to_string()).into()
+    }
+
+    /// Looks for the bridge with the stored ID on the network and points the bridge
+    /// (and the `.huemanity` file) at the address it was found on.
+    fn relocate(&self) -> Result<(), HueError> {
+        let old = self.ip();
+        let id = match &self.id {
+            Some(id) => id,
+            None => return Err(HueError::Unreachable { ip: old }),
+        };
+
+        let (discover, identify) = {
+            let mut relocation = self.relocation.lock().unwrap();
+            if let Some(searched) = relocation.searched {
+                if searched.elapsed() < RELOCATE_INTERVAL {
+                    return Err(HueError::Offline {
+                        ip: old,
+                        retry: (RELOCATE_INTERVAL - searched.elapsed()).as_secs(),
+                    });
+                }
+            }
+            relocation.searched = Some(Instant::now());
+            (relocation.discover, relocation.identify)
+        };
+
+        let found = discover()?
+            .into_iter()
+            .filter(|ip| *ip != old)
+            .find(|ip| match identify(ip) {
+                Ok(candidate) => candidate.eq_ignore_ascii_case(id),
+                Err(_) => false,
+            });
+        let new = match found {
+            Some(ip) => ip,
+            None => {
+                return Err(HueError::BridgeNotFound {
+                    id: id.to_owned(),
+                    ip: old,
+                })
+            }
+        };
+
+        *self.ip.write().unwrap() = new.clone();
+        let mut events = self.events.lock().unwrap();
+        events.push(BridgeEvent::Relocated {
+            id: id.to_owned(),
+            from: old,
+            to: new,
+        });
+
+        if let Err(e) = self.stored_config().save(&self.config) {
+            events.push(BridgeEvent::ConfigNotSaved {
+                reason: e.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Gets the raw answer of the bridge about the lights
+    pub fn debug(&self) -> Result<Value, Box<dyn Error>> {
+        self.fetch("lights")
+    }
+
+    /// Sends a state to a light or to all lights in a group
+    pub fn set(&self, selector: Selector, state: &SendableState) -> Result<(), Box<dyn Error>> {
+        self.update(
+            &selector.state_endpoint(),
+            RequestType::Put,
+            &serde_json::to_value(state)?,
+        )?;
+        Ok(())
+    }
+
+    /// Finds a light by ID or name, or failing that a group by name. The topology
+    /// is looked through for both before the bridge is asked.
+    pub fn select(&self, name: &str) -> Result<Selector, Box<dyn Error>> {
+        if let Ok(id) = name.trim().parse() {
+            return Ok(Selector::Light(id));
+        }
+        let known = self.topology.read().unwrap().as_ref().and_then(|topology| {
+            let light = topology.find("lights", name).map(Selector::Light);
+            light.or_else(|| topology.find("groups", name).map(Selector::Group))
+        });
+        if let Some(selector) = known {
+            return Ok(selector);
+        }
+
+        match self.resolve("lights", name) {
+            Ok(light) => Ok(Selector::Light(light)),
+            Err(e) if matches!(e.downcast_ref(), Some(HueError::UnknownName { .. })) => {
+                match self.resolve("groups", name) {
+                    Ok(group) => Ok(Selector::Group(group)),
+                    // the lights were looked through first
+                    Err(_) => Err(HueError::UnknownName {
+                        kind: "light or group".to_owned(),
+                        name: name.to_owned(),
+                    }
+                    .into()),
+                }
             }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Turns a light off if it is on and on if it is off. A group is turned off if
+    /// any of its lights are on. Returns whether it was turned on.
+    pub fn toggle(&self, selector: Selector) -> Result<bool, Box<dyn Error>> {
+        let resource: Value = self.fetch(&selector.endpoint())?;
+        let on = match selector {
+            Selector::Light(_) => resource["state"]["on"] != true,
+            Selector::Group(_) => resource["state"]["any_on"] != true,
+        };
+        let state = SendableState {
+            on: Some(on),
+            ..SendableState::default()
         };
+        self.set(selector, &state)?;
+        Ok(on)
+    }
+
+    /// Makes a light or group brighter by some percent of the full range, or dimmer
+    /// if it is negative. Lights that are off stay off.
+    pub fn brighten(&self, selector: Selector, percent: f32) -> Result<(), Box<dyn Error>> {
+        let increment = (percent * 2.54).round().clamp(-254.0, 254.0) as i16;
+        let state = SendableState {
+            bri_inc: Some(increment),
+            ..SendableState::default()
+        };
+        self.set(selector, &state)
+    }
+
+    /// Makes a light or group warmer by some mired, or cooler if it is negative.
+    /// The lights stop at the ends of what they support.
+    pub fn warmer(&self, selector: Selector, mired: i32) -> Result<(), Box<dyn Error>> {
+        let state = SendableState {
+            ct_inc: Some(mired.clamp(-65534, 65534)),
+            ..SendableState::default()
+        };
+        self.set(selector, &state)
     }
 
     /// Given a light and a required state, send this state to the light.
@@ -271,7 +800,52 @@ impl Bridge {
         self.send(
             &format!("lights/{}/state", light),
             RequestType::Put,
-            Some(state),
+            Some(&serde_json::to_value(state)?),
+        )?;
+        Ok(())
+    }
+
+    /// Given a group and a required state, send this state to all lights in the group at once.
+    pub fn group_state(
+        &self,
+        group: u8,
+        state: &SendableState,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.send(
+            &format!("groups/{}/action", group),
+            RequestType::Put,
+            Some(&serde_json::to_value(state)?),
+        )?;
+        Ok(())
+    }
+
+    /// Recalls a scene (by ID or name) on a group
+    pub fn recall_scene(&self, group: u8, scene: &str) -> Result<(), Box<dyn Error>> {
+        let known = self
+            .topology
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|topology| cache::find_scene(&topology.scenes, scene));
+        let id = match known {
+            Some(Some(id)) => id,
+            _ => {
+                // the scene may be new since the topology was fetched
+                let scenes: BTreeMap<String, Value> = self.fetch("scenes")?;
+                let found = cache::find_scene(&scenes, scene);
+                if found.is_some() && known.is_some() {
+                    self.invalidate();
+                }
+                found.ok_or_else(|| HueError::UnknownName {
+                    kind: "scene".to_owned(),
+                    name: scene.to_owned(),
+                })?
+            }
+        };
+        self.update(
+            &format!("groups/{}/action", group),
+            RequestType::Put,
+            &serde_json::json!({ "scene": id }),
         )?;
         Ok(())
     }
@@ -280,52 +854,346 @@ impl Bridge {
     /// At the moment it is done in a loop. So the lights don't get the
     /// signal sent concurrently
     pub fn state_all(&self, state: &SendableState) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_rehearsal()?;
         for light in self.light_ids.iter() {
             self.state(*light, state)?;
         }
         Ok(())
     }
 
-    /// Collect all found light ids
-    /// This method updates the following attributes of the bridge:
-    /// - light_ids
-    /// - n_lights
-    /// - lights
-    fn collect_lights(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        // get the lights state
-        let lights: Lights = self.send("lights", RequestType::Get, None)?.json()?;
+    /// Gets any endpoint under the API (e.g. `groups` or `sensors/5`) and deserialises it
+    pub fn fetch<T: DeserializeOwned>(&self, endpoint: &str) -> Result<T, Box<dyn Error>> {
+        Ok(self.send(endpoint, RequestType::Get, None)?.json()?)
+    }
+
+    /// Captures the state of every light so it can be put back with `restore`
+    pub fn snapshot(&self) -> Result<Snapshot, Box<dyn Error>> {
+        let lights: BTreeMap<u8, Value> = self.fetch("lights")?;
+        lights
+            .into_iter()
+            .map(|(id, mut light)| {
+                let saved: SavedState = serde_json::from_value(light["state"].take())?;
+                Ok((id, saved.for_colormode()))
+            })
+            .collect()
+    }
+
+    /// Puts the lights back the way they were in the snapshot, sending each light
+    /// only the colour attributes of its colormode
+    pub fn restore(&self, snapshot: &Snapshot) -> Result<(), Box<dyn Error>> {
+        for (light, saved) in snapshot {
+            self.state(*light, &saved.sendable())?;
+        }
+        Ok(())
+    }
+
+    /// Finds the ID of a light or group (`endpoint` is `lights` or `groups`) from its
+    /// name, ignoring case. Numbers are taken to be the ID already.
+    pub fn resolve(&self, endpoint: &str, name: &str) -> Result<u8, Box<dyn Error>> {
+        if let Ok(id) = name.trim().parse() {
+            return Ok(id);
+        }
+        let known = self
+            .topology
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|topology| topology.find(endpoint, name));
+        if let Some(Some(id)) = known {
+            return Ok(id);
+        }
+
+        // the name may be new since the topology was fetched
+        let resources: BTreeMap<u8, Value> = self.fetch(endpoint)?;
+        let found = cache::find(&resources, name);
+        if found.is_some() && known.is_some() {
+            self.invalidate();
+        }
+        found.ok_or_else(|| {
+            HueError::UnknownName {
+                kind: endpoint.trim_end_matches('s').to_owned(),
+                name: name.to_owned(),
+            }
+            .into()
+        })
+    }
+
+    /// Sends a request with a JSON body and turns any errors the bridge
+    /// reports in its response into a `HueError::Api`
+    fn update(
+        &self,
+        endpoint: &str,
+        req_type: RequestType,
+        body: &Value,
+    ) -> Result<Value, Box<dyn Error>> {
+        let response: Value = self.send(endpoint, req_type, Some(body))?.json()?;
+        let errors: Vec<String> = response
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|item| item["error"]["description"].as_str())
+            .map(str::to_owned)
+            .collect();
+        if errors.is_empty() {
+            Ok(response)
+        } else {
+            // the light or group may be gone
+            self.invalidate();
+            Err(HueError::Api { errors }.into())
+        }
+    }
+
+    /// Gets the entertainment groups set up on the bridge along with the locations
+    /// of their lights
+    pub fn entertainment_groups(&self) -> Result<BTreeMap<u8, Group>, Box<dyn Error>> {
+        let groups: BTreeMap<u8, Group> = self.fetch("groups")?;
+        Ok(groups
+            .into_iter()
+            .filter(|(_, group)| group.r#type == "Entertainment")
+            .collect())
+    }
+
+    /// Takes (or gives up) streaming ownership of an entertainment group
+    pub fn set_streaming(&self, group: u8, active: bool) -> Result<(), Box<dyn Error>> {
+        self.update(
+            &format!("groups/{}", group),
+            RequestType::Put,
+            &serde_json::json!({ "stream": { "active": active } }),
+        )?;
+        Ok(())
+    }
+
+    /// Creates an entertainment area out of the given lights and returns its group ID
+    pub fn create_entertainment_group(
+        &self,
+        name: &str,
+        class: EntertainmentClass,
+        lights: &[u8],
+    ) -> Result<u8, Box<dyn Error>> {
+        let lights: Vec<String> = lights.iter().map(u8::to_string).collect();
+        let response = self.update(
+            "groups",
+            RequestType::Post,
+            &serde_json::json!({
+                "name": name,
+                "type": "Entertainment",
+                "class": class,
+                "lights": lights,
+            }),
+        )?;
+        match response[0]["success"]["id"].as_str().map(str::parse) {
+            Some(Ok(id)) => Ok(id),
+            _ => Err("bridge did not return the ID of the new group".into()),
+        }
+    }
+
+    /// Changes the name, class or lights of an entertainment area. Anything left as
+    /// `None` stays as it is.
+    pub fn edit_entertainment_group(
+        &self,
+        group: u8,
+        name: Option<&str>,
+        class: Option<EntertainmentClass>,
+        lights: Option<&[u8]>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut body = serde_json::Map::new();
+        if let Some(name) = name {
+            body.insert("name".to_owned(), name.into());
+        }
+        if let Some(class) = class {
+            body.insert("class".to_owned(), serde_json::to_value(class)?);
+        }
+        if let Some(lights) = lights {
+            let lights: Vec<String> = lights.iter().map(u8::to_string).collect();
+            body.insert("lights".to_owned(), lights.into());
+        }
+        self.update(
+            &format!("groups/{}", group),
+            RequestType::Put,
+            &Value::Object(body),
+        )?;
+        Ok(())
+    }
+
+    /// Sets where the lights of an entertainment area are. Every coordinate has
+    /// to be between -1 and 1.
+    pub fn set_locations(&self, group: u8, locations: &Locations) -> Result<(), Box<dyn Error>> {
+        for (light, location) in locations {
+            if location.iter().any(|c| !(-1.0..=1.0).contains(c)) {
+                return Err(format!(
+                    "location {:?} of light {} is outside of -1..1",
+                    location, light
+                )
+                .into());
+            }
+        }
+        self.update(
+            &format!("groups/{}", group),
+            RequestType::Put,
+            &serde_json::json!({ "locations": locations }),
+        )?;
+        Ok(())
+    }
+
+    /// Picks the light that relays the stream to the others. `None` lets the
+    /// bridge choose one itself.
+    pub fn set_proxy(&self, group: u8, light: Option<u8>) -> Result<(), Box<dyn Error>> {
+        let stream = match light {
+            Some(light) => serde_json::json!({
+                "proxymode": "manual",
+                "proxynode": format!("/lights/{}", light),
+            }),
+            None => serde_json::json!({ "proxymode": "auto" }),
+        };
+        self.update(
+            &format!("groups/{}", group),
+            RequestType::Put,
+            &serde_json::json!({ "stream": stream }),
+        )?;
+        Ok(())
+    }
+
+    /// Deletes a group
+    pub fn delete_group(&self, group: u8) -> Result<(), Box<dyn Error>> {
+        self.send(&format!("groups/{}", group), RequestType::Delete, None)?;
+        Ok(())
+    }
+
+    /// Activates streaming on an entertainment group and opens the DTLS session to
+    /// the bridge. Needs the client key that is generated during registration, so bridges
+    /// registered before streaming was supported have to be registered again.
+    pub fn stream(&self, group: u8) -> Result<Streamer, Box<dyn Error>> {
+        if self.transport == Transport::DryRun {
+            return Err("streaming can't be rehearsed in a dry run".into());
+        }
+        let clientkey = self
+            .clientkey
+            .as_ref()
+            .ok_or("no `HUE_CLIENTKEY` stored, register again to stream")?;
+        let psk = decode_hex(clientkey).ok_or("`HUE_CLIENTKEY` is not valid hex")?;
+
+        self.set_streaming(group, true)?;
+        match Streamer::connect((self.ip().as_str(), STREAMING_PORT), &self.key, &psk) {
+            Ok(mut streamer) => {
+                let url = format!("{}groups/{}", self.base_url(), group);
+                streamer.release_with(self.client.clone(), url);
+                Ok(streamer)
+            }
+            Err(e) => {
+                // the area would stay locked otherwise
+                let _ = self.set_streaming(group, false);
+                Err(e)
+            }
+        }
+    }
+
+    /// Fetches the lights, groups and scenes again and keeps them on disk
+    pub fn refresh(&mut self) -> Result<(), Box<dyn Error>> {
+        let topology = Topology::fetch(self).inspect_err(|_| {
+            // what there was is not to be trusted any more
+            *self.topology.get_mut().unwrap() = None;
+        })?;
+        // commands still work without the cache, just slower
+        if self.transport == Transport::Http {
+            let _ = topology.save(&self.cache);
+        }
+        self.collect_lights(topology)
+    }
+
+    /// The lights, groups and scenes as of the last time they were fetched
+    pub fn topology(&self) -> Option<Topology> {
+        self.topology.read().unwrap().clone()
+    }
+
+    /// Forgets the topology, here and on disk, after something showed it is out of date
+    fn invalidate(&self) {
+        if self.topology.write().unwrap().take().is_some() {
+            let _ = Topology::invalidate(&self.cache);
+        }
+    }
+
+    fn collect_lights(&mut self, topology: Topology) -> Result<(), Box<dyn Error>> {
+        // a light that doesn't fit `Light` is still there to be sent states
+        let mut lights = Lights::new();
+        for (id, light) in &topology.lights {
+            match serde_json::from_value(light.clone()) {
+                Ok(parsed) => {
+                    lights.insert(*id, parsed);
+                }
+                Err(e) => debug!(light = id, error = %e, "Light not understood"),
+            }
+        }
 
         // update the values with the new ones
-        self.light_ids = lights.keys().cloned().map(|integer| integer).collect();
+        self.light_ids = topology.lights.keys().cloned().collect();
         self.lights = Some(lights);
         self.n_lights = self.light_ids.len() as u8;
+        *self.topology.get_mut().unwrap() = Some(topology);
 
         Ok(())
     }
 
-    /// This is a simple method to show the lights in the terminal
-    pub fn light_info(&self) {
+    /// The lights as a listing to show in the terminal, with none under the heading
+    /// if they haven't been collected
+    pub fn light_info(&self) -> String {
         // TODO: make a macro to nice print
-        println!("--------------------------------");
-        println!("Lights available on your bridge:");
-        println!("--------------------------------");
-
-        let lights = self.lights.as_ref().unwrap();
-        for (id, light) in lights.iter() {
-            println!("{}:{}", id, light);
+        let mut info = String::from(
+            "--------------------------------\n\
+             Lights available on your bridge:\n\
+             --------------------------------\n",
+        );
+        for (id, light) in self.lights.iter().flatten() {
+            info.push_str(&format!("{}:{}\n", id, light));
         }
+        info
     }
 }
 
 impl fmt::Display for Bridge {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "bridge: {}", self.ip)
+        write!(f, "bridge: {}", self.ip())
+    }
+}
+
+/// A light or a group of lights, which take the same states
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Selector {
+    Light(u8),
+    /// Group 0 holds all the lights
+    Group(u8),
+}
+
+impl Selector {
+    /// Where the light or group itself is, like `lights/1`
+    pub fn endpoint(&self) -> String {
+        match self {
+            Selector::Light(id) => format!("lights/{}", id),
+            Selector::Group(id) => format!("groups/{}", id),
+        }
+    }
+
+    /// Where states for the light or group are sent
+    pub fn state_endpoint(&self) -> String {
+        match self {
+            Selector::Light(id) => format!("lights/{}/state", id),
+            Selector::Group(id) => format!("groups/{}/action", id),
+        }
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Selector::Light(id) => write!(f, "light {}", id),
+            Selector::Group(id) => write!(f, "group {}", id),
+        }
     }
 }
 
 /// Discovers bridge IPs on the networks using SSDP
-pub fn discover() -> Vec<String> {
-    println!("Searching for bridges...");
+pub fn discover() -> Result<Vec<String>, HueError> {
+    info!("Searching for bridges...");
     use ssdp::header::{HeaderMut, Man, MX, ST};
     use ssdp::message::{Multicast, SearchRequest};
 
@@ -341,13 +1209,36 @@ pub fn discover() -> Vec<String> {
 
     let mut bridges = Vec::new();
     // Iterate Over Streaming Responses
-    for (_, src) in request.multicast().unwrap() {
+    let responses = request.multicast().map_err(|e| HueError::Discovery {
+        reason: e.to_string(),
+    })?;
+    for (_, src) in responses {
         let ip = src.ip().to_string();
         if !bridges.contains(&ip) {
             bridges.push(ip)
         }
     }
-    bridges
+    Ok(bridges)
+}
+
+/// Asks the bridge at the given IP for its unique ID. This endpoint
+/// does not need a registered key.
+pub fn bridge_id(ip: &str) -> Result<String, Box<dyn Error>> {
+    let config: Value = Client::new()
+        .get(&format!("http://{}/api/config", ip))
+        .send()?
+        .json()?;
+    match config["bridgeid"].as_str() {
+        Some(id) => Ok(id.to_owned()),
+        None => Err(format!("{} did not report a bridge ID", ip).into()),
+    }
+}
+
+/// The application key as it goes in the logs: enough of it to tell keys apart, not
+/// enough to use it
+fn redact(key: &str) -> String {
+    let shown = key.get(..4).filter(|_| key.len() >= 16).unwrap_or_default();
+    format!("{}…", shown)
 }
 
 /// Removes the `~/.huemanity` file
@@ -358,3 +1249,179 @@ pub fn cleanup() -> std::io::Result<()> {
     std::fs::remove_file(filename)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn topology() -> Topology {
+        Topology {
+            bridge: "127.0.0.1:9".to_owned(),
+            lights: [
+                (1, json!({ "name": "Desk", "type": "Extended color light", "state": { "on": true } })),
+                // no `type`, which a `Light` has to have
+                (2, json!({ "name": "Plug", "manufacturername": "Acme" })),
+            ]
+            .into(),
+            groups: [(1, json!({ "name": "Living room", "lights": ["1"] }))].into(),
+            scenes: [("AbC123".to_owned(), json!({ "name": "Relax" }))].into(),
+            ..Topology::default()
+        }
+    }
+
+    /// A bridge nobody is listening for, so anything that is sent fails
+    fn unreachable() -> Bridge {
+        let mut bridge = Bridge::rehearsal(topology());
+        bridge.transport = Transport::Http;
+        bridge
+    }
+
+    const ID: &str = "001788FFFE000001";
+
+    fn temp_file(name: &str) -> String {
+        let path = env::temp_dir().join(format!("huemanity-{}-{}", name, std::process::id()));
+        path.to_string_lossy().into_owned()
+    }
+
+    static SEARCHES: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+    /// A network with the same bridge on another address, and another bridge
+    fn moved() -> Bridge {
+        let mut bridge = unreachable();
+        bridge.id = Some(ID.to_owned());
+        bridge.config = temp_file("moved");
+        *bridge.relocation.get_mut().unwrap() = Relocation {
+            searched: None,
+            discover: || {
+                SEARCHES.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Ok(vec!["127.0.0.1:11".to_owned(), "127.0.0.1:10".to_owned()])
+            },
+            identify: |ip| match ip {
+                "127.0.0.1:10" => Ok(ID.to_lowercase()),
+                _ => Ok("001788FFFE000002".to_owned()),
+            },
+        };
+        bridge
+    }
+
+    #[test]
+    fn relocates_once_a_while() {
+        let bridge = moved();
+        assert!(bridge.fetch::<Value>("lights").is_err());
+        assert_eq!(bridge.ip(), "127.0.0.1:10");
+        assert_eq!(SEARCHES.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(
+            bridge.events(),
+            [BridgeEvent::Relocated {
+                id: ID.to_owned(),
+                from: "127.0.0.1:9".to_owned(),
+                to: "127.0.0.1:10".to_owned(),
+            }]
+        );
+        let saved = std::fs::read_to_string(&bridge.config).unwrap();
+        assert!(saved.contains("HUE_IP=\"127.0.0.1:10\""));
+        assert!(saved.contains(&format!("HUE_ID=\"{}\"", ID)));
+        std::fs::remove_file(&bridge.config).unwrap();
+
+        // the next search has to wait
+        let error = bridge.fetch::<Value>("lights").unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(HueError::Offline { retry, .. }) if *retry > 0
+        ));
+        assert_eq!(SEARCHES.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn sends_posts_once() {
+        let bridge = unreachable();
+        let started = Instant::now();
+        assert!(bridge.send("groups", RequestType::Post, None).is_err());
+        assert!(started.elapsed() < RETRY_PAUSE);
+
+        let started = Instant::now();
+        assert!(bridge.send("groups/1", RequestType::Delete, None).is_err());
+        assert!(started.elapsed() >= RETRY_PAUSE * (RELOCATE_AFTER - 1));
+    }
+
+    #[test]
+    fn upgrades_configs_without_an_id() {
+        let path = temp_file("config");
+        let mut config = Config {
+            ip: "127.0.0.1:9".to_owned(),
+            key: "key".to_owned(),
+            id: None,
+            cert: None,
+            clientkey: Some("00ff".to_owned()),
+        };
+        config.upgrade(&path, |_| Err("no answer".into()));
+        assert!(config.id.is_none());
+        assert!(std::fs::metadata(&path).is_err());
+
+        config.upgrade(&path, |_| Ok(ID.to_owned()));
+        assert_eq!(config.id.as_deref(), Some(ID));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!(
+                "HUE_IP=\"127.0.0.1:9\"\nHUE_KEY=\"key\"\nHUE_ID=\"{}\"\nHUE_CLIENTKEY=\"00ff\"\n",
+                ID
+            )
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        // a config that has an ID is left alone
+        config.upgrade(&path, |_| panic!("asked for the ID again"));
+        assert!(std::fs::metadata(&path).is_err());
+    }
+
+    #[test]
+    fn selects_from_the_topology_without_asking() {
+        let bridge = unreachable();
+        assert_eq!(bridge.select("desk").unwrap(), Selector::Light(1));
+        assert_eq!(bridge.select("Living Room").unwrap(), Selector::Group(1));
+        assert_eq!(bridge.select(" 7 ").unwrap(), Selector::Light(7));
+        assert_eq!(bridge.resolve("groups", "living room").unwrap(), 1);
+        assert!(bridge.topology().is_some());
+    }
+
+    #[test]
+    fn forgets_the_topology_when_the_bridge_is_unreachable() {
+        let bridge = unreachable();
+        assert!(bridge.select("Kitchen").is_err());
+        assert!(bridge.topology().is_none());
+    }
+
+    #[test]
+    fn keeps_lights_that_are_not_understood() {
+        let mut bridge = unreachable();
+        bridge.collect_lights(topology()).unwrap();
+        assert_eq!(bridge.light_ids, [1, 2]);
+        assert_eq!(bridge.n_lights, 2);
+        let lights = bridge.lights.as_ref().unwrap();
+        // the rest of what a light reports is left out
+        assert!(lights[&1].state.on);
+        assert!(!lights.contains_key(&2));
+        assert!(bridge.topology().is_some());
+    }
+
+    #[test]
+    fn refuses_to_rehearse_without_a_topology() {
+        let mut bridge = Bridge::rehearsal(Topology::default());
+        *bridge.topology.get_mut().unwrap() = None;
+        let error = bridge.state_all(&SendableState::default()).unwrap_err();
+        assert_eq!(error.to_string(), NOTHING_TO_REHEARSE);
+        assert!(bridge.fetch::<Value>("lights").is_err());
+
+        let bridge = Bridge::rehearsal(topology());
+        assert!(bridge.state_all(&SendableState::default()).is_ok());
+    }
+
+    #[test]
+    fn finds_scenes_by_id_or_name() {
+        let scenes = topology().scenes;
+        assert_eq!(cache::find_scene(&scenes, "AbC123").unwrap(), "AbC123");
+        assert_eq!(cache::find_scene(&scenes, "relax").unwrap(), "AbC123");
+        assert!(cache::find_scene(&scenes, "abc123").is_none());
+    }
+}
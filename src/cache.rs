@@ -0,0 +1,158 @@
+use crate::bridge::Bridge;
+use serde::*;
+use serde_json::value::Value;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long the command line trusts the topology kept on disk
+pub const TTL: Duration = Duration::from_secs(15 * 60);
+
+/// What is on the bridge: the lights (with their capabilities), groups and scenes.
+/// It is kept on disk so that commands which only need to know what is there, like
+/// looking up a name, don't have to ask the bridge every time. The states in it are
+/// as old as the topology, so anything showing them should fetch them again.
+///
+/// ```no_run
+/// # use huemanity::cache::Topology;
+/// let topology = Topology::load(&Topology::default_path()).unwrap_or_default();
+/// for name in topology.names().all() {
+///     println!("{}", name);
+/// }
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Topology {
+    /// Address of the bridge it came from
+    pub bridge: String,
+    /// When it was fetched, in seconds since the epoch
+    pub fetched: u64,
+    pub lights: BTreeMap<u8, Value>,
+    pub groups: BTreeMap<u8, Value>,
+    pub scenes: BTreeMap<String, Value>,
+}
+
+impl Topology {
+    /// Where the topology is kept, `~/.huemanity_cache.json`
+    pub fn default_path() -> String {
+        let mut path = dirs::home_dir().unwrap_or_default();
+        path.push(".huemanity_cache.json");
+        path.to_string_lossy().into_owned()
+    }
+
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Removes the topology kept at `path`, so the next command fetches it again
+    pub fn invalidate(path: &str) -> std::io::Result<()> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Asks the bridge for everything on it
+    pub fn fetch(bridge: &Bridge) -> Result<Self, Box<dyn Error>> {
+        Ok(Topology {
+            bridge: bridge.ip(),
+            fetched: now(),
+            lights: bridge.fetch("lights")?,
+            groups: bridge.fetch("groups")?,
+            scenes: bridge.fetch("scenes")?,
+        })
+    }
+
+    /// Whether it came from the bridge at `ip` no longer than `ttl` ago
+    pub fn is_fresh(&self, ip: &str, ttl: Duration) -> bool {
+        self.bridge == ip && now().saturating_sub(self.fetched) <= ttl.as_secs()
+    }
+
+    /// The ID of the light or group (`endpoint` is `lights` or `groups`) with the
+    /// given name, ignoring case
+    pub fn find(&self, endpoint: &str, name: &str) -> Option<u8> {
+        let resources = match endpoint {
+            "lights" => &self.lights,
+            "groups" => &self.groups,
+            _ => return None,
+        };
+        find(resources, name)
+    }
+
+    pub fn names(&self) -> Names {
+        fn names<'a>(resources: impl Iterator<Item = &'a Value>) -> Vec<String> {
+            resources
+                .filter_map(|resource| resource["name"].as_str())
+                .map(str::to_owned)
+                .collect()
+        }
+        Names {
+            lights: names(self.lights.values()),
+            groups: names(self.groups.values()),
+            scenes: names(self.scenes.values()),
+        }
+    }
+}
+
+/// Names of the lights, groups and scenes on the bridge, as used for completion
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Names {
+    #[serde(default)]
+    pub lights: Vec<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub scenes: Vec<String>,
+}
+
+impl Names {
+    /// Every name once, sorted
+    pub fn all(&self) -> Vec<String> {
+        let mut names: Vec<String> = [&self.lights, &self.groups, &self.scenes]
+            .iter()
+            .flat_map(|names| names.iter().cloned())
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+/// The ID of the resource with the given name, ignoring case
+pub fn find(resources: &BTreeMap<u8, Value>, name: &str) -> Option<u8> {
+    resources
+        .iter()
+        .find(|(_, resource)| {
+            resource["name"]
+                .as_str()
+                .is_some_and(|n| n.eq_ignore_ascii_case(name.trim()))
+        })
+        .map(|(id, _)| *id)
+}
+
+/// The ID of the scene with the given ID or name, ignoring case for names
+pub fn find_scene(scenes: &BTreeMap<String, Value>, scene: &str) -> Option<String> {
+    if scenes.contains_key(scene) {
+        return Some(scene.to_owned());
+    }
+    scenes
+        .iter()
+        .find(|(_, s)| {
+            s["name"]
+                .as_str()
+                .is_some_and(|n| n.eq_ignore_ascii_case(scene.trim()))
+        })
+        .map(|(id, _)| id.clone())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,801 @@
+use crate::error::HueError;
+use crate::eventstream::EventStream;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::ssl::{SslConnector, SslMethod, SslStream, SslVerifyMode};
+use openssl::x509::X509Ref;
+use reqwest::Method;
+use serde::de::DeserializeOwned;
+use serde::*;
+use serde_json::value::Value;
+use std::error::Error;
+use std::fmt::{self, Write as _};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+use tracing::{debug, debug_span};
+
+/// The resource types the CLIP API v2 exposes under `/clip/v2/resource/`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResourceType {
+    Light,
+    GroupedLight,
+    Room,
+    Zone,
+    Scene,
+    Device,
+    Button,
+    Motion,
+    Temperature,
+    BridgeHome,
+}
+
+impl ResourceType {
+    /// The name of the resource as used in the API paths
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ResourceType::Light => "light",
+            ResourceType::GroupedLight => "grouped_light",
+            ResourceType::Room => "room",
+            ResourceType::Zone => "zone",
+            ResourceType::Scene => "scene",
+            ResourceType::Device => "device",
+            ResourceType::Button => "button",
+            ResourceType::Motion => "motion",
+            ResourceType::Temperature => "temperature",
+            ResourceType::BridgeHome => "bridge_home",
+        }
+    }
+}
+
+impl fmt::Display for ResourceType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Reference from one resource to another
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ResourceRef {
+    pub rid: String,
+    pub rtype: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Metadata {
+    #[serde(default)]
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archetype: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct On {
+    pub on: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Dimming {
+    pub brightness: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ColorTemperature {
+    pub mirek: Option<u16>,
+    #[serde(default)]
+    pub mirek_valid: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Xy {
+    pub x: f32,
+    pub y: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Color {
+    pub xy: Xy,
+}
+
+/// A single light
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Light {
+    pub id: String,
+    pub id_v1: Option<String>,
+    pub owner: ResourceRef,
+    #[serde(default)]
+    pub metadata: Metadata,
+    pub on: On,
+    pub dimming: Option<Dimming>,
+    pub color_temperature: Option<ColorTemperature>,
+    pub color: Option<Color>,
+}
+
+/// All the lights in a room, zone or the whole home controlled as one
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GroupedLight {
+    pub id: String,
+    pub id_v1: Option<String>,
+    pub owner: Option<ResourceRef>,
+    pub on: Option<On>,
+    pub dimming: Option<Dimming>,
+}
+
+/// A room, zone or the bridge home. They all group other resources.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Group {
+    pub id: String,
+    pub id_v1: Option<String>,
+    #[serde(default)]
+    pub metadata: Metadata,
+    #[serde(default)]
+    pub children: Vec<ResourceRef>,
+    #[serde(default)]
+    pub services: Vec<ResourceRef>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Scene {
+    pub id: String,
+    pub id_v1: Option<String>,
+    #[serde(default)]
+    pub metadata: Metadata,
+    pub group: ResourceRef,
+    #[serde(default)]
+    pub actions: Vec<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProductData {
+    pub model_id: String,
+    pub manufacturer_name: String,
+    pub product_name: String,
+    pub software_version: String,
+}
+
+/// A physical device, which offers services such as lights or sensors
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Device {
+    pub id: String,
+    pub id_v1: Option<String>,
+    #[serde(default)]
+    pub metadata: Metadata,
+    pub product_data: Option<ProductData>,
+    #[serde(default)]
+    pub services: Vec<ResourceRef>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ButtonReport {
+    pub last_event: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Button {
+    pub id: String,
+    pub id_v1: Option<String>,
+    pub owner: ResourceRef,
+    pub button: Option<ButtonReport>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MotionReport {
+    pub motion: bool,
+    #[serde(default)]
+    pub motion_valid: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Motion {
+    pub id: String,
+    pub id_v1: Option<String>,
+    pub owner: ResourceRef,
+    pub enabled: bool,
+    pub motion: MotionReport,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TemperatureReport {
+    pub temperature: f32,
+    #[serde(default)]
+    pub temperature_valid: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Temperature {
+    pub id: String,
+    pub id_v1: Option<String>,
+    pub owner: ResourceRef,
+    pub enabled: bool,
+    pub temperature: TemperatureReport,
+}
+
+/// Any resource, as returned when listing everything on the bridge
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Resource {
+    Light(Light),
+    GroupedLight(GroupedLight),
+    Room(Group),
+    Zone(Group),
+    Scene(Scene),
+    Device(Device),
+    Button(Button),
+    Motion(Motion),
+    Temperature(Temperature),
+    BridgeHome(Group),
+    /// Resource types that are not modelled (yet)
+    #[serde(other)]
+    Other,
+}
+
+/// Every v2 response comes wrapped in this
+#[derive(Deserialize, Debug)]
+struct Envelope<T> {
+    #[serde(default)]
+    errors: Vec<ApiError>,
+    #[serde(default = "Vec::new")]
+    data: Vec<T>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ApiError {
+    description: String,
+}
+
+/// Client for the CLIP API v2 of newer bridges. Talks HTTPS to the bridge and
+/// authenticates with the `hue-application-key` header.
+///
+/// The bridges use self signed certificates (or ones signed by the Signify CA) that are
+/// issued to the bridge ID rather than its IP, so the usual verification is replaced by
+/// pinning the SHA-256 fingerprint of the bridge certificate. The fingerprint is checked
+/// on every connection, during the handshake and before anything is sent.
+///
+/// The v1 `Bridge` is still the way to talk to older bridges.
+#[derive(Debug)]
+pub struct ClipBridge {
+    ip: String,
+    key: String,
+    pinned: Pinned,
+}
+
+/// How long a request can go without an answer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+impl ClipBridge {
+    /// Connects to the bridge at `ip` with the given application key. If a `pin` is given
+    /// the bridge certificate has to match it, otherwise whatever the bridge presents
+    /// now gets trusted and can be read back with `fingerprint` to pin it next time.
+    pub fn connect(ip: &str, key: &str, pin: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let address = format!("{}:443", ip);
+        let pin = match pin {
+            Some(pin) => pin.to_owned(),
+            None => probe(&address)?,
+        };
+        let pinned = Pinned::new(&address, &pin)?;
+        // a bridge with another certificate is turned away here rather than on first use
+        pinned.connect(REQUEST_TIMEOUT)?;
+
+        Ok(ClipBridge {
+            ip: ip.to_owned(),
+            key: key.to_owned(),
+            pinned,
+        })
+    }
+
+    /// SHA-256 fingerprint (hex) of the certificate the bridge presented
+    pub fn fingerprint(&self) -> &str {
+        &self.pinned.fingerprint
+    }
+
+    /// The IP of the bridge
+    pub fn ip(&self) -> &str {
+        &self.ip
+    }
+
+    /// Subscribes to the server-sent event stream of the bridge
+    pub fn events(&self) -> Result<EventStream, Box<dyn Error>> {
+        Ok(EventStream::new(
+            self.pinned.clone(),
+            "eventstream/clip/v2",
+            &self.key,
+        ))
+    }
+
+    /// Sends a request to a path under `https://{ip}/` and unwraps the v2 response envelope
+    pub fn request<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&Value>,
+    ) -> Result<Vec<T>, Box<dyn Error>> {
+        let target = format!("https://{}/{}", self.ip, path);
+        // the key is sent in a header, so the address can be logged as it is
+        let span = debug_span!("request", method = %method, url = %target);
+        let _entered = span.enter();
+        let started = Instant::now();
+        let body = body.map(serde_json::to_vec).transpose()?;
+        let response = self
+            .pinned
+            .send(
+                method.as_str(),
+                path,
+                &[("hue-application-key", &self.key)],
+                body.as_deref(),
+                REQUEST_TIMEOUT,
+            )
+            .inspect_err(|e| {
+                debug!(error = %e, elapsed_ms = started.elapsed().as_millis() as u64, "No response")
+            })?;
+        debug!(
+            status = response.status,
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "Response"
+        );
+        let envelope: Envelope<T> = response.json()?;
+        if envelope.errors.is_empty() {
+            Ok(envelope.data)
+        } else {
+            Err(HueError::Api {
+                errors: envelope.errors.into_iter().map(|e| e.description).collect(),
+            }
+            .into())
+        }
+    }
+
+    /// Lists every resource on the bridge
+    pub fn resources(&self) -> Result<Vec<Resource>, Box<dyn Error>> {
+        self.request(Method::GET, "clip/v2/resource", None)
+    }
+
+    /// Lists all resources of the given type, e.g.
+    /// ```no_run
+    /// # use huemanity::clip::*;
+    /// # let clip = ClipBridge::connect("192.168.1.2", "key", None).unwrap();
+    /// let lights: Vec<Light> = clip.get(ResourceType::Light).unwrap();
+    /// ```
+    pub fn get<T: DeserializeOwned>(&self, rtype: ResourceType) -> Result<Vec<T>, Box<dyn Error>> {
+        self.request(Method::GET, &format!("clip/v2/resource/{}", rtype), None)
+    }
+
+    /// Gets a single resource by its ID
+    pub fn get_one<T: DeserializeOwned>(
+        &self,
+        rtype: ResourceType,
+        id: &str,
+    ) -> Result<T, Box<dyn Error>> {
+        self.request(
+            Method::GET,
+            &format!("clip/v2/resource/{}/{}", rtype, id),
+            None,
+        )?
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("{} {} not found", rtype, id).into())
+    }
+
+    /// Updates a resource, returns references to what was changed
+    pub fn put(
+        &self,
+        rtype: ResourceType,
+        id: &str,
+        body: &Value,
+    ) -> Result<Vec<ResourceRef>, Box<dyn Error>> {
+        self.request(
+            Method::PUT,
+            &format!("clip/v2/resource/{}/{}", rtype, id),
+            Some(body),
+        )
+    }
+
+    /// Creates a resource, returns references to what was created
+    pub fn post(
+        &self,
+        rtype: ResourceType,
+        body: &Value,
+    ) -> Result<Vec<ResourceRef>, Box<dyn Error>> {
+        self.request(
+            Method::POST,
+            &format!("clip/v2/resource/{}", rtype),
+            Some(body),
+        )
+    }
+
+    /// Deletes a resource, returns references to what was deleted
+    pub fn delete(
+        &self,
+        rtype: ResourceType,
+        id: &str,
+    ) -> Result<Vec<ResourceRef>, Box<dyn Error>> {
+        self.request(
+            Method::DELETE,
+            &format!("clip/v2/resource/{}/{}", rtype, id),
+            None,
+        )
+    }
+}
+
+/// Does a TLS handshake with the bridge and returns the SHA-256 fingerprint of its certificate
+pub fn fingerprint(ip: &str) -> Result<String, Box<dyn Error>> {
+    probe(&format!("{}:443", ip))
+}
+
+/// Does a TLS handshake with whatever answers on `address` and returns the fingerprint
+/// of its certificate, without sending anything
+fn probe(address: &str) -> Result<String, Box<dyn Error>> {
+    let mut builder = SslConnector::builder(SslMethod::tls())?;
+    builder.set_verify(SslVerifyMode::NONE);
+    let stream = builder
+        .build()
+        .configure()?
+        .verify_hostname(false)
+        .use_server_name_indication(false)
+        .connect(host(address), TcpStream::connect(address)?)?;
+    let cert = stream
+        .ssl()
+        .peer_certificate()
+        .ok_or("bridge did not present a certificate")?;
+    Ok(hex_digest(&cert)?)
+}
+
+fn hex_digest(cert: &X509Ref) -> Result<String, ErrorStack> {
+    let digest = cert.digest(MessageDigest::sha256())?;
+    Ok(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// The host part of an address like `192.168.1.2:443`
+fn host(address: &str) -> &str {
+    address.rsplit_once(':').map_or(address, |(host, _)| host)
+}
+
+/// Opens TLS connections to a bridge which only go through if it presents the certificate
+/// with the pinned SHA-256 fingerprint, so whatever is sent over them (like the
+/// application key) only reaches that bridge.
+///
+/// The certificate chain and host name are not checked, as bridges are known by their
+/// certificate rather than their address.
+#[derive(Clone)]
+pub struct Pinned {
+    address: String,
+    fingerprint: String,
+    connector: SslConnector,
+}
+
+impl fmt::Debug for Pinned {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pinned")
+            .field("address", &self.address)
+            .field("fingerprint", &self.fingerprint)
+            .finish()
+    }
+}
+
+impl Pinned {
+    /// Pins the certificate with the given fingerprint (hex) for the bridge at `address`,
+    /// like `192.168.1.2:443`
+    pub fn new(address: &str, fingerprint: &str) -> Result<Self, Box<dyn Error>> {
+        let fingerprint = fingerprint.to_lowercase();
+        let pin = fingerprint.clone();
+        let mut builder = SslConnector::builder(SslMethod::tls())?;
+        // only the certificate the bridge proves to own matters, the ones above it don't
+        builder.set_verify_callback(SslVerifyMode::PEER, move |_, context| {
+            context.error_depth() > 0
+                || context
+                    .current_cert()
+                    .and_then(|cert| hex_digest(cert).ok())
+                    .is_some_and(|found| found == pin)
+        });
+        Ok(Pinned {
+            address: address.to_owned(),
+            fingerprint,
+            connector: builder.build(),
+        })
+    }
+
+    /// Opens a connection, failing with `HueError::CertificateMismatch` if the other end
+    /// is not the pinned bridge. Reads and writes give up after `timeout`.
+    pub fn connect(&self, timeout: Duration) -> Result<SslStream<TcpStream>, Box<dyn Error>> {
+        let tcp = TcpStream::connect(&self.address)?;
+        tcp.set_read_timeout(Some(timeout))?;
+        tcp.set_write_timeout(Some(timeout))?;
+        let handshake = self
+            .connector
+            .configure()?
+            .verify_hostname(false)
+            .use_server_name_indication(false)
+            .connect(host(&self.address), tcp);
+        let found = match &handshake {
+            Ok(stream) => stream
+                .ssl()
+                .peer_certificate()
+                .map(|cert| hex_digest(&cert)),
+            // a failed handshake is most likely a certificate that isn't the pinned one
+            Err(_) => probe(&self.address).ok().map(Ok),
+        }
+        .transpose()?;
+        match found {
+            Some(found) if found != self.fingerprint => Err(HueError::CertificateMismatch {
+                expected: self.fingerprint.clone(),
+                found,
+            }
+            .into()),
+            None if handshake.is_ok() => Err("bridge did not present a certificate".into()),
+            _ => Ok(handshake?),
+        }
+    }
+
+    /// Sends an HTTP/1.1 request for `path` over a new connection and reads the head of
+    /// the response
+    pub(crate) fn send(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: Option<&[u8]>,
+        timeout: Duration,
+    ) -> Result<Response, Box<dyn Error>> {
+        let mut head = format!(
+            "{} /{} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+            method,
+            path.trim_start_matches('/'),
+            host(&self.address)
+        );
+        let body_headers = body.map(|body| {
+            [
+                ("Content-Type", "application/json".to_owned()),
+                ("Content-Length", body.len().to_string()),
+            ]
+        });
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (*name, value.to_string()))
+            .chain(body_headers.into_iter().flatten());
+        for (name, value) in headers {
+            if value.contains(['\r', '\n']) {
+                return Err(format!("`{}` header can't span lines", name).into());
+            }
+            let _ = write!(head, "{}: {}\r\n", name, value);
+        }
+        head.push_str("\r\n");
+
+        let mut stream = self.connect(timeout)?;
+        stream.write_all(head.as_bytes())?;
+        if let Some(body) = body {
+            stream.write_all(body)?;
+        }
+        stream.flush()?;
+        Response::read(BufReader::new(stream))
+    }
+}
+
+/// A response read off a pinned connection, with the body left to read
+pub(crate) struct Response {
+    pub status: u16,
+    pub body: Box<dyn BufRead + Send>,
+}
+
+impl Response {
+    fn read<R: BufRead + Send + 'static>(mut reader: R) -> Result<Self, Box<dyn Error>> {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let status = line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or("malformed response from the bridge")?;
+
+        let mut length = None;
+        let mut chunked = false;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Err("bridge closed the connection in the middle of a response".into());
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                match name.trim().to_lowercase().as_str() {
+                    "content-length" => length = value.trim().parse().ok(),
+                    "transfer-encoding" => chunked = value.to_lowercase().contains("chunked"),
+                    _ => (),
+                }
+            }
+        }
+
+        let body: Box<dyn BufRead + Send> = match (chunked, length) {
+            (true, _) => Box::new(BufReader::new(Chunked {
+                inner: reader,
+                left: 0,
+                done: false,
+            })),
+            (false, Some(length)) => Box::new(reader.take(length)),
+            // without either, the body ends with the connection
+            (false, None) => Box::new(reader),
+        };
+        Ok(Response { status, body })
+    }
+
+    pub fn json<T: DeserializeOwned>(self) -> Result<T, Box<dyn Error>> {
+        Ok(serde_json::from_reader(self.body)?)
+    }
+}
+
+/// Reads the body of a response sent in chunks
+struct Chunked<R> {
+    inner: R,
+    /// What is left of the current chunk
+    left: usize,
+    done: bool,
+}
+
+impl<R: BufRead> Read for Chunked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.left == 0 {
+            // the line ending the last chunk comes before the size of the next one
+            let mut line = String::new();
+            while line.trim().is_empty() {
+                line.clear();
+                if self.inner.read_line(&mut line)? == 0 {
+                    self.done = true;
+                    return Ok(0);
+                }
+            }
+            let size = line.trim().split(';').next().unwrap_or_default();
+            self.left = usize::from_str_radix(size, 16)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "malformed chunk size"))?;
+            if self.left == 0 {
+                self.done = true;
+                return Ok(0);
+            }
+        }
+        let limit = buf.len().min(self.left);
+        let read = self.inner.read(&mut buf[..limit])?;
+        if read == 0 {
+            self.done = true;
+        }
+        self.left -= read;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::ssl::SslAcceptor;
+    use openssl::x509::{X509NameBuilder, X509};
+    use std::io::Cursor;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Serves HTTPS on a local port with a fresh self signed certificate, answering the
+    /// `n`th request (with its head) with whatever `handler` returns. Returns the address
+    /// and the fingerprint of the certificate.
+    pub(crate) fn stub<F>(mut handler: F) -> (String, String)
+    where
+        F: FnMut(usize, &str) -> Vec<u8> + Send + 'static,
+    {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "bridge").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        cert.set_serial_number(&serial).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = cert.build();
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        let acceptor = acceptor.build();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let mut requests = 0;
+            for tcp in listener.incoming() {
+                let mut stream = match acceptor.accept(tcp.unwrap()) {
+                    Ok(stream) => stream,
+                    // the client turned the certificate down
+                    Err(_) => continue,
+                };
+                let mut head = Vec::new();
+                let mut byte = [0];
+                while !head.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap_or(0) == 1 {
+                    head.push(byte[0]);
+                }
+                // probes hang up without asking anything
+                if head.is_empty() {
+                    continue;
+                }
+                let response = handler(requests, &String::from_utf8_lossy(&head));
+                requests += 1;
+                let _ = stream.write_all(&response);
+            }
+        });
+        (address, hex_digest(&cert).unwrap())
+    }
+
+    #[test]
+    fn sends_requests_to_the_pinned_certificate() {
+        let (address, fingerprint) = stub(|_, head| {
+            assert!(head.starts_with("GET /clip/v2/resource HTTP/1.1\r\n"));
+            assert!(head.contains("hue-application-key: key\r\n"));
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              9\r\n{\"data\": \r\n6\r\n[1, 2]\r\n1\r\n}\r\n0\r\n\r\n"
+                .to_vec()
+        });
+        let pinned = Pinned::new(&address, &fingerprint.to_uppercase()).unwrap();
+        let response = pinned
+            .send(
+                "GET",
+                "clip/v2/resource",
+                &[("hue-application-key", "key")],
+                None,
+                REQUEST_TIMEOUT,
+            )
+            .unwrap();
+        assert_eq!(response.status, 200);
+        let envelope: Envelope<u8> = response.json().unwrap();
+        assert_eq!(envelope.data, [1, 2]);
+    }
+
+    #[test]
+    fn sends_nothing_to_another_certificate() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let seen = requests.clone();
+        let (address, fingerprint) = stub(move |_, _| {
+            seen.fetch_add(1, Ordering::SeqCst);
+            b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}".to_vec()
+        });
+        let pinned = Pinned::new(&address, &"00".repeat(32)).unwrap();
+        let error = pinned
+            .send(
+                "GET",
+                "",
+                &[("hue-application-key", "key")],
+                None,
+                REQUEST_TIMEOUT,
+            )
+            .err()
+            .unwrap();
+        match error.downcast_ref::<HueError>() {
+            Some(HueError::CertificateMismatch { found, .. }) => assert_eq!(*found, fingerprint),
+            _ => panic!("expected a certificate mismatch, got {}", error),
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn reads_bodies_by_length() {
+        let response = Response::read(Cursor::new(
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 2\r\n\r\n{}trailing".to_vec(),
+        ))
+        .unwrap();
+        assert_eq!(response.status, 404);
+        let mut body = String::new();
+        response.body.take(100).read_to_string(&mut body).unwrap();
+        assert_eq!(body, "{}");
+    }
+}
@@ -0,0 +1,203 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// Colours that can be referred to by name
+pub const NAMED: &[(&str, Rgb)] = &[
+    ("red", Rgb::new(255, 0, 0)),
+    ("orange", Rgb::new(255, 128, 0)),
+    ("amber", Rgb::new(255, 191, 0)),
+    ("yellow", Rgb::new(255, 255, 0)),
+    ("lime", Rgb::new(128, 255, 0)),
+    ("green", Rgb::new(0, 255, 0)),
+    ("teal", Rgb::new(0, 128, 128)),
+    ("cyan", Rgb::new(0, 255, 255)),
+    ("blue", Rgb::new(0, 0, 255)),
+    ("indigo", Rgb::new(75, 0, 130)),
+    ("purple", Rgb::new(128, 0, 255)),
+    ("magenta", Rgb::new(255, 0, 255)),
+    ("pink", Rgb::new(255, 105, 180)),
+    ("white", Rgb::new(255, 255, 255)),
+];
+
+/// Warmest and coolest colour temperatures (in mired) the lights take
+pub const MIRED_RANGE: (u16, u16) = (153, 500);
+
+/// A colour in sRGB, which gets converted to the CIE xy the bridge understands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Rgb { r, g, b }
+    }
+
+    /// Converts the colour to CIE xy coordinates (wide gamut, D65)
+    pub fn xy(self) -> [f32; 2] {
+        let [r, g, b] = [self.r, self.g, self.b].map(linear);
+        let x = r * 0.664_511 + g * 0.154_324 + b * 0.162_028;
+        let y = r * 0.283_881 + g * 0.668_433 + b * 0.047_685;
+        let z = r * 0.000_088 + g * 0.072_310 + b * 0.986_039;
+        let sum = x + y + z;
+        if sum == 0.0 {
+            // black has no chromaticity, use the white point
+            return [0.3127, 0.3290];
+        }
+        [x / sum, y / sum]
+    }
+
+    /// The colour as the 16 bit values used by entertainment streaming,
+    /// scaled by `brightness` (0 to 1)
+    pub fn scaled(self, brightness: f32) -> [u16; 3] {
+        let brightness = brightness.clamp(0.0, 1.0);
+        [self.r, self.g, self.b].map(|c| (f32::from(c) * 257.0 * brightness) as u16)
+    }
+
+    /// Colour of the given hue (0 to 360 degrees) at full saturation
+    pub fn from_hue(hue: f32) -> Self {
+        let h = hue.rem_euclid(360.0) / 60.0;
+        let x = 1.0 - (h % 2.0 - 1.0).abs();
+        let (r, g, b) = match h as u8 {
+            0 => (1.0, x, 0.0),
+            1 => (x, 1.0, 0.0),
+            2 => (0.0, 1.0, x),
+            3 => (0.0, x, 1.0),
+            4 => (x, 0.0, 1.0),
+            _ => (1.0, 0.0, x),
+        };
+        Rgb::new((r * 255.0) as u8, (g * 255.0) as u8, (b * 255.0) as u8)
+    }
+}
+
+/// Undoes the sRGB gamma
+fn linear(channel: u8) -> f32 {
+    let c = f32::from(channel) / 255.0;
+    if c > 0.040_45 {
+        ((c + 0.055) / 1.055).powf(2.4)
+    } else {
+        c / 12.92
+    }
+}
+
+impl FromStr for Rgb {
+    type Err = String;
+
+    /// Parses a colour name (`red`), hex code (`#ff0000` or `#f00`) or `rgb(255, 0, 0)`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        if let Some(&(_, rgb)) = NAMED.iter().find(|(name, _)| *name == s) {
+            return Ok(rgb);
+        }
+
+        let invalid = || format!("`{}` is not a colour name, #hex code or rgb(r, g, b)", s);
+        if let Some(hex) = s.strip_prefix('#') {
+            // from_str_radix would take a sign too
+            if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(invalid());
+            }
+            let channel = |i: usize, len: usize| u8::from_str_radix(hex.get(i..i + len)?, 16).ok();
+            let rgb = match hex.len() {
+                6 => (channel(0, 2), channel(2, 2), channel(4, 2)),
+                // #f00 is short for #ff0000
+                3 => (channel(0, 1), channel(1, 1), channel(2, 1)),
+                _ => return Err(invalid()),
+            };
+            let short = hex.len() == 3;
+            return match rgb {
+                (Some(r), Some(g), Some(b)) if short => Ok(Rgb::new(r * 17, g * 17, b * 17)),
+                (Some(r), Some(g), Some(b)) => Ok(Rgb::new(r, g, b)),
+                _ => Err(invalid()),
+            };
+        }
+        if let Some(inner) = s.strip_prefix("rgb(").and_then(|s| s.strip_suffix(')')) {
+            let channels = inner
+                .split(',')
+                .map(|c| c.trim().parse::<u8>())
+                .collect::<Result<Vec<u8>, _>>()
+                .map_err(|_| invalid())?;
+            if let [r, g, b] = channels[..] {
+                return Ok(Rgb::new(r, g, b));
+            }
+        }
+        Err(invalid())
+    }
+}
+
+impl fmt::Display for Rgb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
+}
+
+/// Converts a colour temperature in Kelvin to the mired the bridge takes,
+/// clamped to what the lights support
+pub fn kelvin_to_mired(kelvin: u32) -> u16 {
+    let mired = 1_000_000 / kelvin.max(1);
+    mired.clamp(u32::from(MIRED_RANGE.0), u32::from(MIRED_RANGE.1)) as u16
+}
+
+/// Converts mired back to Kelvin
+pub fn mired_to_kelvin(mired: u16) -> u32 {
+    1_000_000 / u32::from(mired.max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_colours() {
+        assert_eq!("red".parse(), Ok(Rgb::new(255, 0, 0)));
+        assert_eq!(" White ".parse(), Ok(Rgb::new(255, 255, 255)));
+        assert_eq!("#FF8800".parse(), Ok(Rgb::new(255, 136, 0)));
+        assert_eq!("#f80".parse(), Ok(Rgb::new(255, 136, 0)));
+        assert_eq!("rgb(1, 2,3)".parse(), Ok(Rgb::new(1, 2, 3)));
+        for invalid in [
+            "reddish",
+            "#ff00",
+            "#gg0000",
+            "#+f+f+f",
+            "rgb(1, 2)",
+            "rgb(1, 2, 256)",
+            "",
+        ] {
+            assert!(invalid.parse::<Rgb>().is_err(), "{} parsed", invalid);
+        }
+        assert_eq!(Rgb::new(255, 136, 0).to_string(), "#ff8800");
+    }
+
+    #[test]
+    fn converts_to_xy() {
+        let close =
+            |[x, y]: [f32; 2], [ex, ey]: [f32; 2]| (x - ex).abs() < 0.001 && (y - ey).abs() < 0.001;
+        // the corners of the wide gamut and the D65 white point
+        assert!(close(Rgb::new(255, 0, 0).xy(), [0.7006, 0.2993]));
+        assert!(close(Rgb::new(0, 255, 0).xy(), [0.1724, 0.7468]));
+        assert!(close(Rgb::new(0, 0, 255).xy(), [0.1355, 0.0399]));
+        assert!(close(Rgb::new(255, 255, 255).xy(), [0.3227, 0.329]));
+        assert_eq!(Rgb::new(0, 0, 0).xy(), [0.3127, 0.3290]);
+    }
+
+    #[test]
+    fn converts_colour_temperatures() {
+        assert_eq!(kelvin_to_mired(2700), 370);
+        assert_eq!(kelvin_to_mired(6500), 153);
+        // beyond what the lights take
+        assert_eq!(kelvin_to_mired(1000), MIRED_RANGE.1);
+        assert_eq!(kelvin_to_mired(10000), MIRED_RANGE.0);
+        assert_eq!(kelvin_to_mired(0), MIRED_RANGE.1);
+        assert_eq!(mired_to_kelvin(370), 2702);
+        assert_eq!(mired_to_kelvin(0), 1_000_000);
+    }
+
+    #[test]
+    fn makes_hues() {
+        assert_eq!(Rgb::from_hue(0.0), Rgb::new(255, 0, 0));
+        assert_eq!(Rgb::from_hue(120.0), Rgb::new(0, 255, 0));
+        assert_eq!(Rgb::from_hue(-120.0), Rgb::new(0, 0, 255));
+        assert_eq!(Rgb::new(255, 0, 0).scaled(0.5), [32767, 0, 0]);
+    }
+}
@@ -0,0 +1,414 @@
+use crate::bridge::Bridge;
+use crate::color::{kelvin_to_mired, Rgb};
+use crate::lightstructs::*;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Shortest time between two requests to the bridge. It copes with about ten light
+/// updates a second and starts dropping them beyond that.
+pub const REQUEST_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often a running effect checks whether it has been cancelled
+const CANCEL_CHECK: Duration = Duration::from_millis(50);
+
+/// Names of the effects, as taken by `Effect::named`
+pub const NAMES: &[&str] = &[
+    "fade", "breathe", "rainbow", "strobe", "candle", "police", "sunrise",
+];
+
+/// Colours a sunrise goes through, from first light to daylight
+const SUNRISE: &[(f32, Rgb, u8)] = &[
+    (0.0, Rgb::new(255, 30, 0), 1),
+    (0.35, Rgb::new(255, 110, 20), 80),
+    (0.7, Rgb::new(255, 180, 110), 180),
+    (1.0, Rgb::new(255, 235, 215), 254),
+];
+
+/// Time-based animations that can be run on a set of lights
+#[derive(Debug, Clone)]
+pub enum Effect {
+    /// Goes from one state to another over `seconds`
+    Fade {
+        from: SendableState,
+        to: SendableState,
+        seconds: f32,
+    },
+    /// Slowly brightens and dims, one breath every `period` seconds
+    Breathe { color: Rgb, period: f32 },
+    /// Cycles through the colour wheel every `period` seconds, with the lights
+    /// spread out over the wheel
+    Rainbow { period: f32 },
+    /// Flashes on and off `hz` times a second (as far as the rate limit allows)
+    Strobe { color: Rgb, hz: f32 },
+    /// Warm, randomly flickering light
+    Candle,
+    /// Alternates red and blue between every other light
+    Police,
+    /// Goes from a dim red glow to bright daylight over `seconds`
+    Sunrise { seconds: f32 },
+}
+
+/// What an effect does to the lights at one point: a state for every light and
+/// how long to leave them in it
+#[derive(Debug, Clone)]
+pub struct Step {
+    pub states: Vec<SendableState>,
+    pub hold: Duration,
+}
+
+impl Effect {
+    /// Makes an effect with default settings from its name (see `NAMES`), using
+    /// `color` and `seconds` where the effect takes them
+    pub fn named(name: &str, color: Option<Rgb>, seconds: Option<f32>) -> Result<Self, String> {
+        if let Some(seconds) = seconds.filter(|s| !s.is_finite() || *s < 0.0) {
+            return Err(format!("`{}` is not a number of seconds", seconds));
+        }
+        let color = color.unwrap_or(Rgb::new(255, 255, 255));
+        let effect = match name.to_lowercase().as_str() {
+            "fade" => Effect::Fade {
+                from: state!(on: true, bri: 1, xy: color.xy()),
+                to: state!(on: true, bri: 254, xy: color.xy()),
+                seconds: seconds.unwrap_or(10.0),
+            },
+            "breathe" => Effect::Breathe {
+                color,
+                period: seconds.unwrap_or(4.0),
+            },
+            "rainbow" => Effect::Rainbow {
+                period: seconds.unwrap_or(12.0),
+            },
+            "strobe" => Effect::Strobe {
+                color,
+                hz: seconds.map_or(5.0, |s| 1.0 / s.max(0.01)),
+            },
+            "candle" => Effect::Candle,
+            "police" => Effect::Police,
+            "sunrise" => Effect::Sunrise {
+                seconds: seconds.unwrap_or(600.0),
+            },
+            _ => {
+                return Err(format!(
+                    "`{}` is not an effect, try one of: {}",
+                    name,
+                    NAMES.join(", ")
+                ))
+            }
+        };
+        Ok(effect)
+    }
+
+    /// Works out step `n` of the effect for the given number of lights, or `None`
+    /// once the effect is over. Effects that loop never end.
+    pub fn step(&self, n: u32, lights: usize, random: &mut Random) -> Option<Step> {
+        let all = |state: SendableState, hold: f32| Step {
+            states: vec![state; lights],
+            hold: Duration::from_secs_f32(hold),
+        };
+        let step = match self {
+            Effect::Fade { from, to, seconds } => {
+                // the fade is cut into steps of about a second so it can be stopped
+                // part way, the bridge smooths out each step with a transition
+                let steps = seconds.ceil().max(1.0) as u32;
+                let length = seconds / steps as f32;
+                match n {
+                    0 => all(with_transition(from.clone(), 0.0), 0.0),
+                    n if n <= steps => all(
+                        with_transition(blend(from, to, n as f32 / steps as f32), length),
+                        length,
+                    ),
+                    _ => return None,
+                }
+            }
+            Effect::Breathe { color, period } => {
+                let bri = if n % 2 == 1 { 10 } else { 254 };
+                let half = period / 2.0;
+                all(
+                    with_transition(state!(on: true, bri: bri, xy: color.xy()), half),
+                    half,
+                )
+            }
+            Effect::Rainbow { period } => {
+                let length = period / 12.0;
+                Step {
+                    states: (0..lights)
+                        .map(|i| {
+                            let turn = (n as f32 / 12.0 + i as f32 / lights as f32).fract();
+                            let hue = (turn * 65535.0) as u32;
+                            with_transition(state!(on: true, hue: hue, sat: 254), length)
+                        })
+                        .collect(),
+                    hold: Duration::from_secs_f32(length),
+                }
+            }
+            Effect::Strobe { color, hz } => {
+                let state = if n % 2 == 1 {
+                    state!(on: false)
+                } else {
+                    state!(on: true, bri: 254, xy: color.xy())
+                };
+                all(with_transition(state, 0.0), 0.5 / hz.max(0.01))
+            }
+            Effect::Candle => Step {
+                states: (0..lights)
+                    .map(|_| {
+                        let bri = 120 + random.below(135) as u8;
+                        let state = state!(on: true, bri: bri, ct: kelvin_to_mired(2000));
+                        with_transition(state, random.below(3) as f32 / 10.0)
+                    })
+                    .collect(),
+                hold: Duration::from_millis(100 + random.below(300)),
+            },
+            Effect::Police => Step {
+                states: (0..lights)
+                    .map(|i| {
+                        let color = if (i as u32 + n) % 2 == 1 {
+                            Rgb::new(0, 0, 255)
+                        } else {
+                            Rgb::new(255, 0, 0)
+                        };
+                        with_transition(state!(on: true, bri: 254, xy: color.xy()), 0.0)
+                    })
+                    .collect(),
+                hold: Duration::from_millis(400),
+            },
+            Effect::Sunrise { seconds } => {
+                let steps = (seconds / 2.0).ceil().max(1.0) as u32;
+                let length = seconds / steps as f32;
+                if n > steps {
+                    return None;
+                }
+                let (color, bri) = sunrise(n as f32 / steps as f32);
+                let transition = if n == 0 { 0.0 } else { length };
+                all(
+                    with_transition(state!(on: true, bri: bri, xy: color.xy()), transition),
+                    length,
+                )
+            }
+        };
+        Some(step)
+    }
+
+    /// Runs the effect on `lights` until it is over or `stop` gets set, which can be
+    /// done from another thread. Requests are spaced out by `REQUEST_INTERVAL`, so
+    /// steps on many lights take longer than the effect asks for.
+    ///
+    /// ```no_run
+    /// # use huemanity::bridge::Bridge;
+    /// # use huemanity::effects::Effect;
+    /// # use std::sync::atomic::AtomicBool;
+    /// let bridge = Bridge::link();
+    /// let effect = Effect::named("candle", None, None).unwrap();
+    /// effect.run(&bridge, &[1, 2], &AtomicBool::new(false)).unwrap();
+    /// ```
+    pub fn run(
+        &self,
+        bridge: &Bridge,
+        lights: &[u8],
+        stop: &AtomicBool,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut random = Random::new();
+        let mut last_request: Option<Instant> = None;
+        for n in 0.. {
+            let step = match self.step(n, lights.len(), &mut random) {
+                Some(step) => step,
+                None => break,
+            };
+            let started = Instant::now();
+            for (light, state) in lights.iter().zip(&step.states) {
+                if let Some(wait) =
+                    last_request.and_then(|t| REQUEST_INTERVAL.checked_sub(t.elapsed()))
+                {
+                    sleep(wait);
+                }
+                if stop.load(Ordering::Relaxed) {
+                    return Ok(());
+                }
+                bridge.state(*light, state)?;
+                last_request = Some(Instant::now());
+            }
+            while started.elapsed() < step.hold {
+                if stop.load(Ordering::Relaxed) {
+                    return Ok(());
+                }
+                sleep(CANCEL_CHECK.min(step.hold - started.elapsed()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sets how long the bridge takes to get to a state, in seconds
+fn with_transition(mut state: SendableState, seconds: f32) -> SendableState {
+    state.transitiontime = Some((seconds * 10.0).round().clamp(0.0, 65535.0) as u16);
+    state
+}
+
+/// The state part way (`t` from 0 to 1) between two states. Values only one of the
+/// states has are taken from `to`.
+fn blend(from: &SendableState, to: &SendableState, t: f32) -> SendableState {
+    fn mix(a: f32, b: f32, t: f32) -> f32 {
+        a + (b - a) * t
+    }
+    let mut state = to.clone();
+    if let (Some(a), Some(b)) = (from.bri, to.bri) {
+        state.bri = Some(mix(f32::from(a), f32::from(b), t).round() as u8);
+    }
+    if let (Some(a), Some(b)) = (from.hue, to.hue) {
+        state.hue = Some(mix(a as f32, b as f32, t).round() as u32);
+    }
+    if let (Some(a), Some(b)) = (from.sat, to.sat) {
+        state.sat = Some(mix(f32::from(a), f32::from(b), t).round() as u8);
+    }
+    if let (Some(a), Some(b)) = (from.ct, to.ct) {
+        state.ct = Some(mix(f32::from(a), f32::from(b), t).round() as u16);
+    }
+    if let (Some(a), Some(b)) = (from.xy, to.xy) {
+        state.xy = Some([mix(a[0], b[0], t), mix(a[1], b[1], t)]);
+    }
+    state
+}
+
+/// Colour and brightness of a sunrise `t` (0 to 1) of the way through
+fn sunrise(t: f32) -> (Rgb, u8) {
+    let next = SUNRISE
+        .iter()
+        .position(|(at, _, _)| *at >= t)
+        .unwrap_or(SUNRISE.len() - 1);
+    if next == 0 {
+        return (SUNRISE[0].1, SUNRISE[0].2);
+    }
+    let ((start, a, a_bri), (end, b, b_bri)) = (SUNRISE[next - 1], SUNRISE[next]);
+    let t = (t - start) / (end - start);
+    let mix = |a: u8, b: u8| (f32::from(a) + (f32::from(b) - f32::from(a)) * t).round() as u8;
+    (
+        Rgb::new(mix(a.r, b.r), mix(a.g, b.g), mix(a.b, b.b)),
+        mix(a_bri, b_bri),
+    )
+}
+
+/// Small xorshift generator for the effects that flicker, nothing here needs
+/// proper randomness
+pub struct Random(u64);
+
+impl Random {
+    /// Seeds the generator from the clock
+    pub fn new() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_nanos() as u64);
+        Random(nanos | 1)
+    }
+
+    /// A number from 0 up to (not including) `n`
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n.max(1)
+    }
+}
+
+impl Default for Random {
+    fn default() -> Self {
+        Random::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_impossible_lengths() {
+        for seconds in &[-1.0, f32::NAN, f32::INFINITY] {
+            for name in NAMES {
+                assert!(Effect::named(name, None, Some(*seconds)).is_err());
+            }
+        }
+        assert!(Effect::named("fade", None, Some(0.0)).is_ok());
+        assert!(Effect::named("disco", None, None).is_err());
+    }
+
+    #[test]
+    fn fades_in_steps() {
+        let effect = Effect::named("fade", Some(Rgb::new(255, 0, 0)), Some(2.5)).unwrap();
+        let mut random = Random::new();
+        let first = effect.step(0, 2, &mut random).unwrap();
+        assert_eq!(first.states.len(), 2);
+        assert_eq!(first.states[0].bri, Some(1));
+        assert_eq!(first.states[0].transitiontime, Some(0));
+        assert_eq!(first.hold, Duration::from_secs(0));
+
+        let second = effect.step(1, 2, &mut random).unwrap();
+        assert_eq!(second.states[0].bri, Some(85));
+        assert_eq!(second.states[0].transitiontime, Some(8));
+
+        let last = effect.step(3, 2, &mut random).unwrap();
+        assert_eq!(last.states[1].bri, Some(254));
+        assert!(effect.step(4, 2, &mut random).is_none());
+    }
+
+    #[test]
+    fn loops_forever() {
+        let mut random = Random::new();
+        for name in &["breathe", "rainbow", "strobe", "candle", "police"] {
+            let effect = Effect::named(name, None, None).unwrap();
+            let step = effect.step(1000, 3, &mut random).unwrap();
+            assert_eq!(step.states.len(), 3);
+        }
+    }
+
+    #[test]
+    fn spreads_the_rainbow() {
+        let effect = Effect::named("rainbow", None, Some(12.0)).unwrap();
+        let step = effect.step(0, 4, &mut Random::new()).unwrap();
+        let hues: Vec<_> = step.states.iter().map(|s| s.hue.unwrap()).collect();
+        assert_eq!(hues, vec![0, 16383, 32767, 49151]);
+        assert_eq!(step.hold, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn blends_states() {
+        let from = state!(on: true, bri: 0, ct: 200, xy: [0.0, 1.0]);
+        let to = state!(on: true, bri: 200, ct: 400, xy: [1.0, 0.0], hue: 100);
+        let half = blend(&from, &to, 0.5);
+        assert_eq!(half.bri, Some(100));
+        assert_eq!(half.ct, Some(300));
+        assert_eq!(half.xy, Some([0.5, 0.5]));
+        // only `to` has a hue, so it is taken as it is
+        assert_eq!(half.hue, Some(100));
+        assert_eq!(blend(&from, &to, 0.0).bri, Some(0));
+        assert_eq!(blend(&from, &to, 1.0).bri, Some(200));
+    }
+
+    #[test]
+    fn rises_from_red_to_daylight() {
+        assert_eq!(sunrise(0.0), (Rgb::new(255, 30, 0), 1));
+        assert_eq!(sunrise(0.35), (Rgb::new(255, 110, 20), 80));
+        assert_eq!(sunrise(1.0), (Rgb::new(255, 235, 215), 254));
+        let (color, bri) = sunrise(0.175);
+        assert_eq!(color, Rgb::new(255, 70, 10));
+        assert!(bri > 1 && bri < 80);
+
+        let effect = Effect::named("sunrise", None, Some(10.0)).unwrap();
+        let mut random = Random::new();
+        assert_eq!(
+            effect.step(0, 1, &mut random).unwrap().states[0].bri,
+            Some(1)
+        );
+        assert_eq!(
+            effect.step(5, 1, &mut random).unwrap().states[0].bri,
+            Some(254)
+        );
+        assert!(effect.step(6, 1, &mut random).is_none());
+    }
+
+    #[test]
+    fn keeps_random_numbers_in_range() {
+        let mut random = Random::new();
+        assert!((0..1000).all(|_| random.below(7) < 7));
+        assert_eq!(random.below(0), 0);
+    }
+}
@@ -0,0 +1,357 @@
+use openssl::error::ErrorStack;
+use openssl::ssl::{SslConnector, SslMethod, SslStream, SslVerifyMode};
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// The UDP port bridges listen on for entertainment streaming
+pub const STREAMING_PORT: u16 = 2100;
+
+/// The bridge does not take frames faster than this
+pub const MAX_RATE: u32 = 50;
+
+/// Most lights a single v1 frame can address
+pub const MAX_LIGHTS_V1: usize = 10;
+
+/// Most channels a single v2 frame can address
+pub const MAX_CHANNELS_V2: usize = 20;
+
+/// The only cipher suite the bridge accepts
+const CIPHER: &str = "PSK-AES128-GCM-SHA256";
+
+/// How the three colour values in a frame should be interpreted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorSpace {
+    /// Red, green, blue
+    Rgb = 0x00,
+    /// x, y and brightness
+    Xy = 0x01,
+}
+
+/// Encodes a HueStream v1 frame addressing lights by their v1 IDs
+pub fn encode_v1(sequence: u8, colorspace: ColorSpace, lights: &[(u16, [u16; 3])]) -> Vec<u8> {
+    let mut frame = header(1, sequence, colorspace);
+    for (id, color) in lights {
+        frame.push(0x00); // device type: light
+        frame.extend_from_slice(&id.to_be_bytes());
+        for value in color {
+            frame.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+    frame
+}
+
+/// Encodes a HueStream v2 frame addressing the channels of an entertainment configuration
+/// (`area` is its v2 resource ID)
+pub fn encode_v2(
+    sequence: u8,
+    colorspace: ColorSpace,
+    area: &str,
+    channels: &[(u8, [u16; 3])],
+) -> Vec<u8> {
+    let mut frame = header(2, sequence, colorspace);
+    frame.extend_from_slice(area.as_bytes());
+    for (id, color) in channels {
+        frame.push(*id);
+        for value in color {
+            frame.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+    frame
+}
+
+fn header(version: u8, sequence: u8, colorspace: ColorSpace) -> Vec<u8> {
+    let mut header = b"HueStream".to_vec();
+    header.extend_from_slice(&[version, 0x00, sequence, 0x00, 0x00, colorspace as u8, 0x00]);
+    header
+}
+
+/// Decodes a hex string (such as the client key) into bytes
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Lets the DTLS session run over a connected UDP socket, one datagram per read or write
+#[derive(Debug)]
+struct Datagrams(UdpSocket);
+
+impl Read for Datagrams {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf)
+    }
+}
+
+impl Write for Datagrams {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An open entertainment streaming session. Frames are sent over PSK-DTLS to the bridge,
+/// which needs a streaming enabled entertainment group (see `Bridge.stream`).
+///
+/// The sends are paced so no more than `MAX_RATE` frames go out per second. When it is
+/// dropped the session is closed and, if it was opened through `Bridge.stream`, the area
+/// is taken out of streaming mode so it doesn't stay locked.
+///
+/// ```no_run
+/// # use huemanity::bridge::Bridge;
+/// # use huemanity::entertainment::ColorSpace;
+/// let bridge = Bridge::link();
+/// let mut streamer = bridge.stream(5).unwrap();
+/// // light 1 full red, light 2 full blue
+/// streamer
+///     .send_v1(ColorSpace::Rgb, &[(1, [0xffff, 0, 0]), (2, [0, 0, 0xffff])])
+///     .unwrap();
+/// ```
+#[derive(Debug)]
+pub struct Streamer {
+    stream: SslStream<Datagrams>,
+    sequence: u8,
+    last: Option<Instant>,
+    interval: Duration,
+    release: Option<Release>,
+}
+
+/// Where to tell the bridge that the area is done streaming
+#[derive(Debug)]
+struct Release {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl Streamer {
+    /// Opens a DTLS session to `addr` using the application key as the PSK
+    /// identity and the (decoded) client key as the PSK.
+    pub fn connect<A: ToSocketAddrs>(
+        addr: A,
+        identity: &str,
+        psk: &[u8],
+    ) -> Result<Self, Box<dyn Error>> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(addr)?;
+        socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        let identity = identity.as_bytes().to_vec();
+        let psk = psk.to_vec();
+        let mut builder = SslConnector::builder(SslMethod::dtls())?;
+        builder.set_cipher_list(CIPHER)?;
+        builder.set_verify(SslVerifyMode::NONE);
+        builder.set_psk_client_callback(move |_, _, identity_out, psk_out| {
+            if identity.len() >= identity_out.len() || psk.len() > psk_out.len() {
+                return Err(ErrorStack::get());
+            }
+            // the identity has to be NUL terminated
+            identity_out[..identity.len()].copy_from_slice(&identity);
+            identity_out[identity.len()] = 0;
+            psk_out[..psk.len()].copy_from_slice(&psk);
+            Ok(psk.len())
+        });
+
+        let mut ssl = builder
+            .build()
+            .configure()?
+            .verify_hostname(false)
+            .use_server_name_indication(false)
+            .into_ssl("")?;
+        ssl.set_mtu(1400)?;
+        let mut stream = SslStream::new(ssl, Datagrams(socket))?;
+        stream.connect()?;
+
+        Ok(Streamer {
+            stream,
+            sequence: 0,
+            last: None,
+            interval: Duration::from_secs(1) / MAX_RATE,
+            release: None,
+        })
+    }
+
+    /// Has the group at `url` (like `http://{ip}/api/{key}/groups/5`) taken out of
+    /// streaming mode when the streamer is dropped
+    pub(crate) fn release_with(&mut self, client: reqwest::Client, url: String) {
+        self.release = Some(Release { client, url });
+    }
+
+    /// Streams a v1 frame setting the colour of up to `MAX_LIGHTS_V1` lights
+    pub fn send_v1(
+        &mut self,
+        colorspace: ColorSpace,
+        lights: &[(u16, [u16; 3])],
+    ) -> Result<(), Box<dyn Error>> {
+        if lights.len() > MAX_LIGHTS_V1 {
+            return Err(format!("a v1 frame takes at most {} lights", MAX_LIGHTS_V1).into());
+        }
+        let frame = encode_v1(self.sequence, colorspace, lights);
+        self.send(&frame)
+    }
+
+    /// Streams a v2 frame setting the colour of up to `MAX_CHANNELS_V2` channels
+    /// of the entertainment configuration `area`
+    pub fn send_v2(
+        &mut self,
+        colorspace: ColorSpace,
+        area: &str,
+        channels: &[(u8, [u16; 3])],
+    ) -> Result<(), Box<dyn Error>> {
+        if channels.len() > MAX_CHANNELS_V2 {
+            return Err(format!("a v2 frame takes at most {} channels", MAX_CHANNELS_V2).into());
+        }
+        let frame = encode_v2(self.sequence, colorspace, area, channels);
+        self.send(&frame)
+    }
+
+    fn send(&mut self, frame: &[u8]) -> Result<(), Box<dyn Error>> {
+        if let Some(last) = self.last {
+            let elapsed = last.elapsed();
+            if elapsed < self.interval {
+                sleep(self.interval - elapsed);
+            }
+        }
+        self.stream.ssl_write(frame)?;
+        self.last = Some(Instant::now());
+        self.sequence = self.sequence.wrapping_add(1);
+        Ok(())
+    }
+}
+
+impl Drop for Streamer {
+    fn drop(&mut self) {
+        let _ = self.stream.shutdown();
+        if let Some(release) = self.release.take() {
+            let body = serde_json::json!({ "stream": { "active": false } });
+            // the error would show the address, key included
+            if release.client.put(&release.url).json(&body).send().is_err() {
+                warn!("Could not take the area out of streaming mode");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use openssl::ssl::{Ssl, SslContext};
+    use std::thread;
+
+    #[test]
+    fn encodes_v1_frames() {
+        let frame = encode_v1(
+            7,
+            ColorSpace::Rgb,
+            &[(1, [0xffff, 0, 0x1234]), (258, [1, 2, 3])],
+        );
+        let mut expected = b"HueStream".to_vec();
+        expected.extend_from_slice(&[0x01, 0x00, 0x07, 0x00, 0x00, 0x00, 0x00]);
+        expected.extend_from_slice(&[0x00, 0x00, 0x01, 0xff, 0xff, 0x00, 0x00, 0x12, 0x34]);
+        expected.extend_from_slice(&[0x00, 0x01, 0x02, 0x00, 0x01, 0x00, 0x02, 0x00, 0x03]);
+        assert_eq!(frame, expected);
+    }
+
+    #[test]
+    fn encodes_v2_frames() {
+        let area = "1a8d99cc-967b-44f2-9202-43f976c0fa6b";
+        let frame = encode_v2(255, ColorSpace::Xy, area, &[(0, [0x8000, 0x4000, 0xffff])]);
+        let mut expected = b"HueStream".to_vec();
+        expected.extend_from_slice(&[0x02, 0x00, 0xff, 0x00, 0x00, 0x01, 0x00]);
+        expected.extend_from_slice(area.as_bytes());
+        expected.extend_from_slice(&[0x00, 0x80, 0x00, 0x40, 0x00, 0xff, 0xff]);
+        assert_eq!(frame, expected);
+        assert_eq!(frame.len(), 16 + 36 + 7);
+    }
+
+    #[test]
+    fn decodes_hex() {
+        assert_eq!(decode_hex("00ff1A"), Some(vec![0x00, 0xff, 0x1a]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+    }
+
+    /// Plays the bridge: takes one PSK-DTLS session and returns the frames sent over it
+    pub(crate) fn stand_in(
+        identity: &'static [u8],
+        psk: &'static [u8],
+    ) -> (u16, thread::JoinHandle<Vec<Vec<u8>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            socket
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let (_, client) = socket.peek_from(&mut [0; 2048]).unwrap();
+            socket.connect(client).unwrap();
+
+            let mut context = SslContext::builder(SslMethod::dtls()).unwrap();
+            context.set_cipher_list(CIPHER).unwrap();
+            context.set_psk_server_callback(move |_, client_identity, psk_out| {
+                if client_identity != Some(identity) {
+                    return Err(ErrorStack::get());
+                }
+                psk_out[..psk.len()].copy_from_slice(psk);
+                Ok(psk.len())
+            });
+            let mut ssl = Ssl::new(&context.build()).unwrap();
+            ssl.set_mtu(1400).unwrap();
+            let mut stream = SslStream::new(ssl, Datagrams(socket)).unwrap();
+            stream.accept().unwrap();
+
+            let mut frames = Vec::new();
+            let mut buf = [0; 2048];
+            loop {
+                match stream.ssl_read(&mut buf) {
+                    Ok(read) => frames.push(buf[..read].to_vec()),
+                    // the streamer closed the session
+                    Err(e) if e.code() == openssl::ssl::ErrorCode::ZERO_RETURN => break,
+                    Err(e) => panic!("{}", e),
+                }
+            }
+            frames
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn streams_frames_over_psk_dtls() {
+        let (port, bridge) = stand_in(b"key", &[0x12, 0x34, 0x56, 0x78]);
+        let mut streamer =
+            Streamer::connect(("127.0.0.1", port), "key", &[0x12, 0x34, 0x56, 0x78]).unwrap();
+        streamer
+            .send_v1(ColorSpace::Rgb, &[(1, [0xffff, 0, 0])])
+            .unwrap();
+        streamer
+            .send_v1(ColorSpace::Rgb, &[(1, [0, 0, 0xffff])])
+            .unwrap();
+        assert!(streamer
+            .send_v1(ColorSpace::Rgb, &[(1, [0, 0, 0]); MAX_LIGHTS_V1 + 1])
+            .is_err());
+        drop(streamer);
+
+        let frames = bridge.join().unwrap();
+        assert_eq!(
+            frames,
+            [
+                encode_v1(0, ColorSpace::Rgb, &[(1, [0xffff, 0, 0])]),
+                encode_v1(1, ColorSpace::Rgb, &[(1, [0, 0, 0xffff])]),
+            ]
+        );
+    }
+
+    #[test]
+    fn refuses_the_wrong_key() {
+        let (port, _bridge) = stand_in(b"key", &[0x12, 0x34]);
+        assert!(Streamer::connect(("127.0.0.1", port), "key", &[0x43, 0x21]).is_err());
+    }
+}
@@ -0,0 +1,77 @@
+use std::error::Error;
+use std::fmt;
+
+/// Errors raised by the library itself, as opposed to the ones bubbled
+/// up from `reqwest` or `serde_json`.
+#[derive(Debug)]
+pub enum HueError {
+    /// The bridge could not be reached on its last known address and there is no
+    /// stored bridge ID that would allow finding it on a new one.
+    Unreachable { ip: String },
+    /// The bridge could not be reached on its last known address and discovery
+    /// did not find a bridge with the stored ID anywhere else on the network.
+    BridgeNotFound { id: String, ip: String },
+    /// The bridge could not be reached on its last known address, and it was looked
+    /// for on the network too recently to do it again.
+    Offline { ip: String, retry: u64 },
+    /// Bridges could not be searched for on the network, e.g. because it has no
+    /// multicast.
+    Discovery { reason: String },
+    /// The bridge presented a different certificate than the one pinned for it.
+    CertificateMismatch { expected: String, found: String },
+    /// The bridge accepted the request but answered with errors.
+    Api { errors: Vec<String> },
+    /// No light or group on the bridge goes by the given name.
+    UnknownName { kind: String, name: String },
+}
+
+impl HueError {
+    /// A short name for the kind of error, for output meant for programs
+    pub fn kind(&self) -> &'static str {
+        match self {
+            HueError::Unreachable { .. } => "unreachable",
+            HueError::BridgeNotFound { .. } => "bridge_not_found",
+            HueError::Offline { .. } => "unreachable",
+            HueError::Discovery { .. } => "discovery_failed",
+            HueError::CertificateMismatch { .. } => "certificate_mismatch",
+            HueError::Api { .. } => "bridge_error",
+            HueError::UnknownName { .. } => "unknown_name",
+        }
+    }
+}
+
+impl fmt::Display for HueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HueError::Unreachable { ip } => write!(
+                f,
+                "bridge at {} is unreachable and no bridge ID is stored to find it again",
+                ip
+            ),
+            HueError::BridgeNotFound { id, ip } => write!(
+                f,
+                "bridge {} is unreachable at {} and was not found on the network",
+                id, ip
+            ),
+            HueError::Offline { ip, retry } => write!(
+                f,
+                "bridge at {} is unreachable, it will be looked for on the network again in {}s",
+                ip, retry
+            ),
+            HueError::Discovery { reason } => {
+                write!(f, "could not search for bridges: {}", reason)
+            }
+            HueError::CertificateMismatch { expected, found } => write!(
+                f,
+                "bridge certificate {} does not match the pinned {}",
+                found, expected
+            ),
+            HueError::Api { errors } => write!(f, "bridge error: {}", errors.join("; ")),
+            HueError::UnknownName { kind, name } => {
+                write!(f, "there is no {} called `{}`", kind, name)
+            }
+        }
+    }
+}
+
+impl Error for HueError {}
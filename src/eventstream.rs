@@ -0,0 +1,323 @@
+use crate::clip::{Pinned, ResourceRef};
+use serde::*;
+use serde_json::value::Value;
+use serde_json::Map;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::io::BufRead;
+use std::thread::sleep;
+use std::time::Duration;
+
+/// A resource as it appears in an event. Updates only carry the fields that changed,
+/// so apart from the identifying bits everything is kept in `fields`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Change {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub rtype: String,
+    pub id_v1: Option<String>,
+    pub owner: Option<ResourceRef>,
+    #[serde(flatten)]
+    pub fields: Map<String, Value>,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let id = self.id_v1.as_ref().unwrap_or(&self.id);
+        write!(
+            f,
+            "{} {} {}",
+            self.rtype,
+            id,
+            Value::Object(self.fields.clone())
+        )
+    }
+}
+
+/// A single message from the bridge event stream
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Message {
+    Update {
+        id: String,
+        creationtime: String,
+        data: Vec<Change>,
+    },
+    Add {
+        id: String,
+        creationtime: String,
+        data: Vec<Change>,
+    },
+    Delete {
+        id: String,
+        creationtime: String,
+        data: Vec<Change>,
+    },
+    Error {
+        id: String,
+        creationtime: String,
+        data: Vec<Change>,
+    },
+}
+
+impl Message {
+    /// The changes carried by the message, whatever its kind
+    pub fn changes(&self) -> &[Change] {
+        match self {
+            Message::Update { data, .. }
+            | Message::Add { data, .. }
+            | Message::Delete { data, .. }
+            | Message::Error { data, .. } => data,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Message::Update { .. } => "update",
+            Message::Add { .. } => "add",
+            Message::Delete { .. } => "delete",
+            Message::Error { .. } => "error",
+        }
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let changes: Vec<String> = self.changes().iter().map(|c| c.to_string()).collect();
+        write!(f, "{}: {}", self.kind(), changes.join(", "))
+    }
+}
+
+/// How long the stream can stay quiet before reconnecting
+const QUIET_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Subscription to the `/eventstream/clip/v2` server-sent event feed of a bridge.
+///
+/// Iterating over it yields the messages as they arrive. If the connection drops it is
+/// re-established (with the last seen event ID, so nothing is missed) backing off
+/// exponentially up to `max_backoff` between attempts.
+///
+/// ```no_run
+/// # use huemanity::bridge::Bridge;
+/// let bridge = Bridge::link();
+/// for message in bridge.clip().unwrap().events().unwrap() {
+///     println!("{}", message);
+/// }
+/// ```
+pub struct EventStream {
+    pinned: Pinned,
+    path: String,
+    key: String,
+    reader: Option<Box<dyn BufRead + Send>>,
+    pending: VecDeque<Message>,
+    last_id: Option<String>,
+    backoff: Duration,
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Give up after this many failed connection attempts in a row, `None` retries forever
+    pub max_retries: Option<u32>,
+    failures: u32,
+}
+
+impl EventStream {
+    /// Subscribes to the event stream at `path` on the pinned bridge, authenticating with
+    /// the application key. Usually called through `ClipBridge.events`. Nothing is sent
+    /// until the first message is asked for.
+    pub fn new(pinned: Pinned, path: &str, key: &str) -> Self {
+        EventStream {
+            pinned,
+            path: path.to_owned(),
+            key: key.to_owned(),
+            reader: None,
+            pending: VecDeque::new(),
+            last_id: None,
+            backoff: Duration::from_secs(1),
+            min_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            max_retries: None,
+            failures: 0,
+        }
+    }
+
+    fn connect(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut headers = vec![
+            ("hue-application-key", self.key.as_str()),
+            ("Accept", "text/event-stream"),
+        ];
+        if let Some(id) = &self.last_id {
+            headers.push(("last-event-id", id));
+        }
+        let response = self
+            .pinned
+            .send("GET", &self.path, &headers, None, QUIET_TIMEOUT)?;
+        if !(200..300).contains(&response.status) {
+            return Err(format!("event stream answered with status {}", response.status).into());
+        }
+        self.reader = Some(response.body);
+        Ok(())
+    }
+
+    fn back_off(&mut self) {
+        sleep(self.backoff);
+        self.backoff = (self.backoff * 2).min(self.max_backoff);
+    }
+
+    /// Reads a single server-sent event off the stream and queues up the messages in it.
+    /// Returns false once the connection is gone.
+    fn read_event(&mut self) -> bool {
+        let reader = match self.reader.as_mut() {
+            Some(reader) => reader,
+            None => return false,
+        };
+
+        let mut data = String::new();
+        loop {
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(0) | Err(_) => return false,
+                Ok(_) => (),
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+
+            if line.is_empty() {
+                break;
+            } else if let Some(id) = line.strip_prefix("id:") {
+                self.last_id = Some(id.trim().to_owned());
+            } else if let Some(chunk) = line.strip_prefix("data:") {
+                // data spread over several lines is joined with line breaks
+                if !data.is_empty() {
+                    data.push('\n');
+                }
+                data.push_str(chunk.strip_prefix(' ').unwrap_or(chunk));
+            }
+            // comments (`: hi`) and other fields are ignored
+        }
+
+        // a single event holds an array of messages, anything unparseable is skipped
+        if let Ok(messages) = serde_json::from_str::<Vec<Value>>(&data) {
+            self.pending.extend(
+                messages
+                    .into_iter()
+                    .filter_map(|message| serde_json::from_value(message).ok()),
+            );
+        }
+        true
+    }
+}
+
+impl Iterator for EventStream {
+    type Item = Message;
+
+    fn next(&mut self) -> Option<Message> {
+        loop {
+            if let Some(message) = self.pending.pop_front() {
+                return Some(message);
+            }
+
+            if self.reader.is_none() {
+                if let Some(max) = self.max_retries {
+                    if self.failures >= max {
+                        return None;
+                    }
+                }
+                match self.connect() {
+                    Ok(_) => self.failures = 0,
+                    Err(_) => {
+                        self.failures += 1;
+                        self.back_off();
+                        continue;
+                    }
+                }
+            }
+
+            if self.read_event() {
+                self.backoff = self.min_backoff;
+            } else {
+                self.reader = None;
+                self.back_off();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clip::tests::stub;
+    use std::io::Cursor;
+    use std::sync::mpsc;
+
+    fn update(id: &str) -> String {
+        format!(
+            r#"[{{"type": "update", "id": "{}", "creationtime": "2024-01-01T00:00:00Z",
+                 "data": [{{"id": "light-1", "type": "light", "on": {{"on": true}}}}]}}]"#,
+            id
+        )
+    }
+
+    #[test]
+    fn parses_events() {
+        let mut stream = EventStream::new(Pinned::new("127.0.0.1:1", "00").unwrap(), "", "key");
+        let json = update("a").replace('\n', " ");
+        let (first, second) = json.split_at(20);
+        let feed = format!(
+            ": hi\n\nid: 1:0\r\ndata: {}\ndata: {}\nretry: 10\n\ndata: not json\n\n",
+            first, second
+        );
+        stream.reader = Some(Box::new(Cursor::new(feed.into_bytes())));
+
+        // the comment makes an empty event
+        assert!(stream.read_event());
+        assert!(stream.pending.is_empty());
+        assert!(stream.read_event());
+        assert_eq!(stream.last_id.as_deref(), Some("1:0"));
+        let message = stream.pending.pop_front().unwrap();
+        assert!(matches!(&message, Message::Update { id, .. } if id == "a"));
+        assert_eq!(message.changes()[0].fields["on"]["on"], true);
+        // unparseable events are skipped, the end of the feed drops the connection
+        assert!(stream.read_event());
+        assert!(stream.pending.is_empty());
+        assert!(!stream.read_event());
+    }
+
+    #[test]
+    fn reconnects_with_the_last_event_id() {
+        let (heads, received) = mpsc::channel();
+        let (address, fingerprint) = stub(move |n, head| {
+            let _ = heads.send(head.to_owned());
+            match n {
+                0 | 1 => format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\r\nid: {}:0\ndata: {}\n\n",
+                    n + 1,
+                    update(&n.to_string()).replace('\n', " ")
+                ),
+                _ => "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n".to_owned(),
+            }
+            .into_bytes()
+        });
+        let pinned = Pinned::new(&address, &fingerprint).unwrap();
+        let mut stream = EventStream::new(pinned, "eventstream/clip/v2", "key");
+        stream.backoff = Duration::from_millis(10);
+        stream.min_backoff = Duration::from_millis(10);
+        stream.max_retries = Some(1);
+
+        let ids: Vec<String> = stream
+            .map(|message| match message {
+                Message::Update { id, .. } => id,
+                message => panic!("unexpected {}", message),
+            })
+            .collect();
+        assert_eq!(ids, ["0", "1"]);
+
+        let heads: Vec<String> = received.try_iter().collect();
+        assert_eq!(heads.len(), 3);
+        for head in &heads {
+            assert!(head.starts_with("GET /eventstream/clip/v2 HTTP/1.1\r\n"));
+            assert!(head.contains("hue-application-key: key\r\n"));
+        }
+        assert!(!heads[0].contains("last-event-id"));
+        assert!(heads[1].contains("last-event-id: 1:0\r\n"));
+        assert!(heads[2].contains("last-event-id: 2:0\r\n"));
+    }
+}
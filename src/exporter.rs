@@ -0,0 +1,431 @@
+use crate::bridge::Bridge;
+use crate::server::{read_request, respond};
+use serde_json::value::Value;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Write;
+use std::net::TcpListener;
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Content type of the Prometheus text format
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// One metric with all its samples, each sample being its labels and value
+struct Family {
+    name: &'static str,
+    help: &'static str,
+    kind: &'static str,
+    samples: Vec<(String, f64)>,
+}
+
+impl Family {
+    fn gauge(name: &'static str, help: &'static str) -> Self {
+        Family {
+            name,
+            help,
+            kind: "gauge",
+            samples: Vec::new(),
+        }
+    }
+
+    fn counter(name: &'static str, help: &'static str) -> Self {
+        Family {
+            kind: "counter",
+            ..Family::gauge(name, help)
+        }
+    }
+
+    fn render(&self, out: &mut String) {
+        if self.samples.is_empty() {
+            return;
+        }
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} {}", self.name, self.kind);
+        for (labels, value) in &self.samples {
+            let _ = writeln!(out, "{}{} {}", self.name, labels, value);
+        }
+    }
+}
+
+/// How the fetches of one endpoint of the bridge went. A fetch is counted once,
+/// however many times it was retried.
+#[derive(Debug, Clone, Copy, Default)]
+struct Requests {
+    count: u64,
+    errors: u64,
+    seconds: f64,
+}
+
+#[derive(Default)]
+struct Metrics {
+    /// The light and sensor gauges of the last refresh, empty if it failed
+    devices: String,
+    requests: BTreeMap<&'static str, Requests>,
+    up: bool,
+    refreshed: Option<SystemTime>,
+}
+
+/// Serves the state of the lights and sensors in the Prometheus text format on
+/// `/metrics`, along with how long the bridge takes to answer and how often it
+/// fails to. The bridge is polled every `interval`, not on every scrape.
+///
+/// ```no_run
+/// # use huemanity::bridge::Bridge;
+/// # use huemanity::exporter::Exporter;
+/// let bridge = Bridge::link();
+/// Exporter::new(&bridge).serve("0.0.0.0:9184").unwrap();
+/// ```
+pub struct Exporter<'a> {
+    bridge: &'a Bridge,
+    pub interval: Duration,
+    metrics: Mutex<Metrics>,
+}
+
+impl<'a> Exporter<'a> {
+    pub fn new(bridge: &'a Bridge) -> Self {
+        Exporter {
+            bridge,
+            interval: Duration::from_secs(15),
+            metrics: Mutex::new(Metrics::default()),
+        }
+    }
+
+    /// Fetches an endpoint, keeping count of the time it took and whether it failed.
+    /// `Bridge::fetch` tries again (and looks for a bridge that moved) before giving
+    /// up, so the time includes the retries and an error is a fetch that failed
+    /// every try, not a single request.
+    fn fetch(&self, endpoint: &'static str) -> Result<BTreeMap<u8, Value>, Box<dyn Error>> {
+        let started = Instant::now();
+        let result = self.bridge.fetch(endpoint);
+        let mut metrics = self.metrics_lock();
+        let requests = metrics.requests.entry(endpoint).or_default();
+        requests.count += 1;
+        requests.seconds += started.elapsed().as_secs_f64();
+        if result.is_err() {
+            requests.errors += 1;
+        }
+        result
+    }
+
+    /// Polls the bridge for the lights, rooms and sensors once. The light and sensor
+    /// metrics are dropped if that fails, rather than showing stale values.
+    pub fn refresh(&self) -> Result<(), Box<dyn Error>> {
+        let result = self.fetch("lights").and_then(|lights| {
+            Ok(render_devices(
+                &lights,
+                &self.fetch("groups")?,
+                &self.fetch("sensors")?,
+            ))
+        });
+        let mut metrics = self.metrics_lock();
+        metrics.up = result.is_ok();
+        metrics.refreshed = Some(SystemTime::now());
+        metrics.devices = result.as_ref().cloned().unwrap_or_default();
+        result.map(|_| ())
+    }
+
+    /// Everything there is to report, in the Prometheus text format
+    pub fn render(&self) -> String {
+        let metrics = self.metrics_lock();
+        let mut out = metrics.devices.clone();
+
+        let mut up = Family::gauge(
+            "huemanity_bridge_up",
+            "Whether the last poll of the bridge worked",
+        );
+        up.samples.push((String::new(), flag(metrics.up)));
+        let mut refreshed = Family::gauge(
+            "huemanity_bridge_last_poll_timestamp_seconds",
+            "When the bridge was last polled",
+        );
+        if let Some(time) = metrics.refreshed {
+            let seconds = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            refreshed
+                .samples
+                .push((String::new(), seconds.as_secs_f64().floor()));
+        }
+        let mut count = Family::counter(
+            "huemanity_bridge_requests_total",
+            "Fetches from the bridge API, retries included in the one fetch",
+        );
+        let mut errors = Family::counter(
+            "huemanity_bridge_request_errors_total",
+            "Fetches from the bridge API that failed after every retry",
+        );
+        let mut seconds = Family::counter(
+            "huemanity_bridge_request_duration_seconds_total",
+            "Time spent waiting for the bridge API, retries and pauses between them included",
+        );
+        for (endpoint, requests) in &metrics.requests {
+            let labels = labels(&[("endpoint", endpoint)]);
+            count.samples.push((labels.clone(), requests.count as f64));
+            errors
+                .samples
+                .push((labels.clone(), requests.errors as f64));
+            seconds.samples.push((labels, requests.seconds));
+        }
+        for family in [up, refreshed, count, errors, seconds].iter() {
+            family.render(&mut out);
+        }
+        out
+    }
+
+    /// Polls the bridge in the background and answers scrapes on `address` (like
+    /// `0.0.0.0:9184`), forever
+    pub fn serve(&self, address: &str) -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind(address)?;
+        thread::scope(|scope| {
+            scope.spawn(|| loop {
+                // failures show up in the metrics
+                let _ = self.refresh();
+                thread::sleep(self.interval);
+            });
+            for stream in listener.incoming() {
+                let mut stream = stream?;
+                stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+                let _ = match read_request(&mut stream) {
+                    Ok(request) if request.path == "/metrics" => {
+                        respond(&mut stream, 200, CONTENT_TYPE, &self.render())
+                    }
+                    Ok(_) => respond(&mut stream, 404, CONTENT_TYPE, "try /metrics\n"),
+                    Err(e) => respond(&mut stream, e.status, CONTENT_TYPE, &e.message),
+                };
+            }
+            Ok(())
+        })
+    }
+
+    fn metrics_lock(&self) -> MutexGuard<'_, Metrics> {
+        // a panic while holding the lock leaves the metrics as usable as they were
+        self.metrics.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The gauges of every light and sensor, labelled with names, rooms and model IDs
+fn render_devices(
+    lights: &BTreeMap<u8, Value>,
+    groups: &BTreeMap<u8, Value>,
+    sensors: &BTreeMap<u8, Value>,
+) -> String {
+    let mut on = Family::gauge("huemanity_light_on", "Whether the light is on");
+    let mut brightness = Family::gauge(
+        "huemanity_light_brightness_ratio",
+        "Brightness of the light, from 0 to 1",
+    );
+    let mut reachable = Family::gauge(
+        "huemanity_light_reachable",
+        "Whether the bridge can reach the light",
+    );
+    for (id, light) in lights {
+        let room = groups
+            .values()
+            .find(|group| {
+                group["type"] == "Room"
+                    && group["lights"]
+                        .as_array()
+                        .is_some_and(|lights| lights.iter().any(|l| l == &id.to_string()))
+            })
+            .and_then(|group| group["name"].as_str())
+            .unwrap_or_default();
+        let labels = labels(&[
+            ("id", &id.to_string()),
+            ("name", light["name"].as_str().unwrap_or_default()),
+            ("room", room),
+            ("model", light["modelid"].as_str().unwrap_or_default()),
+        ]);
+        let state = &light["state"];
+        if let Some(value) = state["on"].as_bool() {
+            on.samples.push((labels.clone(), flag(value)));
+        }
+        if let Some(value) = state["bri"].as_f64() {
+            brightness.samples.push((labels.clone(), value / 254.0));
+        }
+        if let Some(value) = state["reachable"].as_bool() {
+            reachable.samples.push((labels, flag(value)));
+        }
+    }
+
+    let mut temperature = Family::gauge(
+        "huemanity_sensor_temperature_celsius",
+        "Temperature measured by the sensor",
+    );
+    let mut light_level = Family::gauge(
+        "huemanity_sensor_light_level_lux",
+        "Light level measured by the sensor",
+    );
+    let mut presence = Family::gauge(
+        "huemanity_sensor_presence",
+        "Whether the sensor sees someone",
+    );
+    let mut battery = Family::gauge(
+        "huemanity_sensor_battery_ratio",
+        "Battery level of the sensor, from 0 to 1",
+    );
+    for (id, sensor) in sensors {
+        let labels = labels(&[
+            ("id", &id.to_string()),
+            ("name", sensor["name"].as_str().unwrap_or_default()),
+            ("model", sensor["modelid"].as_str().unwrap_or_default()),
+        ]);
+        let state = &sensor["state"];
+        if let Some(value) = state["temperature"].as_f64() {
+            temperature.samples.push((labels.clone(), value / 100.0));
+        }
+        // the bridge reports 10000 log10(lux) + 1
+        if let Some(value) = state["lightlevel"].as_f64() {
+            let lux = 10f64.powf((value - 1.0) / 10000.0);
+            light_level.samples.push((labels.clone(), lux));
+        }
+        if let Some(value) = state["presence"].as_bool() {
+            presence.samples.push((labels.clone(), flag(value)));
+        }
+        if let Some(value) = sensor["config"]["battery"].as_f64() {
+            battery.samples.push((labels, value / 100.0));
+        }
+    }
+
+    let mut out = String::new();
+    for family in [
+        on,
+        brightness,
+        reachable,
+        temperature,
+        light_level,
+        presence,
+        battery,
+    ]
+    .iter()
+    {
+        family.render(&mut out);
+    }
+    out
+}
+
+fn flag(value: bool) -> f64 {
+    if value {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+/// Formats labels as `{name="value",...}`, escaping the values
+fn labels(pairs: &[(&str, &str)]) -> String {
+    let pairs: Vec<String> = pairs
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::Topology;
+    use serde_json::json;
+
+    #[test]
+    fn escapes_labels() {
+        assert_eq!(labels(&[]), "{}");
+        assert_eq!(
+            labels(&[("id", "1"), ("name", "Desk \"big\" lamp")]),
+            r#"{id="1",name="Desk \"big\" lamp"}"#
+        );
+        assert_eq!(labels(&[("name", "a\\b\nc")]), r#"{name="a\\b\nc"}"#);
+    }
+
+    #[test]
+    fn renders_lights_and_sensors() {
+        let lights = [
+            (
+                1,
+                json!({
+                    "name": "Desk",
+                    "modelid": "LCT015",
+                    "state": { "on": true, "bri": 127, "reachable": true },
+                }),
+            ),
+            (2, json!({ "name": "Hall", "state": { "on": false } })),
+        ]
+        .into();
+        let groups = [
+            // zones hold lights too, but only rooms are what the light is in
+            (
+                1,
+                json!({ "name": "Desk zone", "type": "Zone", "lights": ["1"] }),
+            ),
+            (
+                2,
+                json!({ "name": "Study", "type": "Room", "lights": ["3", "1"] }),
+            ),
+        ]
+        .into();
+        let sensors = [
+            (
+                4,
+                json!({
+                    "name": "Porch",
+                    "modelid": "SML001",
+                    "state": { "temperature": 1850, "lightlevel": 20001, "presence": true },
+                    "config": { "battery": 80 },
+                }),
+            ),
+            (
+                5,
+                json!({ "name": "Daylight", "state": { "daylight": true } }),
+            ),
+        ]
+        .into();
+        let out = render_devices(&lights, &groups, &sensors);
+        let desk = r#"{id="1",name="Desk",room="Study",model="LCT015"}"#;
+        let hall = r#"{id="2",name="Hall",room="",model=""}"#;
+        let porch = r#"{id="4",name="Porch",model="SML001"}"#;
+        for line in [
+            "# TYPE huemanity_light_on gauge".to_owned(),
+            format!("huemanity_light_on{} 1", desk),
+            format!("huemanity_light_on{} 0", hall),
+            format!("huemanity_light_brightness_ratio{} 0.5", desk),
+            format!("huemanity_light_reachable{} 1", desk),
+            format!("huemanity_sensor_temperature_celsius{} 18.5", porch),
+            // 10000 log10(lux) + 1
+            format!("huemanity_sensor_light_level_lux{} 100", porch),
+            format!("huemanity_sensor_presence{} 1", porch),
+            format!("huemanity_sensor_battery_ratio{} 0.8", porch),
+        ] {
+            assert!(out.lines().any(|l| l == line), "no `{}` in\n{}", line, out);
+        }
+        // what a device doesn't report is left out, not made up
+        assert!(!out.contains(&format!("huemanity_light_brightness_ratio{}", hall)));
+        assert!(!out.contains("Daylight"));
+    }
+
+    #[test]
+    fn counts_fetches() {
+        let bridge = Bridge::rehearsal(Topology {
+            bridge: "127.0.0.1".to_owned(),
+            lights: [(1, json!({ "name": "Desk", "state": { "on": true } }))].into(),
+            ..Topology::default()
+        });
+        let exporter = Exporter::new(&bridge);
+        exporter.refresh().unwrap();
+        exporter.refresh().unwrap();
+        let out = exporter.render();
+        for line in [
+            "huemanity_bridge_up 1",
+            r#"huemanity_light_on{id="1",name="Desk",room="",model=""} 1"#,
+            r#"huemanity_bridge_requests_total{endpoint="lights"} 2"#,
+            r#"huemanity_bridge_requests_total{endpoint="sensors"} 2"#,
+            r#"huemanity_bridge_request_errors_total{endpoint="groups"} 0"#,
+        ] {
+            assert!(out.lines().any(|l| l == line), "no `{}` in\n{}", line, out);
+        }
+    }
+}
@@ -2,6 +2,24 @@ extern crate dirs;
 extern crate serde;
 extern crate serde_json;
 
+pub mod audio;
 pub mod bridge;
+pub mod cache;
+pub mod clip;
+pub mod color;
+pub mod entertainment;
+pub mod error;
+pub mod eventstream;
+pub mod exporter;
 #[macro_use]
 pub mod lightstructs;
+pub mod adaptive;
+pub mod effects;
+pub mod midi;
+pub mod mqtt;
+pub mod output;
+pub mod scheduler;
+pub mod sequence;
+pub mod server;
+pub mod tui;
+pub mod watcher;
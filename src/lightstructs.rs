@@ -1,5 +1,6 @@
 // serde deserialisation
 use serde::*;
+use std::collections::BTreeMap;
 
 /// This struct is just a mirror of the default reqwest methods
 #[derive(PartialEq)]
@@ -7,10 +8,25 @@ pub enum RequestType {
     Get,
     Post,
     Put,
+    Delete,
 }
 
-/// This object contains the state part  of each light
+impl RequestType {
+    /// The name of the HTTP method, like `GET`
+    pub fn method(&self) -> &'static str {
+        match self {
+            RequestType::Get => "GET",
+            RequestType::Post => "POST",
+            RequestType::Put => "PUT",
+            RequestType::Delete => "DELETE",
+        }
+    }
+}
+
+/// This object contains the state part  of each light. Lights only report what
+/// they support, the rest is filled in from `Default`.
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
 pub struct LightState {
     pub on: bool,
     pub bri: u8,
@@ -44,26 +60,30 @@ impl Default for LightState {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 struct LightSwUpdate {
     pub state: String,
     pub lastinstall: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 struct LightCapabilities {
     pub certified: bool,
     pub control: LightCapabilityControl,
     pub streaming: LightStreamingCapabilities,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 struct LightCT {
     pub min: u32,
     pub max: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 struct LightCapabilityControl {
     pub mindimlevel: u64,
     pub maxlumen: u64,
@@ -72,13 +92,15 @@ struct LightCapabilityControl {
     pub ct: LightCT,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 struct LightStreamingCapabilities {
     pub renderer: bool,
     pub proxy: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 struct LightConfig {
     pub archetype: String,
     pub function: String,
@@ -86,28 +108,41 @@ struct LightConfig {
     pub startup: LightConfigStartup,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 struct LightConfigStartup {
     pub mode: String,
     pub configured: bool,
 }
 
 // TODO: Decided which bits to expose
-/// Light object representing the complete state of a light
+/// Light object representing the complete state of a light. Only the name and
+/// type are always there, what older or simpler lights leave out is defaulted.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Light {
+    #[serde(default)]
     pub state: LightState,
+    #[serde(default)]
     swupdate: LightSwUpdate,
     pub r#type: String,
     pub name: String,
+    #[serde(default)]
     pub modelid: String,
+    #[serde(default)]
     pub manufacturername: String,
+    #[serde(default)]
     pub productname: String,
+    #[serde(default)]
     capabilities: LightCapabilities,
+    #[serde(default)]
     config: LightConfig,
+    #[serde(default)]
     pub uniqueid: String,
+    #[serde(default)]
     pub swversion: String,
+    #[serde(default)]
     pub swconfigid: String,
+    #[serde(default)]
     pub productid: String,
 }
 
@@ -125,14 +160,66 @@ Color: {:?}
     }
 }
 
+/// Positions of the lights in an entertainment area. `x` is left to right,
+/// `y` front to back and `z` bottom to top, each between -1 and 1.
+pub type Locations = BTreeMap<u8, [f32; 3]>;
+
+/// What an entertainment area is set up around
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum EntertainmentClass {
+    TV,
+    Free,
+}
+
+impl std::str::FromStr for EntertainmentClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "tv" => Ok(EntertainmentClass::TV),
+            "free" => Ok(EntertainmentClass::Free),
+            _ => Err(format!(
+                "unknown entertainment class `{}`, use TV or Free",
+                s
+            )),
+        }
+    }
+}
+
+/// Streaming state of an entertainment group
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GroupStream {
+    pub proxymode: String,
+    pub proxynode: String,
+    pub active: bool,
+    pub owner: Option<String>,
+}
+
+/// Group object, as returned by the `groups` endpoint. Only entertainment
+/// groups have `locations` and `stream`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Group {
+    pub name: String,
+    pub lights: Vec<String>,
+    pub r#type: String,
+    pub class: Option<String>,
+    #[serde(default)]
+    pub locations: BTreeMap<String, [f32; 3]>,
+    pub stream: Option<GroupStream>,
+}
+
 /// Helper object with some tweaks to serialisation. In order to
 /// use this object you have to do one of the following:
 /// ```
-/// let state_1: SendableState = serde_json::from_str(r#"{"on":true}"#);
-/// let state_2: SendableState = SendableState {on:true, ..SendableState::default()};
-/// let state_3: SendableState = state!(on: true, xy: [1.0, 0.123])
+/// # #[macro_use] extern crate huemanity;
+/// # use huemanity::lightstructs::*;
+/// # fn main() {
+/// let state_1: SendableState = serde_json::from_str(r#"{"on":true}"#).unwrap();
+/// let state_2: SendableState = SendableState {on: Some(true), ..SendableState::default()};
+/// let state_3: SendableState = state!(on: true, xy: [1.0, 0.123]);
+/// # }
 /// ```
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SendableState {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub on: Option<bool>,
@@ -148,6 +235,17 @@ pub struct SendableState {
     pub xy: Option<[f32; 2]>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub alert: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ct: Option<u16>,
+    /// In multiples of 100ms
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transitiontime: Option<u16>,
+    /// Change in brightness from whatever it is now, -254 to 254
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bri_inc: Option<i16>,
+    /// Change in mired from whatever it is now, positive being warmer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ct_inc: Option<i32>,
 }
 
 impl Default for SendableState {
@@ -161,14 +259,22 @@ impl Default for SendableState {
             effect: None,
             xy: None,
             alert: None,
+            ct: None,
+            transitiontime: None,
+            bri_inc: None,
+            ct_inc: None,
         }
     }
 }
 
 /// Super useful macro to create `SendibleState`
 /// ```
+/// # #[macro_use] extern crate huemanity;
+/// # use huemanity::lightstructs::*;
+/// # fn main() {
 /// // Usage example
-/// let sendable_state: SendableState = state!(on: true, xy: [1.0, 0.0])
+/// let sendable_state: SendableState = state!(on: true, xy: [1.0, 0.0]);
+/// # }
 /// ```
 #[macro_export]
 macro_rules! state {
@@ -179,3 +285,136 @@ macro_rules! state {
         }
     };
 }
+
+/// The parts of a light's state that can be put back later. Only the colour
+/// attributes that belong to the light's `colormode` are kept.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct SavedState {
+    #[serde(default)]
+    pub on: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bri: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub colormode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub xy: Option<[f32; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ct: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hue: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sat: Option<u8>,
+}
+
+impl SavedState {
+    /// Drops the colour attributes that don't belong to the colormode. Lights that
+    /// can't do colour have no colormode and keep none of them.
+    pub fn for_colormode(self) -> Self {
+        let mode = self.colormode.as_deref();
+        SavedState {
+            xy: self.xy.filter(|_| mode == Some("xy")),
+            ct: self.ct.filter(|_| mode == Some("ct")),
+            hue: self.hue.filter(|_| mode == Some("hs")),
+            sat: self.sat.filter(|_| mode == Some("hs")),
+            ..self
+        }
+    }
+
+    /// The state to send to put the light back. Lights that were off are only
+    /// turned off, as the bridge won't change anything else on them.
+    pub fn sendable(&self) -> SendableState {
+        if !self.on {
+            return state!(on: false);
+        }
+        let saved = self.clone().for_colormode();
+        SendableState {
+            on: Some(true),
+            bri: saved.bri,
+            xy: saved.xy,
+            ct: saved.ct,
+            hue: saved.hue,
+            sat: saved.sat,
+            ..SendableState::default()
+        }
+    }
+}
+
+/// Restorable state of every light, by light ID
+pub type Snapshot = BTreeMap<u8, SavedState>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn saved(colormode: Option<&str>) -> SavedState {
+        SavedState {
+            on: true,
+            bri: Some(200),
+            colormode: colormode.map(str::to_owned),
+            xy: Some([0.4, 0.4]),
+            ct: Some(366),
+            hue: Some(8000),
+            sat: Some(140),
+        }
+    }
+
+    #[test]
+    fn keeps_the_colours_of_the_colormode() {
+        let xy = saved(Some("xy")).for_colormode();
+        assert_eq!(
+            (xy.xy, xy.ct, xy.hue, xy.sat),
+            (Some([0.4, 0.4]), None, None, None)
+        );
+        let ct = saved(Some("ct")).for_colormode();
+        assert_eq!(
+            (ct.xy, ct.ct, ct.hue, ct.sat),
+            (None, Some(366), None, None)
+        );
+        let hs = saved(Some("hs")).for_colormode();
+        assert_eq!(
+            (hs.xy, hs.ct, hs.hue, hs.sat),
+            (None, None, Some(8000), Some(140))
+        );
+        let white = saved(None).for_colormode();
+        assert_eq!(
+            (white.xy, white.ct, white.hue, white.sat),
+            (None, None, None, None)
+        );
+        assert_eq!(white.bri, Some(200));
+        assert!(white.on);
+    }
+
+    #[test]
+    fn puts_lights_back() {
+        let state = saved(Some("ct")).sendable();
+        assert_eq!(state.on, Some(true));
+        assert_eq!(state.bri, Some(200));
+        assert_eq!(state.ct, Some(366));
+        assert_eq!((state.xy, state.hue, state.sat), (None, None, None));
+
+        let state = saved(Some("hs")).sendable();
+        assert_eq!(
+            (state.hue, state.sat, state.ct),
+            (Some(8000), Some(140), None)
+        );
+
+        let state = saved(None).sendable();
+        assert_eq!((state.on, state.bri), (Some(true), Some(200)));
+        assert_eq!(
+            (state.xy, state.ct, state.hue, state.sat),
+            (None, None, None, None)
+        );
+    }
+
+    #[test]
+    fn only_turns_off_lights_that_were_off() {
+        let off = SavedState {
+            on: false,
+            ..saved(Some("xy"))
+        };
+        let state = off.sendable();
+        assert_eq!(state.on, Some(false));
+        assert_eq!((state.bri, state.xy, state.ct), (None, None, None));
+        assert_eq!(serde_json::to_string(&state).unwrap(), r#"{"on":false}"#);
+    }
+}
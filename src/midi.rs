@@ -0,0 +1,441 @@
+use crate::bridge::Bridge;
+use crate::color::Rgb;
+use crate::entertainment::{ColorSpace, Streamer, MAX_LIGHTS_V1};
+use crate::lightstructs::*;
+use serde::*;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+
+/// The MIDI messages that can be mapped to lights
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MidiMessage {
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    NoteOff {
+        channel: u8,
+        note: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+}
+
+impl MidiMessage {
+    /// Parses a raw MIDI message, anything that isn't a note or CC is ignored
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let (status, channel) = (bytes.first()? & 0xf0, bytes.first()? & 0x0f);
+        match (status, bytes.get(1), bytes.get(2)) {
+            // a note on with no velocity is how a lot of devices send note off
+            (0x90, Some(&note), Some(&0)) | (0x80, Some(&note), _) => {
+                Some(MidiMessage::NoteOff { channel, note })
+            }
+            (0x90, Some(&note), Some(&velocity)) => Some(MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            }),
+            (0xb0, Some(&controller), Some(&value)) => Some(MidiMessage::ControlChange {
+                channel,
+                controller,
+                value,
+            }),
+            _ => None,
+        }
+    }
+
+    fn channel(self) -> u8 {
+        match self {
+            MidiMessage::NoteOn { channel, .. }
+            | MidiMessage::NoteOff { channel, .. }
+            | MidiMessage::ControlChange { channel, .. } => channel,
+        }
+    }
+}
+
+/// What a note does to the lights
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NoteRule {
+    pub note: u8,
+    #[serde(default)]
+    pub lights: Vec<u8>,
+    #[serde(default)]
+    pub groups: Vec<u8>,
+    /// Colour name, `#hex` or `rgb(r, g, b)`
+    pub color: Option<String>,
+    /// Scale the brightness with how hard the note was hit
+    #[serde(default = "yes")]
+    pub velocity: bool,
+    /// Any other state to send along
+    #[serde(default)]
+    pub state: SendableState,
+    /// Turn the lights off again when the note is released
+    #[serde(default)]
+    pub release: bool,
+}
+
+/// Light attributes a controller can drive
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Attribute {
+    Bri,
+    Hue,
+    Sat,
+    Ct,
+}
+
+/// What a controller (CC) does to the lights
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ControlRule {
+    pub cc: u8,
+    #[serde(default)]
+    pub lights: Vec<u8>,
+    #[serde(default)]
+    pub groups: Vec<u8>,
+    pub attribute: Attribute,
+}
+
+/// Mapping from MIDI messages to light states, usually loaded from a JSON file:
+/// ```json
+/// {
+///   "channel": 9,
+///   "notes": [
+///     { "note": 38, "lights": [1, 2], "color": "red", "state": { "transitiontime": 0 } },
+///     { "note": 36, "groups": [1], "color": "#0000ff", "release": true }
+///   ],
+///   "controls": [{ "cc": 7, "groups": [1], "attribute": "bri" }]
+/// }
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Mapping {
+    /// Only listen to this channel (0 to 15), all of them if not set
+    pub channel: Option<u8>,
+    #[serde(default)]
+    pub notes: Vec<NoteRule>,
+    #[serde(default)]
+    pub controls: Vec<ControlRule>,
+}
+
+fn yes() -> bool {
+    true
+}
+
+impl Mapping {
+    /// Loads the mapping from a JSON file
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let mapping: Mapping = serde_json::from_str(&fs::read_to_string(path)?)?;
+        for rule in &mapping.notes {
+            if let Some(color) = &rule.color {
+                color.parse::<Rgb>()?;
+            }
+        }
+        Ok(mapping)
+    }
+}
+
+/// Where the mapped states end up
+pub enum Output<'a> {
+    /// Regular REST requests to the bridge, works with lights and groups
+    Rest(&'a Bridge),
+    /// An entertainment stream, much faster but only addresses the lights of the
+    /// streaming entertainment area (groups in the mapping are ignored)
+    Stream(Streamer),
+}
+
+/// Turns incoming MIDI messages into light states according to a `Mapping`
+pub struct Mapper<'a> {
+    mapping: Mapping,
+    output: Output<'a>,
+    /// Current colour of every streamed light
+    frame: BTreeMap<u8, [u16; 3]>,
+}
+
+impl<'a> Mapper<'a> {
+    pub fn new(mapping: Mapping, output: Output<'a>) -> Self {
+        Mapper {
+            mapping,
+            output,
+            frame: BTreeMap::new(),
+        }
+    }
+
+    /// Handles a raw MIDI message
+    pub fn handle(&mut self, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+        let message = match MidiMessage::parse(bytes) {
+            Some(message) => message,
+            None => return Ok(()),
+        };
+        if let Some(channel) = self.mapping.channel {
+            if message.channel() != channel {
+                return Ok(());
+            }
+        }
+
+        match message {
+            MidiMessage::NoteOn { note, velocity, .. } => {
+                let rules: Vec<NoteRule> = self.rules(note).cloned().collect();
+                for rule in rules {
+                    let brightness = if rule.velocity {
+                        f32::from(velocity) / 127.0
+                    } else {
+                        1.0
+                    };
+                    self.note_on(&rule, brightness)?;
+                }
+            }
+            MidiMessage::NoteOff { note, .. } => {
+                let rules: Vec<NoteRule> =
+                    self.rules(note).filter(|r| r.release).cloned().collect();
+                for rule in rules {
+                    self.apply(&rule.lights, &rule.groups, &state!(on: false), [0, 0, 0])?;
+                }
+            }
+            MidiMessage::ControlChange {
+                controller, value, ..
+            } => {
+                let rules: Vec<ControlRule> = self
+                    .mapping
+                    .controls
+                    .iter()
+                    .filter(|r| r.cc == controller)
+                    .cloned()
+                    .collect();
+                for rule in rules {
+                    self.control(&rule, value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Resends the current frame when streaming. The bridge drops the stream if it
+    /// doesn't hear from it for a while, so this should be called when things are quiet.
+    pub fn tick(&mut self) -> Result<(), Box<dyn Error>> {
+        self.flush()
+    }
+
+    fn rules(&self, note: u8) -> impl Iterator<Item = &NoteRule> {
+        self.mapping.notes.iter().filter(move |r| r.note == note)
+    }
+
+    fn note_on(&mut self, rule: &NoteRule, brightness: f32) -> Result<(), Box<dyn Error>> {
+        let rgb = match &rule.color {
+            Some(color) => color.parse::<Rgb>()?,
+            None => Rgb::new(255, 255, 255),
+        };
+        let mut state = rule.state.clone();
+        state.on = Some(true);
+        state.bri = Some((brightness * 253.0) as u8 + 1);
+        if rule.color.is_some() {
+            state.xy = Some(rgb.xy());
+        }
+        self.apply(&rule.lights, &rule.groups, &state, rgb.scaled(brightness))
+    }
+
+    fn control(&mut self, rule: &ControlRule, value: u8) -> Result<(), Box<dyn Error>> {
+        let scale = f32::from(value) / 127.0;
+        let state = match rule.attribute {
+            Attribute::Bri => state!(bri: (scale * 253.0) as u8 + 1),
+            Attribute::Hue => state!(hue: (scale * 65535.0) as u32),
+            Attribute::Sat => state!(sat: (scale * 254.0) as u8),
+            Attribute::Ct => state!(ct: 153 + (scale * 347.0) as u16),
+        };
+        let rgb = match rule.attribute {
+            Attribute::Hue => Rgb::from_hue(scale * 360.0).scaled(1.0),
+            _ => Rgb::new(255, 255, 255).scaled(scale),
+        };
+        self.apply(&rule.lights, &rule.groups, &state, rgb)
+    }
+
+    fn apply(
+        &mut self,
+        lights: &[u8],
+        groups: &[u8],
+        state: &SendableState,
+        color: [u16; 3],
+    ) -> Result<(), Box<dyn Error>> {
+        match &self.output {
+            Output::Rest(bridge) => {
+                for light in lights {
+                    bridge.state(*light, state)?;
+                }
+                for group in groups {
+                    bridge.group_state(*group, state)?;
+                }
+                Ok(())
+            }
+            Output::Stream(_) => {
+                for light in lights {
+                    self.frame.insert(*light, color);
+                }
+                self.flush()
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Output::Stream(streamer) = &mut self.output {
+            let lights: Vec<(u16, [u16; 3])> = self
+                .frame
+                .iter()
+                .map(|(id, color)| (u16::from(*id), *color))
+                .collect();
+            for chunk in lights.chunks(MAX_LIGHTS_V1) {
+                streamer.send_v1(ColorSpace::Rgb, chunk)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Names of the MIDI input ports available
+#[cfg(feature = "midi")]
+pub fn ports() -> Result<Vec<String>, Box<dyn Error>> {
+    let input = midir::MidiInput::new("huemanity")?;
+    Ok(input
+        .ports()
+        .iter()
+        .filter_map(|port| input.port_name(port).ok())
+        .collect())
+}
+
+/// Listens on a MIDI input port (the first one whose name contains `port`, or the
+/// first one there is) and feeds everything that comes in to the mapper. Only returns
+/// if something goes wrong.
+#[cfg(feature = "midi")]
+pub fn listen(port: Option<&str>, mut mapper: Mapper) -> Result<(), Box<dyn Error>> {
+    use std::sync::mpsc::{channel, RecvTimeoutError};
+    use std::time::Duration;
+
+    let input = midir::MidiInput::new("huemanity")?;
+    let ports = input.ports();
+    let selected = ports
+        .iter()
+        .find(|p| match (port, input.port_name(p)) {
+            (Some(wanted), Ok(name)) => name.contains(wanted),
+            (None, _) => true,
+            _ => false,
+        })
+        .ok_or("no matching MIDI input port found")?;
+
+    // the callback runs on its own thread, so the messages get passed over here
+    let (sender, receiver) = channel();
+    let _connection = input
+        .connect(
+            selected,
+            "huemanity",
+            move |_, bytes, _| {
+                let _ = sender.send(bytes.to_vec());
+            },
+            (),
+        )
+        .map_err(|e| e.to_string())?;
+
+    loop {
+        match receiver.recv_timeout(Duration::from_secs(1)) {
+            Ok(bytes) => mapper.handle(&bytes)?,
+            Err(RecvTimeoutError::Timeout) => mapper.tick()?,
+            Err(RecvTimeoutError::Disconnected) => return Err("MIDI port closed".into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entertainment::{encode_v1, tests::stand_in};
+
+    /// A drum pad and a fader on channel 10, with the clock running in between
+    const RECORDING: &[&[u8]] = &[
+        &[0xf8],
+        &[0x99, 38, 127],
+        &[0x90, 38, 100],
+        &[0xf8],
+        &[0xb9, 7, 0],
+        &[0x89, 38, 64],
+        &[0xb9, 7, 127],
+        &[0x99, 38, 0],
+    ];
+
+    #[test]
+    fn parses_the_recording() {
+        let messages: Vec<MidiMessage> = RECORDING
+            .iter()
+            .filter_map(|bytes| MidiMessage::parse(bytes))
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                MidiMessage::NoteOn {
+                    channel: 9,
+                    note: 38,
+                    velocity: 127
+                },
+                MidiMessage::NoteOn {
+                    channel: 0,
+                    note: 38,
+                    velocity: 100
+                },
+                MidiMessage::ControlChange {
+                    channel: 9,
+                    controller: 7,
+                    value: 0
+                },
+                MidiMessage::NoteOff {
+                    channel: 9,
+                    note: 38
+                },
+                MidiMessage::ControlChange {
+                    channel: 9,
+                    controller: 7,
+                    value: 127
+                },
+                MidiMessage::NoteOff {
+                    channel: 9,
+                    note: 38
+                },
+            ]
+        );
+        assert_eq!(MidiMessage::parse(&[]), None);
+        assert_eq!(MidiMessage::parse(&[0x99, 38]), None);
+    }
+
+    #[test]
+    fn maps_the_recording_to_frames() {
+        let mapping: Mapping = serde_json::from_str(
+            r#"{
+                "channel": 9,
+                "notes": [{ "note": 38, "lights": [1], "color": "red", "release": true }],
+                "controls": [{ "cc": 7, "lights": [2], "attribute": "bri" }]
+            }"#,
+        )
+        .unwrap();
+        let (port, bridge) = stand_in(b"key", &[0x12, 0x34]);
+        let streamer = Streamer::connect(("127.0.0.1", port), "key", &[0x12, 0x34]).unwrap();
+        let mut mapper = Mapper::new(mapping, Output::Stream(streamer));
+        for bytes in RECORDING {
+            mapper.handle(bytes).unwrap();
+        }
+        drop(mapper);
+
+        let (red, white, black) = ([0xffff, 0, 0], [0xffff; 3], [0; 3]);
+        let frames = [
+            vec![(1, red)],
+            vec![(1, red), (2, black)],
+            vec![(1, black), (2, black)],
+            vec![(1, black), (2, white)],
+            vec![(1, black), (2, white)],
+        ];
+        let expected: Vec<Vec<u8>> = frames
+            .iter()
+            .enumerate()
+            .map(|(sequence, lights)| encode_v1(sequence as u8, ColorSpace::Rgb, lights))
+            .collect();
+        assert_eq!(bridge.join().unwrap(), expected);
+    }
+}
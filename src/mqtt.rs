@@ -0,0 +1,575 @@
+use crate::bridge::Bridge;
+use crate::color::{kelvin_to_mired, Rgb};
+use crate::lightstructs::*;
+use crate::watcher::Kind;
+use serde::*;
+use serde_json::value::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::time::Duration;
+
+/// A `/set` message: any `SendableState` fields, plus a colour or a colour
+/// temperature given the friendly way, like `{"on": true, "color": "orange"}`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SetRequest {
+    #[serde(flatten)]
+    pub state: SendableState,
+    /// Colour name, `#hex` or `rgb(r, g, b)`, sent as `xy`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    /// Colour temperature in Kelvin, sent as `ct`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kelvin: Option<u32>,
+}
+
+impl SetRequest {
+    /// The state to send to the bridge
+    pub fn sendable(&self) -> Result<SendableState, String> {
+        let mut state = self.state.clone();
+        if let Some(color) = &self.color {
+            state.xy = Some(color.parse::<Rgb>()?.xy());
+        }
+        if let Some(kelvin) = self.kelvin {
+            state.ct = Some(kelvin_to_mired(kelvin));
+        }
+        Ok(state)
+    }
+}
+
+/// A light, group or sensor the way it gets published
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entity {
+    pub kind: Kind,
+    pub id: u8,
+    pub name: String,
+    /// The name as it appears in topics, unique among entities of the same kind
+    pub slug: String,
+    /// What the bridge calls its type, like `Extended color light` or `ZLLPresence`
+    pub device_type: String,
+    pub state: Value,
+}
+
+/// Publishes the lights, groups and sensors of a bridge to an MQTT broker, each
+/// under `<base>/<kind>/<name>/state`, and takes commands on the `/set` topics of
+/// lights and groups. Home Assistant picks them up through its MQTT discovery.
+///
+/// Connecting to a broker needs the `mqtt` feature, the topics and payloads are
+/// worked out without it.
+///
+/// ```no_run
+/// # use huemanity::bridge::Bridge;
+/// # use huemanity::mqtt::Gateway;
+/// let bridge = Bridge::link();
+/// let mut gateway = Gateway::new(&bridge);
+/// gateway.refresh().unwrap();
+/// for (topic, payload) in gateway.states() {
+///     println!("{} {}", topic, payload);
+/// }
+/// ```
+pub struct Gateway<'a> {
+    bridge: &'a Bridge,
+    /// Identifies the bridge in topics and IDs
+    pub name: String,
+    /// Topic everything gets published under, `huemanity/<bridge>` by default
+    pub base: String,
+    /// Where Home Assistant looks for discovery messages, `None` to send none
+    pub discovery_prefix: Option<String>,
+    /// How often the bridge is polled for changes. Every poll takes three
+    /// requests, and commands sent through the gateway are published right away.
+    pub interval: Duration,
+    entities: Vec<Entity>,
+}
+
+impl<'a> Gateway<'a> {
+    pub fn new(bridge: &'a Bridge) -> Self {
+        let name = slug(&bridge.id().map_or_else(|| bridge.ip(), str::to_owned));
+        Gateway {
+            bridge,
+            base: format!("huemanity/{}", name),
+            name,
+            discovery_prefix: Some("homeassistant".to_owned()),
+            interval: Duration::from_secs(5),
+            entities: Vec::new(),
+        }
+    }
+
+    pub fn entities(&self) -> &[Entity] {
+        &self.entities
+    }
+
+    /// Where `online` or `offline` is published, depending on whether the gateway
+    /// is connected
+    pub fn availability_topic(&self) -> String {
+        format!("{}/status", self.base)
+    }
+
+    pub fn state_topic(&self, entity: &Entity) -> String {
+        format!("{}/{}/{}/state", self.base, entity.kind, entity.slug)
+    }
+
+    pub fn set_topic(&self, entity: &Entity) -> String {
+        format!("{}/{}/{}/set", self.base, entity.kind, entity.slug)
+    }
+
+    /// Topic filters to subscribe to for commands
+    pub fn subscriptions(&self) -> Vec<String> {
+        vec![
+            format!("{}/light/+/set", self.base),
+            format!("{}/group/+/set", self.base),
+        ]
+    }
+
+    /// Fetches the current state of everything from the bridge
+    pub fn refresh(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut entities = Vec::new();
+        for (kind, endpoint) in [
+            (Kind::Light, "lights"),
+            (Kind::Group, "groups"),
+            (Kind::Sensor, "sensors"),
+        ] {
+            entities.extend(collect(kind, self.bridge.fetch(endpoint)?));
+        }
+        self.entities = entities;
+        Ok(())
+    }
+
+    /// The state topic and payload of every entity
+    pub fn states(&self) -> Vec<(String, String)> {
+        self.entities
+            .iter()
+            .map(|entity| (self.state_topic(entity), entity.state.to_string()))
+            .collect()
+    }
+
+    /// The Home Assistant discovery topic and config of every entity it can show
+    pub fn discovery(&self) -> Vec<(String, String)> {
+        let prefix = match &self.discovery_prefix {
+            Some(prefix) => prefix,
+            None => return Vec::new(),
+        };
+        self.entities
+            .iter()
+            .filter_map(|entity| {
+                let (component, mut config) = self.discovery_config(entity)?;
+                let id = format!("huemanity_{}_{}_{}", self.name, entity.kind, entity.id);
+                config["name"] = Value::from(entity.name.clone());
+                config["unique_id"] = Value::from(id.clone());
+                config["state_topic"] = Value::from(self.state_topic(entity));
+                config["availability_topic"] = Value::from(self.availability_topic());
+                config["device"] = serde_json::json!({
+                    "identifiers": [format!("huemanity_{}", self.name)],
+                    "name": format!("Hue bridge {}", self.name),
+                    "manufacturer": "Signify",
+                });
+                Some((
+                    format!("{}/{}/{}/config", prefix, component, id),
+                    config.to_string(),
+                ))
+            })
+            .collect()
+    }
+
+    /// The component and the config particular to it, lights and groups both
+    /// become lights. Sensors Home Assistant has no use for get `None`.
+    fn discovery_config(&self, entity: &Entity) -> Option<(&'static str, Value)> {
+        match (entity.kind, entity.device_type.as_str()) {
+            (Kind::Light, _) | (Kind::Group, _) => {
+                let set = self.set_topic(entity);
+                let state = self.state_topic(entity);
+                let mut config = serde_json::json!({
+                    "command_topic": set,
+                    "payload_on": r#"{"on":true}"#,
+                    "payload_off": r#"{"on":false}"#,
+                    "state_value_template":
+                        r#"{% if value_json.on %}{"on":true}{% else %}{"on":false}{% endif %}"#,
+                });
+                if entity.state.get("bri").is_some() {
+                    config["brightness_command_topic"] = Value::from(set.clone());
+                    config["brightness_command_template"] = Value::from(r#"{"bri": {{ value }}}"#);
+                    config["brightness_state_topic"] = Value::from(state.clone());
+                    config["brightness_value_template"] = Value::from("{{ value_json.bri }}");
+                    config["brightness_scale"] = Value::from(254);
+                }
+                if entity.state.get("ct").is_some() {
+                    config["color_temp_command_topic"] = Value::from(set.clone());
+                    config["color_temp_command_template"] = Value::from(r#"{"ct": {{ value }}}"#);
+                    config["color_temp_state_topic"] = Value::from(state.clone());
+                    config["color_temp_value_template"] = Value::from("{{ value_json.ct }}");
+                    config["min_mireds"] = Value::from(153);
+                    config["max_mireds"] = Value::from(500);
+                }
+                if entity.state.get("xy").is_some() {
+                    config["xy_command_topic"] = Value::from(set);
+                    config["xy_command_template"] = Value::from(r#"{"xy": [{{ x }}, {{ y }}]}"#);
+                    config["xy_state_topic"] = Value::from(state);
+                    config["xy_value_template"] = Value::from("{{ value_json.xy | join(',') }}");
+                }
+                Some(("light", config))
+            }
+            (Kind::Sensor, "ZLLPresence") => Some((
+                "binary_sensor",
+                serde_json::json!({
+                    "device_class": "motion",
+                    "value_template": "{{ 'ON' if value_json.presence else 'OFF' }}",
+                }),
+            )),
+            (Kind::Sensor, "ZLLTemperature") => Some((
+                "sensor",
+                serde_json::json!({
+                    "device_class": "temperature",
+                    "unit_of_measurement": "°C",
+                    "value_template": "{{ value_json.temperature / 100 }}",
+                }),
+            )),
+            (Kind::Sensor, "ZLLLightLevel") => Some((
+                "sensor",
+                serde_json::json!({
+                    "device_class": "illuminance",
+                    "unit_of_measurement": "lx",
+                    // the bridge reports 10000 log10(lux) + 1
+                    "value_template": "{{ (10 ** ((value_json.lightlevel - 1) / 10000)) | round(1) }}",
+                }),
+            )),
+            (Kind::Sensor, _) => None,
+        }
+    }
+
+    /// Carries out a message received on a `/set` topic
+    pub fn set(&self, topic: &str, payload: &str) -> Result<(), Box<dyn Error>> {
+        let entity = self
+            .entities
+            .iter()
+            .find(|entity| entity.kind != Kind::Sensor && self.set_topic(entity) == topic)
+            .ok_or_else(|| format!("nothing is listening on {}", topic))?;
+        let request: SetRequest = serde_json::from_str(payload)?;
+        let state = request.sendable()?;
+        match entity.kind {
+            Kind::Group => self.bridge.group_state(entity.id, &state)?,
+            _ => self.bridge.state(entity.id, &state)?,
+        }
+        Ok(())
+    }
+
+    /// Connects to the broker at `host` and `port` and keeps publishing every change,
+    /// telling `on_event` what is going on. Only returns if the first connection to
+    /// the broker fails.
+    #[cfg(feature = "mqtt")]
+    pub fn run<F: FnMut(&str)>(
+        &mut self,
+        host: &str,
+        port: u16,
+        credentials: Option<(&str, &str)>,
+        mut on_event: F,
+    ) -> Result<(), Box<dyn Error>> {
+        use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, QoS};
+        use std::sync::mpsc::{channel, RecvTimeoutError};
+        use std::thread;
+        use std::time::Instant;
+
+        let mut options = MqttOptions::new(format!("huemanity-{}", self.name), host, port);
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_last_will(LastWill::new(
+            self.availability_topic(),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        if let Some((username, password)) = credentials {
+            options.set_credentials(username, password);
+        }
+        let (client, mut connection) = Client::new(options, 100);
+
+        // the connection has to be polled all the time for anything to get sent
+        let (sender, events) = channel();
+        thread::spawn(move || {
+            for event in connection.iter() {
+                let failed = event.is_err();
+                if sender.send(event.map_err(|e| e.to_string())).is_err() {
+                    return;
+                }
+                // the next poll reconnects right away
+                if failed {
+                    thread::sleep(Duration::from_secs(2));
+                }
+            }
+        });
+
+        let mut connected = false;
+        let mut ever_connected = false;
+        let mut polled: Option<Instant> = None;
+        // what the broker has been sent, so only changes get published
+        let mut published: BTreeMap<String, String> = BTreeMap::new();
+        loop {
+            let wait = polled.map_or(Duration::ZERO, |at| {
+                self.interval.saturating_sub(at.elapsed())
+            });
+            match events.recv_timeout(wait) {
+                Ok(Ok(Event::Incoming(Packet::ConnAck(_)))) => {
+                    on_event(&format!("connected to {}:{}", host, port));
+                    connected = true;
+                    ever_connected = true;
+                    published.clear();
+                    polled = None;
+                    for filter in self.subscriptions() {
+                        client.subscribe(filter, QoS::AtLeastOnce)?;
+                    }
+                    client.publish(self.availability_topic(), QoS::AtLeastOnce, true, "online")?;
+                }
+                Ok(Ok(Event::Incoming(Packet::Publish(message)))) => {
+                    let payload = String::from_utf8_lossy(&message.payload);
+                    match self.set(&message.topic, &payload) {
+                        // publish the new state straight away
+                        Ok(()) => polled = None,
+                        Err(e) => on_event(&format!("{}: {}", message.topic, e)),
+                    }
+                }
+                Ok(Ok(_)) => (),
+                Ok(Err(e)) if !ever_connected => return Err(e.into()),
+                Ok(Err(e)) => {
+                    if connected {
+                        on_event(&format!("lost the broker, reconnecting: {}", e));
+                    }
+                    connected = false;
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err("connection thread stopped".into())
+                }
+            }
+
+            if connected && polled.is_none_or(|at| at.elapsed() >= self.interval) {
+                polled = Some(Instant::now());
+                if let Err(e) = self.refresh() {
+                    on_event(&format!("could not poll the bridge: {}", e));
+                    continue;
+                }
+                for (topic, payload) in self.discovery().into_iter().chain(self.states()) {
+                    if published.get(&topic) != Some(&payload) {
+                        client.publish(topic.clone(), QoS::AtLeastOnce, true, payload.clone())?;
+                        published.insert(topic, payload);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Turns the resources of one kind into entities with slugs of their own
+fn collect(kind: Kind, resources: BTreeMap<u8, Value>) -> Vec<Entity> {
+    let mut slugs = BTreeSet::new();
+    let mut entities = Vec::new();
+    for (id, resource) in resources {
+        let name = resource["name"].as_str().unwrap_or_default().to_owned();
+        let mut entity_slug = slug(&name);
+        // a name can come up twice, and the bridge allows names with nothing left in a topic
+        if entity_slug.is_empty() || !slugs.insert(entity_slug.clone()) {
+            entity_slug = format!("{}_{}", entity_slug, id)
+                .trim_start_matches('_')
+                .to_owned();
+        }
+        entities.push(Entity {
+            kind,
+            id,
+            name,
+            slug: entity_slug,
+            device_type: resource["type"].as_str().unwrap_or_default().to_owned(),
+            state: published_state(kind, &resource),
+        });
+    }
+    entities
+}
+
+/// The state of a resource as it gets published: the state of a light, the last
+/// action of a group with `on` being whether any of its lights are, and the state
+/// of a sensor along with its battery level
+fn published_state(kind: Kind, resource: &Value) -> Value {
+    let mut state = match kind {
+        Kind::Group => resource["action"].clone(),
+        _ => resource["state"].clone(),
+    };
+    if let Some(state) = state.as_object_mut() {
+        match kind {
+            Kind::Group => {
+                for (field, value) in resource["state"].as_object().into_iter().flatten() {
+                    state.insert(field.clone(), value.clone());
+                }
+                state.insert("on".to_owned(), resource["state"]["any_on"].clone());
+            }
+            Kind::Sensor => {
+                if let Some(battery) = resource["config"].get("battery") {
+                    state.insert("battery".to_owned(), battery.clone());
+                }
+            }
+            Kind::Light => (),
+        }
+    }
+    state
+}
+
+/// Turns a name into something that can go in a topic: lowercase letters, digits
+/// and underscores
+pub fn slug(name: &str) -> String {
+    let mut slug = String::with_capacity(name.len());
+    for c in name.to_lowercase().chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('_') {
+            slug.push('_');
+        }
+    }
+    slug.trim_end_matches('_').to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::Topology;
+    use serde_json::json;
+
+    fn topology() -> Topology {
+        let light = |name: &str| {
+            json!({
+                "name": name,
+                "type": "Extended color light",
+                "state": { "on": true, "bri": 200, "ct": 366, "xy": [0.45, 0.41], "reachable": true },
+            })
+        };
+        Topology {
+            bridge: "127.0.0.1".to_owned(),
+            lights: [(1, light("Desk")), (2, light("desk")), (3, light("!!"))].into(),
+            groups: [(
+                1,
+                json!({
+                    "name": "Living room",
+                    "type": "Room",
+                    "state": { "all_on": false, "any_on": true },
+                    "action": { "on": false, "bri": 100 },
+                }),
+            )]
+            .into(),
+            ..Topology::default()
+        }
+    }
+
+    #[test]
+    fn makes_slugs_unique() {
+        assert_eq!(slug("Living room"), "living_room");
+        assert_eq!(slug("  Hall light #2!"), "hall_light_2");
+        assert_eq!(slug("!!"), "");
+
+        let slugs: Vec<String> = collect(Kind::Light, topology().lights)
+            .into_iter()
+            .map(|entity| entity.slug)
+            .collect();
+        assert_eq!(slugs, ["desk", "desk_2", "3"]);
+    }
+
+    #[test]
+    fn publishes_states() {
+        let group = &topology().groups[&1];
+        assert_eq!(
+            published_state(Kind::Group, group),
+            json!({ "on": true, "all_on": false, "any_on": true, "bri": 100 })
+        );
+        let sensor = json!({
+            "state": { "presence": true },
+            "config": { "on": true, "battery": 80 },
+        });
+        assert_eq!(
+            published_state(Kind::Sensor, &sensor),
+            json!({ "presence": true, "battery": 80 })
+        );
+        assert_eq!(published_state(Kind::Light, &json!({})), Value::Null);
+    }
+
+    #[test]
+    fn describes_entities_to_home_assistant() {
+        let bridge = Bridge::rehearsal(topology());
+        let mut gateway = Gateway::new(&bridge);
+        gateway.refresh().unwrap();
+        gateway.entities.extend(collect(
+            Kind::Sensor,
+            [
+                (
+                    5,
+                    json!({ "name": "Hall", "type": "ZLLPresence", "state": {}, "config": {} }),
+                ),
+                (
+                    6,
+                    json!({ "name": "Switch", "type": "ZLLSwitch", "state": {}, "config": {} }),
+                ),
+            ]
+            .into(),
+        ));
+
+        let discovery: BTreeMap<String, Value> = gateway
+            .discovery()
+            .into_iter()
+            .map(|(topic, config)| (topic, serde_json::from_str(&config).unwrap()))
+            .collect();
+        assert_eq!(discovery.len(), 5);
+
+        let desk = &discovery["homeassistant/light/huemanity_127_0_0_1_light_1/config"];
+        assert_eq!(desk["command_topic"], "huemanity/127_0_0_1/light/desk/set");
+        assert_eq!(desk["state_topic"], "huemanity/127_0_0_1/light/desk/state");
+        assert_eq!(desk["availability_topic"], "huemanity/127_0_0_1/status");
+        assert!(desk.get("color_temp_command_topic").is_some());
+        assert!(desk.get("xy_command_topic").is_some());
+
+        let living = &discovery["homeassistant/light/huemanity_127_0_0_1_group_1/config"];
+        assert_eq!(living["name"], "Living room");
+        assert!(living.get("brightness_command_topic").is_some());
+        assert!(living.get("xy_command_topic").is_none());
+
+        let hall = &discovery["homeassistant/binary_sensor/huemanity_127_0_0_1_sensor_5/config"];
+        assert_eq!(hall["device_class"], "motion");
+
+        gateway.discovery_prefix = None;
+        assert!(gateway.discovery().is_empty());
+    }
+
+    /// Needs a broker, at `HUEMANITY_TEST_BROKER` or on localhost:1883
+    #[cfg(feature = "mqtt")]
+    #[test]
+    #[ignore]
+    fn publishes_to_a_broker() {
+        use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
+        use std::thread;
+
+        let broker =
+            std::env::var("HUEMANITY_TEST_BROKER").unwrap_or_else(|_| "localhost:1883".to_owned());
+        let (host, port) = broker.split_once(':').unwrap_or((&broker, "1883"));
+        let (host, port) = (host.to_owned(), port.parse().unwrap());
+
+        let bridge: &'static Bridge = Box::leak(Box::new(Bridge::rehearsal(topology())));
+        let mut gateway = Gateway::new(bridge);
+        gateway.base = format!("huemanity-test/{}", std::process::id());
+        gateway.discovery_prefix = None;
+        let (state, base) = (
+            format!("{}/light/desk/state", gateway.base),
+            gateway.base.clone(),
+        );
+        let (broker_host, broker_port) = (host.clone(), port);
+        thread::spawn(move || {
+            let _ = gateway.run(&broker_host, broker_port, None, |_| ());
+        });
+
+        let options =
+            MqttOptions::new(format!("huemanity-test-{}", std::process::id()), host, port);
+        let (client, mut connection) = Client::new(options, 10);
+        client
+            .subscribe(format!("{}/#", base), QoS::AtLeastOnce)
+            .unwrap();
+        let published = connection
+            .iter()
+            .take(100)
+            .find_map(|event| match event.unwrap() {
+                Event::Incoming(Packet::Publish(message)) if message.topic == state => {
+                    Some(serde_json::from_slice::<Value>(&message.payload).unwrap())
+                }
+                _ => None,
+            })
+            .expect("the desk was never published");
+        assert_eq!(published["bri"], 200);
+    }
+}
@@ -0,0 +1,168 @@
+use serde_json::value::Value;
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// Names of the formats, as taken by `Format::from_str`
+pub const FORMATS: &[&str] = &["json", "table", "csv", "plain"];
+
+/// How results get printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Table,
+    Csv,
+    /// Sentences meant for people, the way the CLI always printed
+    Plain,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "table" => Ok(Format::Table),
+            "csv" => Ok(Format::Csv),
+            "plain" => Ok(Format::Plain),
+            _ => Err(format!(
+                "`{}` is not an output format, try one of: {}",
+                s,
+                FORMATS.join(", ")
+            )),
+        }
+    }
+}
+
+/// Turns any serialised value into a header and rows of cells.
+///
+/// A list gives a row per item and an object a single row, unless all of its values
+/// are objects (like the bridge's maps of lights by ID), which gives a row per value
+/// with the key in an `id` column. Nested objects become `parent.child` columns and
+/// lists are written out as JSON.
+///
+/// ```
+/// # use huemanity::output::rows;
+/// let lights = serde_json::json!({"1": {"name": "Desk", "state": {"on": true}}});
+/// let (header, rows) = rows(&lights);
+/// assert_eq!(header, ["id", "name", "state.on"]);
+/// assert_eq!(rows, [["1", "Desk", "true"]]);
+/// ```
+pub fn rows(value: &Value) -> (Vec<String>, Vec<Vec<String>>) {
+    let records: Vec<Vec<(String, String)>> = match value {
+        Value::Array(items) => items.iter().map(flatten).collect(),
+        Value::Object(map) if !map.is_empty() && map.values().all(Value::is_object) => map
+            .iter()
+            .map(|(id, item)| {
+                let mut record = vec![("id".to_owned(), id.clone())];
+                record.extend(flatten(item).into_iter().filter(|(key, _)| key != "id"));
+                record
+            })
+            .collect(),
+        Value::Null => Vec::new(),
+        value => vec![flatten(value)],
+    };
+
+    // columns in the order they first show up
+    let mut header: Vec<String> = Vec::new();
+    for (key, _) in records.iter().flatten() {
+        if !header.contains(key) {
+            header.push(key.clone());
+        }
+    }
+    let rows = records
+        .iter()
+        .map(|record| {
+            header
+                .iter()
+                .map(|column| {
+                    record
+                        .iter()
+                        .find(|(key, _)| key == column)
+                        .map(|(_, cell)| cell.clone())
+                        .unwrap_or_default()
+                })
+                .collect()
+        })
+        .collect();
+    (header, rows)
+}
+
+/// The fields of one record, with nested objects spread out
+fn flatten(value: &Value) -> Vec<(String, String)> {
+    fn walk(prefix: &str, value: &Value, fields: &mut Vec<(String, String)>) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map {
+                    let key = if prefix.is_empty() {
+                        key.clone()
+                    } else {
+                        format!("{}.{}", prefix, key)
+                    };
+                    walk(&key, value, fields);
+                }
+            }
+            value => {
+                let key = if prefix.is_empty() { "value" } else { prefix };
+                fields.push((key.to_owned(), cell(value)));
+            }
+        }
+    }
+    let mut fields = Vec::new();
+    walk("", value, &mut fields);
+    fields
+}
+
+/// A single value the way it goes in a cell
+pub fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Lines the rows up in columns under the header
+pub fn table(value: &Value) -> String {
+    let (header, rows) = rows(value);
+    let mut widths: Vec<usize> = header.iter().map(|h| h.chars().count()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut out = String::new();
+    for row in std::iter::once(&header).chain(&rows) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        let _ = writeln!(out, "{}", cells.join("  ").trim_end());
+    }
+    out
+}
+
+/// The header and rows as comma separated values
+pub fn csv(value: &Value) -> String {
+    let (header, rows) = rows(value);
+    let mut out = String::new();
+    for row in std::iter::once(&header).chain(&rows) {
+        let _ = writeln!(out, "{}", csv_row(row));
+    }
+    out
+}
+
+/// One line of comma separated values, quoting cells that need it
+pub fn csv_row(cells: &[String]) -> String {
+    let cells: Vec<String> = cells
+        .iter()
+        .map(|cell| {
+            if cell.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", cell.replace('"', "\"\""))
+            } else {
+                cell.clone()
+            }
+        })
+        .collect();
+    cells.join(",")
+}
@@ -0,0 +1,716 @@
+use crate::bridge::Bridge;
+use crate::effects::Effect;
+use crate::lightstructs::*;
+use crate::sequence::Target;
+use chrono::{DateTime, Duration as Offset, Local, NaiveDate, TimeZone, Utc};
+use serde::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+/// How long effects that would otherwise loop forever run for, in seconds
+const EFFECT_DURATION: f32 = 60.0;
+
+/// Longest the daemon sleeps without looking at the clock, so it notices the
+/// clock jumping (suspend, daylight saving)
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+/// How late a job that was missed (while the daemon was down) can still be run,
+/// anything later is only reported
+const MISSED_GRACE: Duration = Duration::from_secs(15 * 60);
+
+/// Noon on the 1st of January 2000 in unix time, which the solar formulas count from
+const J2000: f64 = 946_728_000.0;
+
+/// Points in the sun's day that jobs can be tied to
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SolarEvent {
+    /// Start of civil twilight, when the sun is 6° below the horizon
+    Dawn,
+    Sunrise,
+    Noon,
+    Sunset,
+    /// End of civil twilight
+    Dusk,
+}
+
+impl SolarEvent {
+    /// How far the centre of the sun is below the horizon at the event, in degrees.
+    /// Sunrise and sunset allow for refraction and the size of the sun.
+    fn depression(self) -> f64 {
+        match self {
+            SolarEvent::Dawn | SolarEvent::Dusk => 6.0,
+            SolarEvent::Sunrise | SolarEvent::Sunset => 0.833,
+            SolarEvent::Noon => 0.0,
+        }
+    }
+}
+
+impl fmt::Display for SolarEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SolarEvent::Dawn => write!(f, "dawn"),
+            SolarEvent::Sunrise => write!(f, "sunrise"),
+            SolarEvent::Noon => write!(f, "noon"),
+            SolarEvent::Sunset => write!(f, "sunset"),
+            SolarEvent::Dusk => write!(f, "dusk"),
+        }
+    }
+}
+
+/// Solar noon (in days from noon on the 1st of January 2000) and the sun's
+/// declination (radians) for the day that `mean_noon` falls in
+fn solar_day(mean_noon: f64) -> (f64, f64) {
+    let anomaly = (357.5291 + 0.985_600_28 * mean_noon)
+        .rem_euclid(360.0)
+        .to_radians();
+    let center =
+        1.9148 * anomaly.sin() + 0.02 * (2.0 * anomaly).sin() + 0.0003 * (3.0 * anomaly).sin();
+    let ecliptic = (anomaly.to_degrees() + center + 180.0 + 102.9372)
+        .rem_euclid(360.0)
+        .to_radians();
+    let noon = mean_noon + 0.0053 * anomaly.sin() - 0.0069 * (2.0 * ecliptic).sin();
+    let declination = (ecliptic.sin() * 23.4397_f64.to_radians().sin()).asin();
+    (noon, declination)
+}
+
+/// When `event` happens on `date` at the given place (degrees, north and east are
+/// positive), or `None` if the sun doesn't get that high or low that day, as happens
+/// near the poles. Follows the sunrise equation, which is good to a minute or so.
+pub fn solar_time(
+    event: SolarEvent,
+    date: NaiveDate,
+    latitude: f64,
+    longitude: f64,
+) -> Option<DateTime<Utc>> {
+    // days from noon on the 1st of January 2000, which the formulas count from
+    let days = (date - NaiveDate::from_ymd_opt(2000, 1, 1)?).num_days() as f64;
+    let (noon, declination) = solar_day(days - longitude / 360.0);
+
+    let latitude = latitude.to_radians();
+    let cos_hour = ((-event.depression()).to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+    if !(-1.0..=1.0).contains(&cos_hour) {
+        return None;
+    }
+    let hour = cos_hour.acos().to_degrees() / 360.0;
+    let time = match event {
+        SolarEvent::Dawn | SolarEvent::Sunrise => noon - hour,
+        SolarEvent::Noon => noon,
+        SolarEvent::Sunset | SolarEvent::Dusk => noon + hour,
+    };
+    Utc.timestamp_opt((J2000 + time * 86_400.0).round() as i64, 0)
+        .single()
+}
+
+/// How high the sun is above the horizon at `time` at the given place, in degrees
+/// (negative when it is below)
+pub fn solar_elevation(time: DateTime<Utc>, latitude: f64, longitude: f64) -> f64 {
+    let days = (time.timestamp() as f64 - J2000) / 86_400.0;
+    let (noon, declination) = solar_day((days + longitude / 360.0).round() - longitude / 360.0);
+    let hour = ((days - noon) * 360.0).to_radians();
+    let latitude = latitude.to_radians();
+    (latitude.sin() * declination.sin() + latitude.cos() * declination.cos() * hour.cos())
+        .asin()
+        .to_degrees()
+}
+
+/// Parses a cron expression. The usual five fields (minute, hour, day of month,
+/// month, day of week) are taken as well as ones with seconds in front. Days of the
+/// week have to be given by name (`Mon-Fri`): the cron crate counts them from
+/// Sunday = 1 rather than 0, so numbers would quietly run jobs a day early.
+pub fn parse_cron(expression: &str) -> Result<cron::Schedule, String> {
+    let expression = match expression.split_whitespace().count() {
+        5 => format!("0 {}", expression),
+        _ => expression.to_owned(),
+    };
+    // only the steps (`*/2`) can be numbers, they mean the same in both countings
+    let numbered = expression.split_whitespace().nth(5).is_some_and(|days| {
+        days.split(',').any(|part| {
+            part.split('/')
+                .next()
+                .unwrap_or_default()
+                .contains(|c: char| c.is_ascii_digit())
+        })
+    });
+    if numbered {
+        return Err(format!(
+            "`{}` gives days of the week by number, use names like Mon-Fri or Sun instead",
+            expression
+        ));
+    }
+    cron::Schedule::from_str(&expression)
+        .map_err(|e| format!("`{}` is not a valid cron expression: {}", expression, e))
+}
+
+/// Something to do to the lights at set times. Each job has either a `cron`
+/// expression or a `sun` event (moved by `offset` minutes), and applies a `state`,
+/// a `scene` or an `effect`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Job {
+    pub name: String,
+    pub cron: Option<String>,
+    pub sun: Option<SolarEvent>,
+    /// Minutes after the solar event, negative for before
+    #[serde(default)]
+    pub offset: i64,
+    #[serde(default)]
+    pub lights: Vec<Target>,
+    #[serde(default)]
+    pub groups: Vec<Target>,
+    pub state: Option<SendableState>,
+    /// Scene ID or name, recalled on the groups
+    pub scene: Option<String>,
+    /// Name of an effect to run on the lights (and the lights of the groups)
+    pub effect: Option<String>,
+    /// Seconds to run the effect for, `EFFECT_DURATION` if not set. Effects that end
+    /// by themselves (fade, sunrise) take this as their length instead.
+    pub duration: Option<f32>,
+}
+
+impl Job {
+    /// When the job is triggered, for listing
+    pub fn trigger(&self) -> String {
+        match (&self.cron, self.sun, self.offset) {
+            (Some(cron), _, _) => format!("cron {}", cron),
+            (None, Some(sun), 0) => sun.to_string(),
+            (None, Some(sun), offset) => format!("{} {:+}min", sun, offset),
+            (None, None, _) => "never".to_owned(),
+        }
+    }
+
+    fn check(&self, located: bool) -> Result<(), String> {
+        let fail = |reason: &str| Err(format!("job `{}` {}", self.name, reason));
+        match (&self.cron, self.sun) {
+            (Some(cron), None) => {
+                parse_cron(cron)?;
+            }
+            (None, Some(_)) if !located => {
+                return fail("runs on the sun, which needs `latitude` and `longitude`")
+            }
+            (None, Some(_)) => (),
+            _ => return fail("needs either `cron` or `sun`"),
+        }
+        if let Some(duration) = self.duration.filter(|d| !d.is_finite() || *d < 0.0) {
+            return fail(&format!("has a duration of {} seconds", duration));
+        }
+        if self.lights.is_empty() && self.groups.is_empty() {
+            return fail("has no lights or groups");
+        }
+        if self.scene.is_some() && self.groups.is_empty() {
+            return fail("recalls a scene, which needs groups");
+        }
+        match (&self.state, &self.scene, &self.effect) {
+            (Some(_), None, None) | (None, Some(_), None) => Ok(()),
+            (None, None, Some(effect)) => Effect::named(effect, None, None).map(|_| ()),
+            _ => fail("needs one of `state`, `scene` or `effect`"),
+        }
+    }
+
+    /// Carries out the job. Effects run to their end (or for `duration`) before
+    /// this returns.
+    pub fn run(&self, bridge: &Bridge) -> Result<(), Box<dyn Error>> {
+        let lights = self
+            .lights
+            .iter()
+            .map(|light| light.resolve(bridge, "lights"))
+            .collect::<Result<Vec<u8>, _>>()?;
+        let groups = self
+            .groups
+            .iter()
+            .map(|group| group.resolve(bridge, "groups"))
+            .collect::<Result<Vec<u8>, _>>()?;
+
+        if let Some(state) = &self.state {
+            for light in &lights {
+                bridge.state(*light, state)?;
+            }
+            for group in &groups {
+                bridge.group_state(*group, state)?;
+            }
+        }
+        if let Some(scene) = &self.scene {
+            for group in &groups {
+                bridge.recall_scene(*group, scene)?;
+            }
+        }
+        if let Some(effect) = &self.effect {
+            // effects work light by light, so groups are broken up into theirs
+            let mut all: BTreeSet<u8> = lights.into_iter().collect();
+            for group in groups {
+                let group: Group = bridge.fetch(&format!("groups/{}", group))?;
+                for light in group.lights {
+                    all.insert(light.parse()?);
+                }
+            }
+            let all: Vec<u8> = all.into_iter().collect();
+            // the others would loop forever, so they are stopped once the duration is up
+            let (seconds, limit) = match effect.to_lowercase().as_str() {
+                "fade" | "sunrise" => (self.duration, None),
+                _ => (None, Some(self.duration.unwrap_or(EFFECT_DURATION))),
+            };
+            let effect = Effect::named(effect, None, seconds)?;
+            let limit = limit.map(Duration::try_from_secs_f32).transpose()?;
+            let stop = &AtomicBool::new(false);
+            thread::scope(|scope| {
+                if let Some(limit) = limit {
+                    scope.spawn(move || {
+                        let started = Instant::now();
+                        while started.elapsed() < limit && !stop.load(Ordering::Relaxed) {
+                            sleep(Duration::from_millis(100));
+                        }
+                        stop.store(true, Ordering::Relaxed);
+                    });
+                }
+                let result = effect.run(bridge, &all, stop);
+                stop.store(true, Ordering::Relaxed);
+                result
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// The jobs the daemon runs, usually loaded from a YAML (or JSON) file:
+/// ```yaml
+/// latitude: 51.48
+/// longitude: -0.01
+/// jobs:
+///   - name: porch
+///     sun: sunset
+///     offset: -30
+///     groups: [Outside]
+///     state: { on: true, bri: 200 }
+///   - name: wake up
+///     cron: "30 6 * * Mon-Fri"
+///     lights: [Bedroom]
+///     effect: sunrise
+///     duration: 900
+///   - name: bedtime
+///     cron: "0 23 * * *"
+///     groups: [Living]
+///     scene: Nightlight
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Schedule {
+    /// Where the sun is worked out for, in degrees (north is positive)
+    pub latitude: Option<f64>,
+    /// East is positive
+    pub longitude: Option<f64>,
+    pub jobs: Vec<Job>,
+}
+
+impl Schedule {
+    /// Where the schedule is kept if no other file is given, `~/.huemanity_schedule.yaml`
+    pub fn default_path() -> String {
+        let mut path = dirs::home_dir().unwrap_or_default();
+        path.push(".huemanity_schedule.yaml");
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Loads and checks the schedule, read as JSON if the file ends in `.json`
+    /// and as YAML otherwise
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path)?;
+        let schedule: Schedule = if path.ends_with(".json") {
+            serde_json::from_str(&text)?
+        } else {
+            serde_yaml::from_str(&text)?
+        };
+        let located = schedule.latitude.is_some() && schedule.longitude.is_some();
+        let mut names = BTreeSet::new();
+        for job in &schedule.jobs {
+            job.check(located)?;
+            if !names.insert(&job.name) {
+                return Err(format!("there is more than one job called `{}`", job.name).into());
+            }
+        }
+        Ok(schedule)
+    }
+
+    /// The first time after `after` that the job is due
+    pub fn next(&self, job: &Job, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        if let Some(cron) = &job.cron {
+            return parse_cron(cron).ok()?.after(&after).next();
+        }
+        let (sun, latitude, longitude) = (job.sun?, self.latitude?, self.longitude?);
+        // starting the day before catches events that fall on another date in UTC
+        let first = after.date_naive().pred_opt()?;
+        first
+            .iter_days()
+            .take(370)
+            .filter_map(|date| solar_time(sun, date, latitude, longitude))
+            .map(|time| time.with_timezone(&Local) + Offset::minutes(job.offset))
+            .find(|time| *time > after)
+    }
+
+    /// The latest time after `after` and no later than `now` that the job was due,
+    /// which is what a daemon that was down catches up on
+    fn due(
+        &self,
+        job: &Job,
+        after: DateTime<Local>,
+        now: DateTime<Local>,
+    ) -> Option<DateTime<Local>> {
+        if let Some(cron) = &job.cron {
+            return parse_cron(cron)
+                .ok()?
+                .after(&after)
+                .take_while(|time| *time <= now)
+                .last();
+        }
+        let mut due = None;
+        while let Some(next) = self
+            .next(job, due.unwrap_or(after))
+            .filter(|next| *next <= now)
+        {
+            due = Some(next);
+        }
+        due
+    }
+
+    /// The next `count` times any job is due, in order
+    pub fn upcoming(&self, after: DateTime<Local>, count: usize) -> Vec<(DateTime<Local>, &Job)> {
+        let mut upcoming = Vec::new();
+        for job in &self.jobs {
+            let mut time = after;
+            for _ in 0..count {
+                match self.next(job, time) {
+                    Some(next) => upcoming.push((next, job)),
+                    None => break,
+                }
+                time = upcoming[upcoming.len() - 1].0;
+            }
+        }
+        upcoming.sort_by_key(|(time, _)| *time);
+        upcoming.truncate(count);
+        upcoming
+    }
+
+    /// Where the last runs of the jobs in the schedule at `path` are kept, next to
+    /// it: `~/.huemanity_schedule.runs.json` for the default schedule
+    pub fn runs_path(path: &str) -> String {
+        Path::new(path)
+            .with_extension("runs.json")
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Runs the jobs as they come due, handing every result to `on_run`. Effects run
+    /// on their own threads, other jobs that fall due while one is running go right
+    /// after it. Only returns if no job will ever be due again.
+    ///
+    /// When each job last ran is kept in the file at `runs` (see `runs_path`), so
+    /// jobs that fell due while the daemon wasn't running are caught up on: run once
+    /// if their latest missed run is at most `MISSED_GRACE` late, and reported as
+    /// failed otherwise.
+    pub fn run<F: FnMut(&Job, Result<(), Box<dyn Error>>)>(
+        &self,
+        bridge: &Bridge,
+        runs: &str,
+        mut on_run: F,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut last = load_runs(runs);
+        last.retain(|name, _| self.jobs.iter().any(|job| &job.name == name));
+        // jobs that never ran start from now, and have nothing to catch up on
+        let started = Local::now();
+        for job in &self.jobs {
+            last.entry(job.name.clone()).or_insert(started);
+        }
+        save_runs(runs, &last)?;
+
+        let (finished, effects) = channel();
+        thread::scope(|scope| loop {
+            let now = Local::now();
+            let mut ran = false;
+            for job in &self.jobs {
+                let due = match self.due(job, last[&job.name], now) {
+                    Some(due) => due,
+                    None => continue,
+                };
+                last.insert(job.name.clone(), now);
+                ran = true;
+                if (now - due).to_std().unwrap_or_default() > MISSED_GRACE {
+                    let missed = format!("missed its run at {}", due.format("%Y-%m-%d %H:%M"));
+                    on_run(job, Err(missed.into()));
+                } else if job.effect.is_some() {
+                    let finished = finished.clone();
+                    scope.spawn(move || {
+                        let result = job.run(bridge).map_err(|e| e.to_string());
+                        let _ = finished.send((job, result));
+                    });
+                } else {
+                    on_run(job, job.run(bridge));
+                }
+            }
+            if ran {
+                save_runs(runs, &last)?;
+            }
+
+            let next = self
+                .jobs
+                .iter()
+                .filter_map(|job| self.next(job, last[&job.name]))
+                .min()
+                .ok_or("no job in the schedule is ever due")?;
+            let wait = (next - Local::now()).to_std().unwrap_or_default();
+            // effects that finish in the meantime get reported as they do
+            if let Ok((job, result)) = effects.recv_timeout(wait.min(MAX_SLEEP)) {
+                on_run(job, result.map_err(Into::into));
+            }
+        })
+    }
+}
+
+/// Reads when each job last ran, nothing if there is no record yet
+fn load_runs(path: &str) -> BTreeMap<String, DateTime<Local>> {
+    let runs: BTreeMap<String, i64> = fs::read_to_string(path)
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default();
+    runs.into_iter()
+        .filter_map(|(name, time)| Some((name, Local.timestamp_opt(time, 0).single()?)))
+        .collect()
+}
+
+fn save_runs(path: &str, runs: &BTreeMap<String, DateTime<Local>>) -> Result<(), Box<dyn Error>> {
+    let runs: BTreeMap<&String, i64> = runs
+        .iter()
+        .map(|(name, time)| (name, time.timestamp()))
+        .collect();
+    fs::write(path, serde_json::to_string_pretty(&runs)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::Topology;
+    use chrono::{Datelike, Timelike};
+    use serde_json::json;
+
+    fn local(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap()
+    }
+
+    fn job(yaml: &str) -> Job {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn schedule(jobs: Vec<Job>) -> Schedule {
+        Schedule {
+            latitude: Some(51.48),
+            longitude: Some(-0.01),
+            jobs,
+        }
+    }
+
+    /// Minutes between two times
+    fn apart<A: TimeZone, B: TimeZone>(a: DateTime<A>, b: DateTime<B>) -> i64 {
+        (a.timestamp() - b.timestamp()).abs() / 60
+    }
+
+    #[test]
+    fn parses_cron_expressions() {
+        // a Saturday, the next weekday is Monday
+        let saturday = local(2024, 6, 22, 12, 0);
+        let next = parse_cron("30 6 * * Mon-Fri")
+            .unwrap()
+            .after(&saturday)
+            .next()
+            .unwrap();
+        assert_eq!(next, local(2024, 6, 24, 6, 30));
+
+        let sundays = parse_cron("0 0 * * */2").unwrap();
+        assert_eq!(
+            sundays
+                .after(&saturday)
+                .next()
+                .unwrap()
+                .weekday()
+                .num_days_from_sunday()
+                % 2,
+            0
+        );
+        assert!(parse_cron("*/15 * * * *").is_ok());
+        assert!(parse_cron("0 0 12 1 1 * 2020").is_ok());
+
+        let numbered = parse_cron("0 23 * * 1-5").unwrap_err();
+        assert!(numbered.contains("Mon-Fri"), "{}", numbered);
+        assert!(parse_cron("0 0 0 * * 7").is_err());
+        assert!(parse_cron("0 0 * * Sat,0").is_err());
+        assert!(parse_cron("every day").is_err());
+    }
+
+    #[test]
+    fn works_out_the_sun() {
+        // midsummer at Greenwich: up at 03:43 and down at 20:21 (UTC)
+        let date = NaiveDate::from_ymd_opt(2024, 6, 21).unwrap();
+        let at = |h, m| Utc.with_ymd_and_hms(2024, 6, 21, h, m, 0).unwrap();
+        let time = |event| solar_time(event, date, 51.48, -0.01).unwrap();
+        assert!(apart(time(SolarEvent::Sunrise), at(3, 43)) <= 2);
+        assert!(apart(time(SolarEvent::Noon), at(12, 2)) <= 2);
+        assert!(apart(time(SolarEvent::Sunset), at(20, 21)) <= 2);
+        assert!(time(SolarEvent::Dawn) < time(SolarEvent::Sunrise));
+        assert!(time(SolarEvent::Dusk) > time(SolarEvent::Sunset));
+
+        // the sun doesn't set in Tromsø in June, nor rise in December
+        assert!(solar_time(SolarEvent::Sunset, date, 69.65, 18.96).is_none());
+        let winter = NaiveDate::from_ymd_opt(2024, 12, 21).unwrap();
+        assert!(solar_time(SolarEvent::Sunrise, winter, 69.65, 18.96).is_none());
+
+        assert!(solar_elevation(at(12, 2), 51.48, -0.01) > 60.0);
+        assert!(solar_elevation(at(0, 0), 51.48, -0.01) < 0.0);
+    }
+
+    #[test]
+    fn finds_the_next_run() {
+        let porch =
+            job("{ name: porch, sun: sunset, offset: -30, groups: [1], state: { on: true } }");
+        let schedule = schedule(vec![porch.clone()]);
+        let midnight = Utc.with_ymd_and_hms(2024, 6, 21, 0, 0, 0).unwrap();
+        let next = schedule
+            .next(&porch, midnight.with_timezone(&Local))
+            .unwrap();
+        assert!(apart(next, Utc.with_ymd_and_hms(2024, 6, 21, 19, 51, 0).unwrap()) <= 2);
+        // straight after the run it is the next day's
+        let after = schedule.next(&porch, next).unwrap();
+        assert_eq!(after.with_timezone(&Utc).day(), 22);
+
+        let nowhere = Schedule {
+            latitude: None,
+            ..schedule
+        };
+        assert!(nowhere.next(&porch, next).is_none());
+    }
+
+    #[test]
+    fn lists_upcoming_runs_in_order() {
+        let hourly = job("{ name: hourly, cron: '0 * * * *', lights: [1], state: { on: true } }");
+        let often = job("{ name: often, cron: '30 */2 * * *', lights: [1], state: { on: true } }");
+        let schedule = schedule(vec![hourly, often]);
+        let upcoming: Vec<(u32, u32, &str)> = schedule
+            .upcoming(local(2024, 6, 21, 0, 0), 4)
+            .into_iter()
+            .map(|(time, job)| (time.hour(), time.minute(), job.name.as_str()))
+            .collect();
+        assert_eq!(
+            upcoming,
+            [
+                (0, 30, "often"),
+                (1, 0, "hourly"),
+                (2, 0, "hourly"),
+                (2, 30, "often")
+            ]
+        );
+    }
+
+    #[test]
+    fn catches_up_on_the_latest_missed_run() {
+        let job = job("{ name: often, cron: '*/10 * * * *', lights: [1], state: { on: true } }");
+        let schedule = schedule(vec![job.clone()]);
+        let down = local(2024, 6, 21, 9, 0);
+        assert_eq!(
+            schedule.due(&job, down, local(2024, 6, 21, 12, 5)),
+            Some(local(2024, 6, 21, 12, 0))
+        );
+        assert_eq!(schedule.due(&job, down, local(2024, 6, 21, 9, 5)), None);
+    }
+
+    /// Runs a schedule of one job that last ran in 2019, until nothing is left to
+    /// run, and gives what was reported
+    fn catch_up(cron: &str) -> Vec<Result<(), String>> {
+        let bridge = Bridge::rehearsal(Topology {
+            bridge: "127.0.0.1".to_owned(),
+            lights: [(
+                1,
+                json!({ "name": "Desk", "type": "Extended color light", "state": { "on": true } }),
+            )]
+            .into(),
+            ..Topology::default()
+        });
+        let job = job(&format!(
+            "{{ name: once, cron: '{}', lights: [1], state: {{ on: true }} }}",
+            cron
+        ));
+        let path = std::env::temp_dir().join(format!(
+            "huemanity-catch-up-{}-{}.json",
+            std::process::id(),
+            cron.len()
+        ));
+        let path = path.to_string_lossy();
+        save_runs(
+            &path,
+            &[("once".to_owned(), local(2019, 1, 1, 0, 0))].into(),
+        )
+        .unwrap();
+
+        let mut reported = Vec::new();
+        let result = schedule(vec![job]).run(&bridge, &path, |_, result| {
+            reported.push(result.map_err(|e| e.to_string()))
+        });
+        assert!(result.unwrap_err().to_string().contains("ever due"));
+        fs::remove_file(&*path).unwrap();
+        reported
+    }
+
+    #[test]
+    fn reports_runs_missed_long_ago() {
+        let reported = catch_up("0 0 12 1 1 * 2020");
+        assert_eq!(reported.len(), 1);
+        assert!(reported[0]
+            .as_ref()
+            .unwrap_err()
+            .contains("missed its run at 2020-01-01 12:00"));
+    }
+
+    #[test]
+    fn runs_a_recent_miss_after_an_old_one() {
+        // due long ago, and again five minutes ago
+        let recent = Local::now() - Offset::minutes(5);
+        let cron = format!(
+            "0 {} {} {} {} * {},{}",
+            recent.minute(),
+            recent.hour(),
+            recent.day(),
+            recent.month(),
+            recent.year() - 4,
+            recent.year()
+        );
+        assert_eq!(catch_up(&cron), [Ok(())]);
+    }
+
+    #[test]
+    fn keeps_runs_next_to_the_schedule() {
+        assert_eq!(
+            Schedule::runs_path("/home/me/.huemanity_schedule.yaml"),
+            "/home/me/.huemanity_schedule.runs.json"
+        );
+        assert_eq!(Schedule::runs_path("jobs.json"), "jobs.runs.json");
+    }
+
+    #[test]
+    fn reads_back_saved_runs() {
+        let path = std::env::temp_dir().join(format!("huemanity-runs-{}.json", std::process::id()));
+        let path = path.to_string_lossy();
+        assert!(load_runs(&path).is_empty());
+
+        let time = Local.timestamp_opt(1_700_000_000, 0).unwrap();
+        let runs: BTreeMap<String, DateTime<Local>> = [
+            ("porch".to_owned(), time),
+            ("wake up".to_owned(), time + Offset::hours(1)),
+        ]
+        .into();
+        save_runs(&path, &runs).unwrap();
+        assert_eq!(load_runs(&path), runs);
+        fs::remove_file(&*path).unwrap();
+    }
+}
@@ -0,0 +1,628 @@
+use crate::bridge::Bridge;
+use crate::color::Rgb;
+use crate::effects::REQUEST_INTERVAL;
+use crate::lightstructs::*;
+use serde::*;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// A light or group, by numerical ID or by name
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Target {
+    Id(u8),
+    Name(String),
+}
+
+impl Target {
+    /// The ID of the light or group (`endpoint` is `lights` or `groups`)
+    pub fn resolve(&self, bridge: &Bridge, endpoint: &str) -> Result<u8, Box<dyn Error>> {
+        match self {
+            Target::Id(id) => Ok(*id),
+            Target::Name(name) => bridge.resolve(endpoint, name),
+        }
+    }
+}
+
+/// One timed step of a show. A step either changes some lights and groups, waits
+/// for a key press, or holds steps of its own that get repeated.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Step {
+    /// When the step happens, from the start of the show (or of the enclosing step)
+    pub at: f32,
+    #[serde(default)]
+    pub lights: Vec<Target>,
+    #[serde(default)]
+    pub groups: Vec<Target>,
+    /// Colour name, `#hex` or `rgb(r, g, b)`
+    pub color: Option<String>,
+    /// Any other state to send along
+    #[serde(default)]
+    pub state: SendableState,
+    /// How long the lights take to get to the new state
+    pub transition: Option<f32>,
+    /// Stop the show here until a key is pressed
+    #[serde(default)]
+    pub wait: bool,
+    /// Steps played from this step on, `repeat` times in a row
+    #[serde(default)]
+    pub steps: Vec<Step>,
+    #[serde(default = "once")]
+    pub repeat: u32,
+    /// How long one repetition of `steps` takes
+    pub length: Option<f32>,
+}
+
+/// A scripted light show, usually loaded from a YAML (or JSON) file:
+/// ```yaml
+/// bpm: 120        # times are in beats, in seconds without this
+/// loops: 2        # 0 loops forever
+/// steps:
+///   - { at: 0, groups: [Living], color: red, transition: 0.5 }
+///   - { at: 1, lights: [1, Desk], state: { on: false } }
+///   - { at: 2, wait: true }
+///   - at: 2
+///     repeat: 4
+///     length: 1
+///     steps:
+///       - { at: 0, groups: [Living], color: white }
+///       - { at: 0.5, groups: [Living], color: blue }
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Show {
+    /// Beats per minute, makes every time in the show count beats
+    pub bpm: Option<f32>,
+    /// How many times to play the show, 0 to keep looping (which takes a length above zero)
+    #[serde(default = "once")]
+    pub loops: u32,
+    /// Length of the show, the time of the last step if not set
+    pub length: Option<f32>,
+    pub steps: Vec<Step>,
+}
+
+fn once() -> u32 {
+    1
+}
+
+impl Show {
+    /// Loads a show from a file, which is read as JSON if it ends in `.json`
+    /// and as YAML otherwise
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path)?;
+        let show: Show = if path.ends_with(".json") {
+            serde_json::from_str(&text)?
+        } else {
+            serde_yaml::from_str(&text)?
+        };
+        if let Some(bpm) = show.bpm {
+            if bpm <= 0.0 {
+                return Err(format!("bpm has to be positive, not {}", bpm).into());
+            }
+        }
+        Ok(show)
+    }
+
+    /// Works out when everything in the show happens, in seconds, looking up the
+    /// lights and groups that are given by name
+    pub fn timeline(&self, bridge: &Bridge) -> Result<Timeline, Box<dyn Error>> {
+        let unit = self.bpm.map_or(1.0, |bpm| 60.0 / bpm);
+        let mut cues = Vec::new();
+        flatten(&self.steps, 0.0, unit, bridge, &mut cues)?;
+        cues.sort_by(|a: &Cue, b: &Cue| a.time.total_cmp(&b.time));
+        let length = match self.length {
+            Some(length) => length * unit,
+            None => cues.last().map_or(0.0, |cue| cue.time),
+        };
+        let timeline = Timeline {
+            cues,
+            length,
+            loops: self.loops,
+        };
+        timeline.check()?;
+        Ok(timeline)
+    }
+}
+
+/// Turns nested (and repeated) steps into cues at absolute times
+fn flatten(
+    steps: &[Step],
+    offset: f32,
+    unit: f32,
+    bridge: &Bridge,
+    cues: &mut Vec<Cue>,
+) -> Result<(), Box<dyn Error>> {
+    for step in steps {
+        let time = offset + step.at * unit;
+        if step.wait {
+            cues.push(Cue {
+                time,
+                action: Action::Wait,
+            });
+        }
+        if !step.lights.is_empty() || !step.groups.is_empty() {
+            let mut state = step.state.clone();
+            if let Some(color) = &step.color {
+                state.on = Some(true);
+                state.xy = Some(color.parse::<Rgb>()?.xy());
+            }
+            if let Some(transition) = step.transition {
+                state.transitiontime = Some((transition * unit * 10.0).round() as u16);
+            }
+            cues.push(Cue {
+                time,
+                action: Action::State {
+                    lights: resolve_all(&step.lights, bridge, "lights")?,
+                    groups: resolve_all(&step.groups, bridge, "groups")?,
+                    state,
+                },
+            });
+        }
+        if !step.steps.is_empty() {
+            let length = match (step.length, step.repeat) {
+                (Some(length), _) => length,
+                (None, 1) => 0.0,
+                (None, _) => return Err("steps that repeat need a `length`".into()),
+            };
+            for i in 0..step.repeat {
+                flatten(
+                    &step.steps,
+                    time + i as f32 * length * unit,
+                    unit,
+                    bridge,
+                    cues,
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn resolve_all(
+    targets: &[Target],
+    bridge: &Bridge,
+    endpoint: &str,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    targets
+        .iter()
+        .map(|t| t.resolve(bridge, endpoint))
+        .collect()
+}
+
+/// What happens at a point of the show
+#[derive(Debug, Clone)]
+pub enum Action {
+    State {
+        lights: Vec<u8>,
+        groups: Vec<u8>,
+        state: SendableState,
+    },
+    /// Pause until a key is pressed
+    Wait,
+}
+
+/// Something that happens at `time` seconds into the show
+#[derive(Debug, Clone)]
+pub struct Cue {
+    pub time: f32,
+    pub action: Action,
+}
+
+/// A show worked out into cues, ready to be played
+#[derive(Debug, Clone)]
+pub struct Timeline {
+    /// Ordered by time
+    pub cues: Vec<Cue>,
+    /// Length of one pass in seconds
+    pub length: f32,
+    /// How many passes to play, 0 to keep looping
+    pub loops: u32,
+}
+
+impl Timeline {
+    /// Makes sure the timeline can be played, a pass of no length can't be looped
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        if self.loops == 0 && self.length <= 0.0 {
+            return Err("a show that loops forever needs a length above zero".into());
+        }
+        Ok(())
+    }
+}
+
+/// Ways to steer a show while it plays
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Control {
+    Pause,
+    Resume,
+    /// Pauses when playing and resumes when paused
+    Toggle,
+    /// Jumps to a time (in seconds) in the current pass
+    Seek(f32),
+    /// Carries on after a `wait` step
+    Key,
+    Stop,
+}
+
+/// Plays a timeline against the bridge.
+///
+/// ```no_run
+/// # use huemanity::bridge::Bridge;
+/// # use huemanity::sequence::*;
+/// let bridge = Bridge::link();
+/// let timeline = Show::load("show.yaml").unwrap().timeline(&bridge).unwrap();
+/// let (controls, receiver) = std::sync::mpsc::channel();
+/// std::thread::spawn(move || {
+///     std::thread::sleep(std::time::Duration::from_secs(60));
+///     controls.send(Control::Stop).unwrap();
+/// });
+/// Player::new(&bridge, timeline).run(&receiver).unwrap();
+/// ```
+pub struct Player<'a> {
+    bridge: &'a Bridge,
+    timeline: Timeline,
+    /// Index of the next cue to fire
+    next: usize,
+    /// How many passes have been played in full
+    pass: u32,
+    /// Position when the clock was last started or stopped
+    offset: f32,
+    /// When the clock was started, `None` while paused
+    started: Option<Instant>,
+    /// Paused on a `wait` step
+    waiting: bool,
+    /// When the last request went to the bridge
+    last_request: Option<Instant>,
+}
+
+impl<'a> Player<'a> {
+    /// Makes a player, paused at the start of the show
+    pub fn new(bridge: &'a Bridge, timeline: Timeline) -> Self {
+        Player {
+            bridge,
+            timeline,
+            next: 0,
+            pass: 0,
+            offset: 0.0,
+            started: None,
+            waiting: false,
+            last_request: None,
+        }
+    }
+
+    /// Seconds into the current pass
+    pub fn position(&self) -> f32 {
+        self.offset + self.started.map_or(0.0, |t| t.elapsed().as_secs_f32())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.started.is_none()
+    }
+
+    pub fn pause(&mut self) {
+        self.offset = self.position();
+        self.started = None;
+    }
+
+    pub fn resume(&mut self) {
+        if self.started.is_none() {
+            self.started = Some(Instant::now());
+        }
+        self.waiting = false;
+    }
+
+    /// Jumps to `time` seconds into the current pass. The lights are set to where the
+    /// show would have left them, so seeking into the middle of a scene works.
+    pub fn seek(&mut self, time: f32) -> Result<(), Box<dyn Error>> {
+        let time = time.clamp(0.0, self.timeline.length);
+        self.offset = time;
+        if self.started.is_some() {
+            self.started = Some(Instant::now());
+        }
+        self.next = self
+            .timeline
+            .cues
+            .iter()
+            .position(|cue| cue.time >= time)
+            .unwrap_or(self.timeline.cues.len());
+
+        let mut latest: BTreeMap<(bool, u8), &SendableState> = BTreeMap::new();
+        for cue in &self.timeline.cues[..self.next] {
+            if let Action::State {
+                lights,
+                groups,
+                state,
+            } = &cue.action
+            {
+                for light in lights {
+                    latest.insert((false, *light), state);
+                }
+                for group in groups {
+                    latest.insert((true, *group), state);
+                }
+            }
+        }
+        let latest: Vec<((bool, u8), SendableState)> = latest
+            .into_iter()
+            .map(|(target, state)| (target, state.clone()))
+            .collect();
+        for ((group, id), state) in latest {
+            self.send(group, id, &state)?;
+        }
+        Ok(())
+    }
+
+    /// Plays the show until it is over or told to stop, taking controls from
+    /// `controls` as they come in
+    pub fn run(&mut self, controls: &Receiver<Control>) -> Result<(), Box<dyn Error>> {
+        self.timeline.check()?;
+        self.resume();
+        let mut connected = true;
+        loop {
+            self.fire()?;
+            if !self.waiting && self.next >= self.timeline.cues.len() {
+                let overshoot = self.position() - self.timeline.length;
+                if overshoot >= 0.0 {
+                    self.pass += 1;
+                    if self.timeline.loops != 0 && self.pass >= self.timeline.loops {
+                        return Ok(());
+                    }
+                    self.next = 0;
+                    self.offset = overshoot;
+                    self.started = self.started.map(|_| Instant::now());
+                    continue;
+                }
+            }
+
+            // sleep until the next cue is due, or forever while paused
+            let due = match self.timeline.cues.get(self.next) {
+                Some(cue) => cue.time,
+                None => self.timeline.length,
+            };
+            let timeout = match self.started {
+                Some(_) => Duration::from_secs_f32((due - self.position()).max(0.0)),
+                None => Duration::from_secs(3600),
+            };
+            if !connected {
+                if self.is_paused() {
+                    return Err("the show is paused and nothing is left to resume it".into());
+                }
+                sleep(timeout);
+                continue;
+            }
+            match controls.recv_timeout(timeout) {
+                Ok(Control::Pause) => self.pause(),
+                Ok(Control::Resume) => self.resume(),
+                Ok(Control::Toggle) if self.is_paused() => self.resume(),
+                Ok(Control::Toggle) => self.pause(),
+                Ok(Control::Seek(time)) => self.seek(time)?,
+                Ok(Control::Key) if self.waiting => self.resume(),
+                Ok(Control::Key) => (),
+                Ok(Control::Stop) => return Ok(()),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => connected = false,
+            }
+        }
+    }
+
+    /// Sends a state to a light or group, spaced out by `REQUEST_INTERVAL` from
+    /// the previous request
+    fn send(&mut self, group: bool, id: u8, state: &SendableState) -> Result<(), Box<dyn Error>> {
+        if let Some(wait) = self
+            .last_request
+            .and_then(|t| REQUEST_INTERVAL.checked_sub(t.elapsed()))
+        {
+            sleep(wait);
+        }
+        self.last_request = Some(Instant::now());
+        if group {
+            self.bridge.group_state(id, state)?;
+        } else {
+            self.bridge.state(id, state)?;
+        }
+        Ok(())
+    }
+
+    /// Carries out every cue that is due
+    fn fire(&mut self) -> Result<(), Box<dyn Error>> {
+        let position = self.position();
+        while let Some(cue) = self.timeline.cues.get(self.next) {
+            if cue.time > position || self.waiting {
+                break;
+            }
+            self.next += 1;
+            let time = cue.time;
+            match cue.action.clone() {
+                Action::State {
+                    lights,
+                    groups,
+                    state,
+                } => {
+                    for light in lights {
+                        self.send(false, light, &state)?;
+                    }
+                    for group in groups {
+                        self.send(true, group, &state)?;
+                    }
+                }
+                Action::Wait => {
+                    // pick up from the wait step, not from when the key gets pressed
+                    self.pause();
+                    self.offset = time;
+                    self.waiting = true;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::Topology;
+    use serde_json::json;
+
+    fn bridge() -> Bridge {
+        Bridge::rehearsal(Topology {
+            bridge: "127.0.0.1".to_owned(),
+            lights: [(
+                1,
+                json!({ "name": "Desk", "type": "Extended color light", "state": { "on": true } }),
+            )]
+            .into(),
+            groups: [(1, json!({ "name": "Living", "lights": ["1"] }))].into(),
+            ..Topology::default()
+        })
+    }
+
+    fn show(yaml: &str) -> Show {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    /// The show from the `Show` docs
+    const SHOW: &str = "
+bpm: 120
+loops: 2
+steps:
+  - { at: 0, groups: [Living], color: red, transition: 0.5 }
+  - { at: 1, lights: [1, Desk], state: { on: false } }
+  - { at: 2, wait: true }
+  - at: 2
+    repeat: 4
+    length: 1
+    steps:
+      - { at: 0, groups: [Living], color: white }
+      - { at: 0.5, groups: [Living], color: blue }
+";
+
+    #[test]
+    fn works_out_the_timeline() {
+        let timeline = show(SHOW).timeline(&bridge()).unwrap();
+        let times: Vec<f32> = timeline.cues.iter().map(|cue| cue.time).collect();
+        assert_eq!(
+            times,
+            [0.0, 0.5, 1.0, 1.0, 1.25, 1.5, 1.75, 2.0, 2.25, 2.5, 2.75]
+        );
+        // no length, so the show ends on its last cue
+        assert_eq!(timeline.length, 2.75);
+        assert_eq!(timeline.loops, 2);
+
+        match &timeline.cues[0].action {
+            Action::State {
+                lights,
+                groups,
+                state,
+            } => {
+                assert!(lights.is_empty());
+                assert_eq!(groups, &[1]);
+                assert_eq!(state.on, Some(true));
+                assert_eq!(state.xy, Some(Rgb::new(255, 0, 0).xy()));
+                // half a beat at 120 bpm
+                assert_eq!(state.transitiontime, Some(3));
+            }
+            action => panic!("{:?}", action),
+        }
+        match &timeline.cues[1].action {
+            Action::State { lights, state, .. } => {
+                assert_eq!(lights, &[1, 1]);
+                assert_eq!(state.on, Some(false));
+            }
+            action => panic!("{:?}", action),
+        }
+        assert!(timeline
+            .cues
+            .iter()
+            .any(|cue| cue.time == 1.0 && matches!(cue.action, Action::Wait)));
+    }
+
+    #[test]
+    fn counts_in_seconds_without_a_bpm() {
+        let timeline = show("{ length: 4, steps: [{ at: 1, lights: [1], color: red }] }")
+            .timeline(&bridge())
+            .unwrap();
+        assert_eq!(timeline.cues[0].time, 1.0);
+        assert_eq!(timeline.length, 4.0);
+    }
+
+    #[test]
+    fn refuses_shows_that_cannot_be_played() {
+        let bridge = bridge();
+        let fails = |yaml: &str| show(yaml).timeline(&bridge).is_err();
+        // repeating steps need to know how long a repetition is
+        assert!(fails(
+            "{ steps: [{ at: 0, repeat: 2, steps: [{ at: 0, lights: [1] }] }] }"
+        ));
+        assert!(fails("{ steps: [{ at: 0, lights: [Attic] }] }"));
+        assert!(fails(
+            "{ steps: [{ at: 0, lights: [1], color: mauve-ish }] }"
+        ));
+        assert!(fails("{ loops: 0, steps: [{ at: 0, lights: [1] }] }"));
+        assert!(!fails(
+            "{ loops: 0, length: 1, steps: [{ at: 0, lights: [1] }] }"
+        ));
+    }
+
+    #[test]
+    fn seeks_and_pauses() {
+        let bridge = bridge();
+        let timeline = show(SHOW).timeline(&bridge).unwrap();
+        let mut player = Player::new(&bridge, timeline);
+        assert!(player.is_paused());
+        assert_eq!(player.position(), 0.0);
+
+        player.seek(1.3).unwrap();
+        assert!(player.is_paused());
+        assert_eq!(player.position(), 1.3);
+        // the next cue is the one at 1.5
+        assert_eq!(player.next, 5);
+
+        player.seek(100.0).unwrap();
+        assert_eq!(player.position(), 2.75);
+        // the last cue is right at the end and still to come
+        assert_eq!(player.next, 10);
+        player.seek(-1.0).unwrap();
+        assert_eq!(player.position(), 0.0);
+        assert_eq!(player.next, 0);
+
+        player.resume();
+        sleep(Duration::from_millis(50));
+        player.pause();
+        let paused_at = player.position();
+        assert!(paused_at >= 0.05);
+        sleep(Duration::from_millis(50));
+        assert_eq!(player.position(), paused_at);
+    }
+
+    #[test]
+    fn stops_on_waits_until_a_key() {
+        let bridge = bridge();
+        let timeline = show("{ steps: [{ at: 0, wait: true }, { at: 0, lights: [1] }] }")
+            .timeline(&bridge)
+            .unwrap();
+        let mut player = Player::new(&bridge, timeline);
+        player.resume();
+        player.fire().unwrap();
+        assert!(player.waiting);
+        assert!(player.is_paused());
+        assert_eq!(player.next, 1);
+
+        player.resume();
+        player.fire().unwrap();
+        assert_eq!(player.next, 2);
+    }
+
+    #[test]
+    fn plays_to_the_end() {
+        let bridge = bridge();
+        let timeline = show("{ loops: 2, length: 0.1, steps: [{ at: 0, lights: [1] }] }")
+            .timeline(&bridge)
+            .unwrap();
+        let (_controls, receiver) = std::sync::mpsc::channel();
+        let started = Instant::now();
+        Player::new(&bridge, timeline).run(&receiver).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,1160 @@
+use crate::bridge::Bridge;
+use crate::color::{kelvin_to_mired, mired_to_kelvin, Rgb};
+use crate::effects::{Effect, Random};
+use crate::error::HueError;
+use crate::lightstructs::*;
+use crate::watcher::Watcher;
+use serde::*;
+use serde_json::value::Value;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+use tracing::warn;
+use tungstenite::handshake::server::{ErrorResponse, Request as Handshake, Response};
+use tungstenite::Message;
+
+/// Largest request body the server reads
+const MAX_BODY: usize = 1 << 20;
+
+/// How long a websocket waits for messages from the client before passing on events
+const WEBSOCKET_POLL: Duration = Duration::from_millis(100);
+
+/// Every route the server answers, used for routing as well as for the OpenAPI
+/// document: method, path, summary and the schema of the request body
+pub const ROUTES: &[(&str, &str, &str, Option<&str>)] = &[
+    ("get", "/lights", "List the lights", None),
+    ("get", "/lights/{name}", "Get a light by name or ID", None),
+    ("put", "/lights/{name}", "Change a light", Some("State")),
+    (
+        "post",
+        "/lights/{name}/effect",
+        "Start an effect on a light",
+        Some("Effect"),
+    ),
+    (
+        "delete",
+        "/lights/{name}/effect",
+        "Stop the effect on a light",
+        None,
+    ),
+    ("get", "/groups", "List the groups", None),
+    ("get", "/groups/{name}", "Get a group by name or ID", None),
+    (
+        "put",
+        "/groups/{name}",
+        "Change all lights of a group",
+        Some("State"),
+    ),
+    (
+        "post",
+        "/groups/{name}/effect",
+        "Start an effect on the lights of a group",
+        Some("Effect"),
+    ),
+    (
+        "delete",
+        "/groups/{name}/effect",
+        "Stop the effect on a group",
+        None,
+    ),
+    ("get", "/scenes", "List the scenes", None),
+    (
+        "post",
+        "/scenes/{name}/recall",
+        "Recall a scene",
+        Some("Recall"),
+    ),
+    ("get", "/openapi.json", "This document", None),
+    (
+        "get",
+        "/ws",
+        "WebSocket with live events, also takes commands",
+        None,
+    ),
+];
+
+/// A change to a light or group, in friendlier terms than the bridge's
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StateRequest {
+    pub on: Option<bool>,
+    /// Percent, 0 to 100
+    pub brightness: Option<f32>,
+    /// Colour name, `#hex` or `rgb(r, g, b)`
+    pub color: Option<String>,
+    /// Colour temperature in Kelvin
+    pub kelvin: Option<u32>,
+    /// Seconds to get to the new state
+    pub transition: Option<f32>,
+    /// `select` blinks once, `lselect` for 15 seconds
+    pub alert: Option<String>,
+    /// `colorloop` cycles through the colours until `none`
+    pub effect: Option<String>,
+}
+
+impl StateRequest {
+    /// The state to send to the bridge
+    pub fn sendable(&self) -> Result<SendableState, String> {
+        self.apply(SendableState::default())
+    }
+
+    /// Overrides the parts of `state` this request sets
+    pub fn apply(&self, mut state: SendableState) -> Result<SendableState, String> {
+        if let Some(on) = self.on {
+            state.on = Some(on);
+        }
+        if let Some(alert) = &self.alert {
+            if !["none", "select", "lselect"].contains(&alert.as_str()) {
+                return Err(format!(
+                    "`{}` is not an alert, try select, lselect or none",
+                    alert
+                ));
+            }
+            state.alert = Some(alert.clone());
+        }
+        if let Some(effect) = &self.effect {
+            if !["none", "colorloop"].contains(&effect.as_str()) {
+                return Err(format!(
+                    "`{}` is not an effect the bridge runs, try colorloop or none",
+                    effect
+                ));
+            }
+            state.effect = Some(effect.clone());
+        }
+        if let Some(brightness) = self.brightness {
+            if !(0.0..=100.0).contains(&brightness) {
+                return Err(format!(
+                    "brightness is in percent, {} is out of range",
+                    brightness
+                ));
+            }
+            state.bri = Some((brightness * 2.54).round().max(1.0) as u8);
+        }
+        if let Some(color) = &self.color {
+            state.xy = Some(color.parse::<Rgb>()?.xy());
+        }
+        if let Some(kelvin) = self.kelvin {
+            state.ct = Some(kelvin_to_mired(kelvin));
+        }
+        if let Some(transition) = self.transition {
+            state.transitiontime = Some((transition.max(0.0) * 10.0).round() as u16);
+        }
+        Ok(state)
+    }
+}
+
+/// An effect to start on some lights
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EffectRequest {
+    /// One of the names in `effects::NAMES`
+    pub effect: String,
+    pub color: Option<String>,
+    /// Length of the effect or of one cycle, depending on the effect
+    pub seconds: Option<f32>,
+    /// Stop after this many seconds, effects that loop run until stopped otherwise
+    pub duration: Option<f32>,
+}
+
+/// A state command sent over the websocket, like `{"light": "Desk", "color": "red"}`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Command {
+    pub light: Option<String>,
+    pub group: Option<String>,
+    #[serde(flatten)]
+    pub state: StateRequest,
+}
+
+/// Which group to recall a scene on, all lights if not given
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RecallRequest {
+    pub group: Option<String>,
+}
+
+/// A light as the server shows it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LightView {
+    pub id: u8,
+    pub name: String,
+    pub on: bool,
+    pub brightness: Option<u8>,
+    pub kelvin: Option<u32>,
+    pub xy: Option<[f32; 2]>,
+    pub reachable: bool,
+}
+
+impl LightView {
+    pub fn new(id: u8, light: &Value) -> Self {
+        let state = &light["state"];
+        let mode = state["colormode"].as_str();
+        LightView {
+            id,
+            name: light["name"].as_str().unwrap_or_default().to_owned(),
+            on: state["on"].as_bool().unwrap_or_default(),
+            brightness: state["bri"]
+                .as_u64()
+                .map(|bri| (bri as f32 / 2.54).round() as u8),
+            kelvin: state["ct"]
+                .as_u64()
+                .filter(|_| mode == Some("ct"))
+                .map(|ct| mired_to_kelvin(ct as u16)),
+            xy: serde_json::from_value(state["xy"].clone())
+                .ok()
+                .filter(|_| mode == Some("xy")),
+            reachable: state["reachable"].as_bool().unwrap_or(true),
+        }
+    }
+}
+
+/// A group as the server shows it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GroupView {
+    pub id: u8,
+    pub name: String,
+    pub r#type: String,
+    pub lights: Vec<u8>,
+    pub any_on: bool,
+    pub all_on: bool,
+}
+
+impl GroupView {
+    fn new(id: u8, group: &Value) -> Self {
+        GroupView {
+            id,
+            name: group["name"].as_str().unwrap_or_default().to_owned(),
+            r#type: group["type"].as_str().unwrap_or_default().to_owned(),
+            lights: serde_json::from_value::<Vec<String>>(group["lights"].clone())
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|id| id.parse().ok())
+                .collect(),
+            any_on: group["state"]["any_on"].as_bool().unwrap_or_default(),
+            all_on: group["state"]["all_on"].as_bool().unwrap_or_default(),
+        }
+    }
+}
+
+/// A scene as the server shows it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SceneView {
+    pub id: String,
+    pub name: String,
+    pub group: Option<String>,
+}
+
+/// An error with the HTTP status it is answered with
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl ApiError {
+    fn new(status: u16, message: &str) -> Self {
+        ApiError {
+            status,
+            message: message.to_owned(),
+        }
+    }
+}
+
+impl From<Box<dyn Error>> for ApiError {
+    fn from(e: Box<dyn Error>) -> Self {
+        let status = match e.downcast_ref::<HueError>() {
+            Some(HueError::UnknownName { .. }) => 404,
+            Some(HueError::Api { .. }) => 400,
+            _ => 502,
+        };
+        ApiError::new(status, &e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> Self {
+        ApiError::new(400, &format!("invalid request body: {}", e))
+    }
+}
+
+impl From<String> for ApiError {
+    fn from(message: String) -> Self {
+        ApiError::new(400, &message)
+    }
+}
+
+type Reply = Result<(u16, Value), ApiError>;
+
+/// A small HTTP server in front of the bridge, with lights, groups and scenes
+/// addressed by name and states given in percent, colour names and Kelvin.
+/// Every request but `/openapi.json` needs an `Authorization: Bearer <token>` header.
+///
+/// `/ws` upgrades to a websocket that pushes every change to the lights and sensors
+/// as JSON, and takes `Command`s. Browsers can't set headers on websockets, so the
+/// token can be given as `/ws?token=<token>` as well.
+///
+/// ```no_run
+/// # use huemanity::bridge::Bridge;
+/// # use huemanity::server::Server;
+/// let server = Server::new(Bridge::link(), "secret".to_owned());
+/// server.serve("127.0.0.1:8080").unwrap();
+/// ```
+pub struct Server {
+    bridge: Arc<Bridge>,
+    token: String,
+    /// Watch for changes by polling rather than through the event stream (for v1 bridges)
+    pub poll: bool,
+    /// Stop flags of the running effects, by the path they were started on
+    effects: Mutex<BTreeMap<String, Arc<AtomicBool>>>,
+    /// Websockets waiting for events
+    subscribers: Arc<Mutex<Vec<Sender<String>>>>,
+    /// Whether events are being collected for the websockets
+    watching: Arc<AtomicBool>,
+}
+
+/// The parts of a request the server looks at
+pub(crate) struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl HttpRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl Server {
+    pub fn new(bridge: Bridge, token: String) -> Self {
+        Server {
+            bridge: Arc::new(bridge),
+            token,
+            poll: false,
+            effects: Mutex::new(BTreeMap::new()),
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            watching: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Makes up a token for when none is configured
+    pub fn generate_token() -> String {
+        let mut random = Random::new();
+        (0..32).map(|_| format!("{:x}", random.below(16))).collect()
+    }
+
+    pub fn bridge(&self) -> &Bridge {
+        &self.bridge
+    }
+
+    /// Answers requests on `address` (like `127.0.0.1:8080`), each connection on
+    /// its own thread, forever
+    pub fn serve(&self, address: &str) -> Result<(), Box<dyn Error>> {
+        self.accept(TcpListener::bind(address)?)
+    }
+
+    /// Answers the connections that come in on `listener`
+    fn accept(&self, listener: TcpListener) -> Result<(), Box<dyn Error>> {
+        thread::scope(|scope| {
+            for stream in listener.incoming() {
+                // a connection that went away before it was accepted, or running out
+                // of file descriptors for a moment, is no reason to stop serving
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("Could not accept a connection: {}", e);
+                        continue;
+                    }
+                };
+                scope.spawn(move || {
+                    let _ = self.connection(stream);
+                });
+            }
+            Ok(())
+        })
+    }
+
+    fn connection(&self, mut stream: TcpStream) -> Result<(), Box<dyn Error>> {
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        if is_websocket(&stream)? {
+            return self.websocket(stream);
+        }
+        let reply = read_request(&mut stream).and_then(|request| self.route(&request));
+        let (status, body) = match reply {
+            Ok(reply) => reply,
+            Err(e) => (e.status, serde_json::json!({ "error": e.message })),
+        };
+        respond(&mut stream, status, "application/json", &body.to_string())?;
+        Ok(())
+    }
+
+    fn authorised(&self, request: &HttpRequest) -> bool {
+        request
+            .header("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            == Some(self.token.as_str())
+    }
+
+    fn route(&self, request: &HttpRequest) -> Reply {
+        let parts: Vec<String> = request
+            .path
+            .split('/')
+            .filter(|part| !part.is_empty())
+            .map(percent_decode)
+            .collect();
+        let parts: Vec<&str> = parts.iter().map(String::as_str).collect();
+        let method = request.method.as_str();
+        let body = &request.body;
+
+        if method == "GET" && parts == ["openapi.json"] {
+            return Ok((200, openapi()));
+        }
+        if !self.authorised(request) {
+            return Err(ApiError::new(401, "missing or wrong bearer token"));
+        }
+
+        match (method, parts.as_slice()) {
+            ("GET", ["lights"]) => self.lights(),
+            ("GET", ["lights", name]) => {
+                let id = self.bridge.resolve("lights", name)?;
+                let light: Value = self.bridge.fetch(&format!("lights/{}", id))?;
+                Ok((200, serde_json::to_value(LightView::new(id, &light))?))
+            }
+            ("PUT", ["lights", name]) => {
+                let id = self.bridge.resolve("lights", name)?;
+                let state = parse::<StateRequest>(body)?.sendable()?;
+                self.bridge.state(id, &state)?;
+                Ok((200, serde_json::to_value(state)?))
+            }
+            ("GET", ["groups"]) => self.groups(),
+            ("GET", ["groups", name]) => {
+                let id = self.bridge.resolve("groups", name)?;
+                let group: Value = self.bridge.fetch(&format!("groups/{}", id))?;
+                Ok((200, serde_json::to_value(GroupView::new(id, &group))?))
+            }
+            ("PUT", ["groups", name]) => {
+                let id = self.bridge.resolve("groups", name)?;
+                let state = parse::<StateRequest>(body)?.sendable()?;
+                self.bridge.group_state(id, &state)?;
+                Ok((200, serde_json::to_value(state)?))
+            }
+            ("POST", [kind @ ("lights" | "groups"), name, "effect"]) => {
+                self.start_effect(kind, name, parse(body)?)
+            }
+            ("DELETE", [kind @ ("lights" | "groups"), name, "effect"]) => {
+                self.stop_effect(kind, name)
+            }
+            ("GET", ["scenes"]) => self.scenes(),
+            ("POST", ["scenes", name, "recall"]) => {
+                let recall: RecallRequest = parse(body)?;
+                let group = match &recall.group {
+                    Some(group) => self.bridge.resolve("groups", group)?,
+                    None => 0,
+                };
+                self.bridge.recall_scene(group, name)?;
+                Ok((200, serde_json::json!({ "recalled": name, "group": group })))
+            }
+            _ if ROUTES.iter().any(|(_, route, _, _)| matches(route, &parts)) => {
+                Err(ApiError::new(405, "method not allowed"))
+            }
+            _ => Err(ApiError::new(404, "no such endpoint, see /openapi.json")),
+        }
+    }
+
+    fn lights(&self) -> Reply {
+        let lights: BTreeMap<u8, Value> = self.bridge.fetch("lights")?;
+        let lights: Vec<LightView> = lights
+            .iter()
+            .map(|(id, light)| LightView::new(*id, light))
+            .collect();
+        Ok((200, serde_json::to_value(lights)?))
+    }
+
+    fn groups(&self) -> Reply {
+        let groups: BTreeMap<u8, Value> = self.bridge.fetch("groups")?;
+        let groups: Vec<GroupView> = groups
+            .iter()
+            .map(|(id, group)| GroupView::new(*id, group))
+            .collect();
+        Ok((200, serde_json::to_value(groups)?))
+    }
+
+    fn scenes(&self) -> Reply {
+        let scenes: BTreeMap<String, Value> = self.bridge.fetch("scenes")?;
+        let scenes: Vec<SceneView> = scenes
+            .iter()
+            .map(|(id, scene)| SceneView {
+                id: id.to_owned(),
+                name: scene["name"].as_str().unwrap_or_default().to_owned(),
+                group: scene["group"].as_str().map(str::to_owned),
+            })
+            .collect();
+        Ok((200, serde_json::to_value(scenes)?))
+    }
+
+    /// Starts an effect in the background, replacing any effect already running
+    /// on the same light or group
+    fn start_effect(&self, kind: &str, name: &str, request: EffectRequest) -> Reply {
+        let id = self.bridge.resolve(kind, name)?;
+        let lights = match kind {
+            "lights" => vec![id],
+            _ => GroupView::new(id, &self.bridge.fetch(&format!("groups/{}", id))?).lights,
+        };
+        let color = request
+            .color
+            .as_deref()
+            .map(str::parse::<Rgb>)
+            .transpose()?;
+        let effect = Effect::named(&request.effect, color, request.seconds)?;
+
+        let key = format!("{}/{}", kind, id);
+        let stop = Arc::new(AtomicBool::new(false));
+        if let Some(previous) = self.effects_lock().insert(key, stop.clone()) {
+            previous.store(true, Ordering::Relaxed);
+        }
+        if let Some(duration) = request.duration {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_secs_f32(duration.max(0.0)));
+                stop.store(true, Ordering::Relaxed);
+            });
+        }
+        let bridge = self.bridge.clone();
+        let started = lights.clone();
+        thread::spawn(move || {
+            let _ = effect.run(&bridge, &started, &stop);
+        });
+        Ok((
+            202,
+            serde_json::json!({ "started": request.effect, "lights": lights }),
+        ))
+    }
+
+    fn stop_effect(&self, kind: &str, name: &str) -> Reply {
+        let id = self.bridge.resolve(kind, name)?;
+        match self.effects_lock().remove(&format!("{}/{}", kind, id)) {
+            Some(stop) => {
+                stop.store(true, Ordering::Relaxed);
+                Ok((200, serde_json::json!({ "stopped": true })))
+            }
+            None => Err(ApiError::new(404, "no effect is running there")),
+        }
+    }
+
+    fn effects_lock(&self) -> MutexGuard<'_, BTreeMap<String, Arc<AtomicBool>>> {
+        // a panic while holding the lock leaves the map as usable as it was
+        self.effects.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Carries out a command sent over a websocket
+    fn command(&self, text: &str) -> Result<Value, ApiError> {
+        let command: Command = serde_json::from_str(text)?;
+        let state = command.state.sendable()?;
+        match (&command.light, &command.group) {
+            (Some(light), None) => self
+                .bridge
+                .state(self.bridge.resolve("lights", light)?, &state)?,
+            (None, Some(group)) => self
+                .bridge
+                .group_state(self.bridge.resolve("groups", group)?, &state)?,
+            _ => {
+                return Err(ApiError::new(
+                    400,
+                    "a command needs either `light` or `group`",
+                ))
+            }
+        }
+        Ok(serde_json::json!({ "ok": serde_json::to_value(state)? }))
+    }
+
+    /// Talks to a websocket client until it goes away: events are pushed as they
+    /// come and every command is answered with the state sent or an error
+    fn websocket(&self, stream: TcpStream) -> Result<(), Box<dyn Error>> {
+        let token = self.token.clone();
+        // tungstenite decides the type of the refusal
+        #[allow(clippy::result_large_err)]
+        let check =
+            move |request: &Handshake, response: Response| -> Result<Response, ErrorResponse> {
+                let query_token = request
+                    .uri()
+                    .query()
+                    .into_iter()
+                    .flat_map(|q| q.split('&'))
+                    .any(|pair| {
+                        pair.strip_prefix("token=").map(percent_decode).as_deref()
+                            == Some(token.as_str())
+                    });
+                let header_token = request
+                    .headers()
+                    .get("Authorization")
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.strip_prefix("Bearer "))
+                    == Some(token.as_str());
+                if query_token || header_token {
+                    Ok(response)
+                } else {
+                    let mut refusal = ErrorResponse::new(Some("missing or wrong token".to_owned()));
+                    *refusal.status_mut() = tungstenite::http::StatusCode::UNAUTHORIZED;
+                    Err(refusal)
+                }
+            };
+        let mut socket = tungstenite::accept_hdr(stream, check).map_err(|e| e.to_string())?;
+        socket.get_ref().set_read_timeout(Some(WEBSOCKET_POLL))?;
+
+        let (sender, events) = channel();
+        self.subscribers_lock().push(sender);
+        self.watch();
+        loop {
+            match socket.read() {
+                Ok(Message::Text(text)) => {
+                    let reply = self
+                        .command(&text)
+                        .unwrap_or_else(|e| serde_json::json!({ "error": e.message }));
+                    socket.send(Message::Text(reply.to_string()))?;
+                }
+                Ok(Message::Close(_)) => return Ok(()),
+                Ok(_) => (),
+                Err(tungstenite::Error::Io(e))
+                    if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+                Err(e) => return Err(e.into()),
+            }
+            for event in events.try_iter() {
+                socket.send(Message::Text(event))?;
+            }
+        }
+    }
+
+    fn subscribers_lock(&self) -> MutexGuard<'_, Vec<Sender<String>>> {
+        self.subscribers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Starts passing changes on to the websockets, unless that is going on already.
+    /// Changes come from the event stream, or the watcher when `poll` is set.
+    fn watch(&self) {
+        if self.watching.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let (bridge, subscribers, watching) = (
+            self.bridge.clone(),
+            self.subscribers.clone(),
+            self.watching.clone(),
+        );
+        let poll = self.poll;
+        thread::spawn(move || {
+            let broadcast = |event: Value| {
+                let text = event.to_string();
+                let mut subscribers = subscribers.lock().unwrap_or_else(|e| e.into_inner());
+                subscribers.retain(|subscriber| subscriber.send(text.clone()).is_ok());
+            };
+            let stopped = if poll {
+                Watcher::new(&bridge)
+                    .run(|change| broadcast(serde_json::to_value(change).unwrap_or_default()))
+                    .err()
+            } else {
+                match bridge.clip().and_then(|clip| clip.events()) {
+                    Ok(events) => {
+                        for message in events {
+                            broadcast(serde_json::to_value(message).unwrap_or_default());
+                        }
+                        None
+                    }
+                    Err(e) => Some(e),
+                }
+            };
+            if let Some(e) = stopped {
+                broadcast(serde_json::json!({ "error": format!("stopped watching: {}", e) }));
+            }
+            // the next websocket to connect starts watching again
+            watching.store(false, Ordering::SeqCst);
+        });
+    }
+}
+
+/// Whether the connection asks for the websocket, without taking anything off it
+fn is_websocket(stream: &TcpStream) -> Result<bool, Box<dyn Error>> {
+    let mut start = [0; 8];
+    let mut tries = 0;
+    loop {
+        let read = stream.peek(&mut start)?;
+        if read == start.len() || read == 0 || tries > 100 {
+            return Ok(&start[..read] == b"GET /ws " || &start[..read] == b"GET /ws?");
+        }
+        tries += 1;
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Reads the request line, headers and body
+pub(crate) fn read_request(stream: &mut TcpStream) -> Result<HttpRequest, ApiError> {
+    let bad = |e: std::io::Error| ApiError::new(400, &e.to_string());
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(bad)?;
+    let mut words = line.split_whitespace();
+    let (method, target) = match (words.next(), words.next()) {
+        (Some(method), Some(target)) => (method.to_owned(), target.to_owned()),
+        _ => return Err(ApiError::new(400, "malformed request line")),
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line).map_err(bad)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((field, value)) = header.split_once(':') {
+            headers.push((field.trim().to_owned(), value.trim().to_owned()));
+        }
+        if headers.len() > 100 {
+            return Err(ApiError::new(431, "too many headers"));
+        }
+    }
+    let mut request = HttpRequest {
+        method,
+        path: target.split('?').next().unwrap_or_default().to_owned(),
+        headers,
+        body: String::new(),
+    };
+
+    let length: usize = request
+        .header("Content-Length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    if length > MAX_BODY {
+        return Err(ApiError::new(413, "request body too large"));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).map_err(bad)?;
+    request.body = String::from_utf8(body).map_err(|e| ApiError::new(400, &e.to_string()))?;
+    Ok(request)
+}
+
+/// Writes a whole response and closes the connection
+pub(crate) fn respond(
+    stream: &mut TcpStream,
+    status: u16,
+    content_type: &str,
+    body: &str,
+) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason(status),
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+/// Reason phrases of the status codes the server answers with
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        502 => "Bad Gateway",
+        _ => "",
+    }
+}
+
+fn parse<T: de::DeserializeOwned + Default>(body: &str) -> Result<T, ApiError> {
+    if body.trim().is_empty() {
+        return Ok(T::default());
+    }
+    Ok(serde_json::from_str(body)?)
+}
+
+/// Whether a route like `/lights/{name}` matches the path
+fn matches(route: &str, parts: &[&str]) -> bool {
+    let route: Vec<&str> = route.split('/').filter(|part| !part.is_empty()).collect();
+    route.len() == parts.len()
+        && route
+            .iter()
+            .zip(parts)
+            .all(|(route, part)| route.starts_with('{') || route == part)
+}
+
+/// Undoes the `%20`s in names taken from the path
+fn percent_decode(part: &str) -> String {
+    let bytes = part.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = part
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Builds the OpenAPI document out of `ROUTES`
+pub fn openapi() -> Value {
+    let mut paths = serde_json::Map::new();
+    for (method, path, summary, body) in ROUTES {
+        let mut operation = serde_json::json!({
+            "summary": summary,
+            "responses": {
+                "200": { "description": "Success" },
+                "401": { "description": "Missing or wrong bearer token" },
+                "404": { "description": "Unknown light, group or scene" },
+            },
+        });
+        if path.contains("{name}") {
+            operation["parameters"] = serde_json::json!([{
+                "name": "name",
+                "in": "path",
+                "required": true,
+                "description": "Name (ignoring case) or numerical ID",
+                "schema": { "type": "string" },
+            }]);
+        }
+        if let Some(body) = body {
+            operation["requestBody"] = serde_json::json!({
+                "content": { "application/json": {
+                    "schema": { "$ref": format!("#/components/schemas/{}", body) },
+                } },
+            });
+        }
+        if *path == "/openapi.json" {
+            operation["security"] = serde_json::json!([]);
+        }
+        let entry = paths
+            .entry(path.to_string())
+            .or_insert_with(|| serde_json::json!({}));
+        entry[*method] = operation;
+    }
+
+    serde_json::json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "huemanity",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "A simpler API in front of a Philips Hue bridge",
+        },
+        "paths": paths,
+        "security": [{ "token": [] }],
+        "components": {
+            "securitySchemes": { "token": { "type": "http", "scheme": "bearer" } },
+            "schemas": {
+                "State": { "type": "object", "properties": {
+                    "on": { "type": "boolean" },
+                    "brightness": { "type": "number", "minimum": 0, "maximum": 100 },
+                    "color": { "type": "string", "description": "Colour name, #hex or rgb(r, g, b)" },
+                    "kelvin": { "type": "integer", "minimum": 2000, "maximum": 6500 },
+                    "transition": { "type": "number", "description": "Seconds" },
+                    "alert": { "type": "string", "enum": ["none", "select", "lselect"] },
+                    "effect": { "type": "string", "enum": ["none", "colorloop"] },
+                } },
+                "Effect": { "type": "object", "required": ["effect"], "properties": {
+                    "effect": { "type": "string", "enum": crate::effects::NAMES },
+                    "color": { "type": "string" },
+                    "seconds": { "type": "number" },
+                    "duration": { "type": "number", "description": "Seconds until the effect stops" },
+                } },
+                "Recall": { "type": "object", "properties": {
+                    "group": { "type": "string", "description": "Group name or ID, all lights if left out" },
+                } },
+            },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::Topology;
+    use serde_json::json;
+
+    fn server() -> Server {
+        let bridge = Bridge::rehearsal(Topology {
+            bridge: "127.0.0.1".to_owned(),
+            lights: [(
+                1,
+                json!({
+                    "name": "Desk lamp",
+                    "type": "Extended color light",
+                    "state": { "on": true, "bri": 127, "ct": 370, "colormode": "ct" },
+                }),
+            )]
+            .into(),
+            groups: [(
+                1,
+                json!({ "name": "Living", "type": "Room", "lights": ["1"] }),
+            )]
+            .into(),
+            ..Topology::default()
+        });
+        Server::new(bridge, "secret".to_owned())
+    }
+
+    fn request(method: &str, path: &str, token: Option<&str>, body: &str) -> HttpRequest {
+        HttpRequest {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            headers: token
+                .map(|token| ("authorization".to_owned(), format!("Bearer {}", token)))
+                .into_iter()
+                .collect(),
+            body: body.to_owned(),
+        }
+    }
+
+    /// The status the server answers a request with the right token with
+    fn answer(server: &Server, method: &str, path: &str, body: &str) -> u16 {
+        match server.route(&request(method, path, Some("secret"), body)) {
+            Ok((status, _)) => status,
+            Err(e) => e.status,
+        }
+    }
+
+    #[test]
+    fn wants_the_token() {
+        let server = server();
+        let refused = |token| {
+            server
+                .route(&request("GET", "/lights", token, ""))
+                .unwrap_err()
+                .status
+        };
+        assert_eq!(refused(None), 401);
+        assert_eq!(refused(Some("guess")), 401);
+        // the document is for anyone to read
+        let (status, _) = server
+            .route(&request("GET", "/openapi.json", None, ""))
+            .unwrap();
+        assert_eq!(status, 200);
+    }
+
+    #[test]
+    fn routes_requests() {
+        let server = server();
+        let (status, lights) = server
+            .route(&request("GET", "/lights", Some("secret"), ""))
+            .unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(lights[0]["name"], "Desk lamp");
+        assert_eq!(lights[0]["brightness"], 50);
+        assert_eq!(lights[0]["kelvin"], 2702);
+
+        let (_, light) = server
+            .route(&request("GET", "/lights/desk%20LAMP", Some("secret"), ""))
+            .unwrap();
+        assert_eq!(light["id"], 1);
+        let (_, group) = server
+            .route(&request("GET", "/groups/Living", Some("secret"), ""))
+            .unwrap();
+        assert_eq!(group["lights"], json!([1]));
+
+        let (status, sent) = server
+            .route(&request(
+                "PUT",
+                "/lights/1",
+                Some("secret"),
+                r#"{"brightness": 50}"#,
+            ))
+            .unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(sent, json!({ "bri": 127 }));
+        let put = |body| answer(&server, "PUT", "/groups/Living", body);
+        assert_eq!(put(r#"{"brightness": 150}"#), 400);
+        assert_eq!(put("{brightness"), 400);
+    }
+
+    #[test]
+    fn tells_unknown_paths_from_wrong_methods() {
+        let server = server();
+        assert_eq!(answer(&server, "GET", "/lights/Attic", ""), 404);
+        assert_eq!(answer(&server, "GET", "/sensors", ""), 404);
+        assert_eq!(answer(&server, "GET", "/lights/1/effect/extra", ""), 404);
+        assert_eq!(answer(&server, "DELETE", "/lights", ""), 405);
+        assert_eq!(answer(&server, "PATCH", "/lights/1", ""), 405);
+        assert_eq!(answer(&server, "GET", "/scenes/Relax/recall", ""), 405);
+    }
+
+    #[test]
+    fn decodes_names() {
+        assert_eq!(percent_decode("Desk%20lamp"), "Desk lamp");
+        assert_eq!(percent_decode("caf%C3%A9"), "café");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%2"), "%zz%2");
+        assert_eq!(percent_decode("plain"), "plain");
+    }
+
+    #[test]
+    fn refuses_states_out_of_range() {
+        let apply = |json: Value| {
+            serde_json::from_value::<StateRequest>(json)
+                .unwrap()
+                .apply(state!(on: false, bri: 10))
+        };
+        for wrong in [
+            json!({ "brightness": -1 }),
+            json!({ "brightness": 100.5 }),
+            json!({ "alert": "blink" }),
+            json!({ "effect": "disco" }),
+            json!({ "color": "mauve-ish" }),
+        ] {
+            assert!(apply(wrong.clone()).is_err(), "{}", wrong);
+        }
+
+        let state = apply(json!({ "on": true, "brightness": 0, "kelvin": 2700, "transition": -3 }))
+            .unwrap();
+        assert_eq!(state.on, Some(true));
+        // the bridge takes 1 as the dimmest, 0 would be ignored
+        assert_eq!(state.bri, Some(1));
+        assert_eq!(state.ct, Some(370));
+        assert_eq!(state.transitiontime, Some(0));
+        // what the request leaves out stays as it was
+        assert_eq!(apply(json!({})).unwrap().bri, Some(10));
+    }
+
+    #[test]
+    fn documents_every_route() {
+        let document = openapi();
+        assert_eq!(document["openapi"], "3.0.3");
+        for (method, path, summary, body) in ROUTES {
+            let operation = &document["paths"][path][method];
+            assert_eq!(operation["summary"], *summary, "{} {}", method, path);
+            assert_eq!(operation["requestBody"].is_object(), body.is_some());
+            if let Some(body) = body {
+                assert!(document["components"]["schemas"][body].is_object());
+            }
+        }
+        assert_eq!(
+            document["paths"]["/openapi.json"]["get"]["security"],
+            json!([])
+        );
+        assert_eq!(
+            document["paths"]["/lights/{name}"]["put"]["parameters"][0]["name"],
+            "name"
+        );
+    }
+
+    /// A light for the websocket test, on or off
+    fn desk(on: bool) -> Topology {
+        Topology {
+            bridge: "127.0.0.1".to_owned(),
+            lights: [(
+                1,
+                json!({ "name": "Desk", "type": "Extended color light", "state": { "on": on } }),
+            )]
+            .into(),
+            ..Topology::default()
+        }
+    }
+
+    type Socket = tungstenite::WebSocket<tungstenite::stream::MaybeTlsStream<TcpStream>>;
+
+    fn receive(socket: &mut Socket) -> Value {
+        loop {
+            match socket.read().unwrap() {
+                Message::Text(text) => return serde_json::from_str(&text).unwrap(),
+                _ => continue,
+            }
+        }
+    }
+
+    #[test]
+    fn talks_over_websockets() {
+        let mut server = Server::new(Bridge::rehearsal(desk(true)), "se cret".to_owned());
+        server.poll = true;
+        let server: &'static Server = Box::leak(Box::new(server));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("ws://{}/ws", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let _ = server.accept(listener);
+        });
+
+        // the refusal is tungstenite's error, which is a large one
+        #[allow(clippy::result_large_err)]
+        let connect = |request| -> Result<Socket, tungstenite::Error> {
+            let (socket, _) = tungstenite::connect(request)?;
+            if let tungstenite::stream::MaybeTlsStream::Plain(stream) = socket.get_ref() {
+                stream
+                    .set_read_timeout(Some(Duration::from_secs(5)))
+                    .unwrap();
+            }
+            Ok(socket)
+        };
+        use tungstenite::client::IntoClientRequest;
+        let with_token = |token: &str| {
+            let mut request = address.as_str().into_client_request().unwrap();
+            request.headers_mut().insert(
+                "Authorization",
+                format!("Bearer {}", token).parse().unwrap(),
+            );
+            request
+        };
+
+        match connect(
+            format!("{}?token=wrong", address)
+                .into_client_request()
+                .unwrap(),
+        ) {
+            Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 401),
+            other => panic!("connected without the token: {:?}", other.map(|_| ())),
+        }
+        assert!(connect(with_token("wrong")).is_err());
+        assert!(connect(address.as_str().into_client_request().unwrap()).is_err());
+
+        let mut by_query = connect(
+            format!("{}?other=1&token=se%20cret", address)
+                .into_client_request()
+                .unwrap(),
+        )
+        .unwrap();
+        let mut by_header = connect(with_token("se cret")).unwrap();
+
+        by_query
+            .send(Message::Text(
+                r#"{ "light": "desk", "brightness": 100 }"#.to_owned(),
+            ))
+            .unwrap();
+        assert_eq!(receive(&mut by_query), json!({ "ok": { "bri": 254 } }));
+        by_header
+            .send(Message::Text(r#"{ "on": true }"#.to_owned()))
+            .unwrap();
+        assert!(receive(&mut by_header)["error"]
+            .as_str()
+            .unwrap()
+            .contains("either"));
+
+        // give the watcher time to take its first look before the light changes
+        thread::sleep(Duration::from_millis(500));
+        server.bridge().rehearse_with(desk(false));
+        let event = json!({
+            "change": "field",
+            "kind": "light",
+            "id": 1,
+            "field": "state.on",
+            "old": true,
+            "new": false,
+        });
+        assert_eq!(receive(&mut by_query), event);
+        assert_eq!(receive(&mut by_header), event);
+    }
+}
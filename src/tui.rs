@@ -0,0 +1,640 @@
+use crate::bridge::Bridge;
+use crate::color::{mired_to_kelvin, MIRED_RANGE, NAMED};
+use crate::lightstructs::*;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Clear, List, ListItem, ListState, Paragraph};
+use ratatui::Frame;
+use serde_json::value::Value;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+/// How much the arrow keys change the brightness (out of 254) and colour temperature (in mired)
+const BRIGHTNESS_STEP: i32 = 25;
+const MIRED_STEP: i32 = 25;
+
+/// A room and the lights in it. Lights that aren't in any room end up in one
+/// without an ID.
+#[derive(Debug, Clone)]
+struct Room {
+    id: Option<u8>,
+    name: String,
+    lights: Vec<u8>,
+}
+
+/// Everything the interface shows, as fetched from the bridge
+#[derive(Debug, Clone, Default)]
+struct Home {
+    rooms: Vec<Room>,
+    lights: BTreeMap<u8, Value>,
+    scenes: BTreeMap<String, Value>,
+}
+
+impl Home {
+    fn fetch(bridge: &Bridge) -> Result<Self, Box<dyn Error>> {
+        let lights: BTreeMap<u8, Value> = bridge.fetch("lights")?;
+        let groups: BTreeMap<u8, Value> = bridge.fetch("groups")?;
+        let scenes: BTreeMap<String, Value> = bridge.fetch("scenes")?;
+
+        let mut rooms: Vec<Room> = groups
+            .iter()
+            .filter(|(_, group)| group["type"] == "Room")
+            .map(|(id, group)| Room {
+                id: Some(*id),
+                name: group["name"].as_str().unwrap_or_default().to_owned(),
+                lights: light_ids(&group["lights"]),
+            })
+            .collect();
+        let other: Vec<u8> = lights
+            .keys()
+            .filter(|id| !rooms.iter().any(|room| room.lights.contains(id)))
+            .cloned()
+            .collect();
+        if !other.is_empty() {
+            rooms.push(Room {
+                id: None,
+                name: "Not in a room".to_owned(),
+                lights: other,
+            });
+        }
+        Ok(Home {
+            rooms,
+            lights,
+            scenes,
+        })
+    }
+
+    /// The scenes that can be recalled in a room, as IDs and names
+    fn scenes(&self, room: &Room) -> Vec<(String, String)> {
+        let mut scenes: Vec<(String, String)> = self
+            .scenes
+            .iter()
+            .filter(|(_, scene)| {
+                let group = scene["group"].as_str().and_then(|g| g.parse().ok());
+                match group {
+                    Some(group) => room.id == Some(group),
+                    None => {
+                        let lights = light_ids(&scene["lights"]);
+                        !lights.is_empty() && lights.iter().all(|l| room.lights.contains(l))
+                    }
+                }
+            })
+            .map(|(id, scene)| {
+                let name = scene["name"].as_str().unwrap_or(id).to_owned();
+                (id.clone(), name)
+            })
+            .collect();
+        scenes.sort_by(|a, b| a.1.cmp(&b.1));
+        scenes
+    }
+
+    /// Shows a state sent to a light straight away, rather than after the next refresh
+    fn apply(&mut self, light: u8, sent: &SendableState) {
+        let state = match self.lights.get_mut(&light) {
+            Some(light) => &mut light["state"],
+            None => return,
+        };
+        if let Some(on) = sent.on {
+            state["on"] = Value::from(on);
+        }
+        if let Some(bri) = sent.bri {
+            state["bri"] = Value::from(bri);
+        }
+        if let Some(ct) = sent.ct {
+            state["ct"] = Value::from(ct);
+            state["colormode"] = Value::from("ct");
+        }
+        if let Some(xy) = sent.xy {
+            state["xy"] = serde_json::json!(xy);
+            state["colormode"] = Value::from("xy");
+        }
+    }
+}
+
+/// What the interface asks the bridge to do, in the background
+enum Request {
+    Light(u8, SendableState),
+    Room(u8, SendableState),
+    Scene(u8, String),
+    Refresh,
+}
+
+/// What comes back from the background
+enum Update {
+    Home(Home),
+    Failed(String),
+}
+
+/// One line of the list
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Row {
+    Room(usize),
+    Light(usize, u8),
+}
+
+/// A list shown over the others, with the selected entry
+enum Popup {
+    Palette(usize),
+    Scenes(u8, Vec<(String, String)>, usize),
+}
+
+/// A full screen terminal interface listing the rooms and lights with their live
+/// state, to switch, dim and colour them and recall scenes from the keyboard.
+///
+/// ```no_run
+/// # use huemanity::bridge::Bridge;
+/// # use huemanity::tui::Tui;
+/// let bridge = Bridge::link();
+/// Tui::new(&bridge).run().unwrap();
+/// ```
+pub struct Tui<'a> {
+    bridge: &'a Bridge,
+    /// How often the state gets fetched from the bridge
+    pub interval: Duration,
+}
+
+impl<'a> Tui<'a> {
+    pub fn new(bridge: &'a Bridge) -> Self {
+        Tui {
+            bridge,
+            interval: Duration::from_secs(2),
+        }
+    }
+
+    /// Takes over the terminal until `q` is pressed
+    pub fn run(&self) -> Result<(), Box<dyn Error>> {
+        let (requests, pending) = channel();
+        let (updates, incoming) = channel();
+        thread::scope(|scope| {
+            scope.spawn(|| self.background(pending, updates));
+            let mut terminal = ratatui::init();
+            let result = App::new(requests).run(&mut terminal, &incoming);
+            ratatui::restore();
+            // dropping the app's sender lets the background thread finish
+            result
+        })
+    }
+
+    /// Sends what the interface asks for to the bridge, and fetches the state
+    /// after every change and every `interval`
+    fn background(&self, requests: Receiver<Request>, updates: Sender<Update>) {
+        loop {
+            let first = match requests.recv_timeout(self.interval) {
+                Ok(request) => Some(request),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+            // quick changes (like holding an arrow key) only need one fetch at the end
+            for request in first.into_iter().chain(requests.try_iter()) {
+                let result = match request {
+                    Request::Light(id, state) => self.bridge.state(id, &state),
+                    Request::Room(id, state) => self.bridge.group_state(id, &state),
+                    Request::Scene(group, scene) => self.bridge.recall_scene(group, &scene),
+                    Request::Refresh => Ok(()),
+                };
+                if let Err(e) = result {
+                    let _ = updates.send(Update::Failed(e.to_string()));
+                }
+            }
+            let update = match Home::fetch(self.bridge) {
+                Ok(home) => Update::Home(home),
+                Err(e) => Update::Failed(format!("could not refresh: {}", e)),
+            };
+            if updates.send(update).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+struct App {
+    home: Home,
+    rows: Vec<Row>,
+    list: ListState,
+    popup: Option<Popup>,
+    message: Option<String>,
+    requests: Sender<Request>,
+    loaded: bool,
+}
+
+impl App {
+    fn new(requests: Sender<Request>) -> Self {
+        let _ = requests.send(Request::Refresh);
+        App {
+            home: Home::default(),
+            rows: Vec::new(),
+            list: ListState::default(),
+            popup: None,
+            message: None,
+            requests,
+            loaded: false,
+        }
+    }
+
+    fn run(
+        &mut self,
+        terminal: &mut ratatui::DefaultTerminal,
+        updates: &Receiver<Update>,
+    ) -> Result<(), Box<dyn Error>> {
+        loop {
+            for update in updates.try_iter() {
+                match update {
+                    Update::Home(home) => self.show(home),
+                    Update::Failed(e) => self.message = Some(e),
+                }
+            }
+            terminal.draw(|frame| self.draw(frame))?;
+            if event::poll(Duration::from_millis(100))? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press && !self.key(key) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Takes in a fresh state from the bridge, keeping the same row selected
+    fn show(&mut self, home: Home) {
+        let selected = self.selected();
+        self.rows = home
+            .rooms
+            .iter()
+            .enumerate()
+            .flat_map(|(i, room)| {
+                std::iter::once(Row::Room(i))
+                    .chain(room.lights.iter().map(move |light| Row::Light(i, *light)))
+            })
+            .collect();
+        self.home = home;
+        let index = selected
+            .and_then(|row| self.rows.iter().position(|r| *r == row))
+            .unwrap_or(0);
+        self.list.select(if self.rows.is_empty() {
+            None
+        } else {
+            Some(index)
+        });
+        self.loaded = true;
+    }
+
+    fn selected(&self) -> Option<Row> {
+        self.list.selected().and_then(|i| self.rows.get(i).cloned())
+    }
+
+    /// Handles a key press, returns whether to carry on
+    fn key(&mut self, key: KeyEvent) -> bool {
+        if let Some(popup) = &mut self.popup {
+            let (selected, length) = match popup {
+                Popup::Palette(selected) => (selected, NAMED.len()),
+                Popup::Scenes(_, scenes, selected) => (selected, scenes.len()),
+            };
+            match key.code {
+                KeyCode::Up | KeyCode::Char('k') => *selected = selected.saturating_sub(1),
+                KeyCode::Down | KeyCode::Char('j') => {
+                    *selected = (*selected + 1).min(length.saturating_sub(1))
+                }
+                KeyCode::Enter => {
+                    match self.popup.take() {
+                        Some(Popup::Palette(i)) => {
+                            self.send(state!(on: true, xy: NAMED[i].1.xy()));
+                        }
+                        Some(Popup::Scenes(group, scenes, i)) => {
+                            if let Some((id, _)) = scenes.get(i) {
+                                let _ = self.requests.send(Request::Scene(group, id.clone()));
+                            }
+                        }
+                        None => (),
+                    };
+                }
+                KeyCode::Esc | KeyCode::Char('q') => self.popup = None,
+                _ => (),
+            }
+            return true;
+        }
+
+        let shift = key.modifiers.contains(KeyModifiers::SHIFT);
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Up | KeyCode::Char('k') => self.list.select_previous(),
+            KeyCode::Down | KeyCode::Char('j') => self.list.select_next(),
+            KeyCode::Char(' ') | KeyCode::Enter => {
+                let on = self.current("on").is_some_and(|on| on > 0);
+                self.send(state!(on: !on));
+            }
+            KeyCode::Left if shift => self.step_mired(MIRED_STEP),
+            KeyCode::Right if shift => self.step_mired(-MIRED_STEP),
+            KeyCode::Char('[') => self.step_mired(MIRED_STEP),
+            KeyCode::Char(']') => self.step_mired(-MIRED_STEP),
+            KeyCode::Left => self.step_brightness(-BRIGHTNESS_STEP),
+            KeyCode::Right => self.step_brightness(BRIGHTNESS_STEP),
+            KeyCode::Char('c') => self.popup = Some(Popup::Palette(0)),
+            KeyCode::Char('s') => match self.room() {
+                Some(room) if room.id.is_some() => {
+                    let scenes = self.home.scenes(room);
+                    if scenes.is_empty() {
+                        self.message = Some(format!("{} has no scenes", room.name));
+                    } else {
+                        self.popup = Some(Popup::Scenes(room.id.unwrap_or_default(), scenes, 0));
+                    }
+                }
+                _ => self.message = Some("scenes are recalled on a room".to_owned()),
+            },
+            KeyCode::Char('r') => {
+                let _ = self.requests.send(Request::Refresh);
+            }
+            _ => (),
+        }
+        true
+    }
+
+    /// The room of the selected row
+    fn room(&self) -> Option<&Room> {
+        match self.selected()? {
+            Row::Room(i) | Row::Light(i, _) => self.home.rooms.get(i),
+        }
+    }
+
+    /// The lights the selected row stands for
+    fn targets(&self) -> Vec<u8> {
+        match self.selected() {
+            Some(Row::Light(_, light)) => vec![light],
+            Some(Row::Room(_)) => self.room().map(|r| r.lights.clone()).unwrap_or_default(),
+            None => Vec::new(),
+        }
+    }
+
+    /// A state field of the selected light, or its average over the lights that
+    /// are on in the selected room (`on` counts the lights that are on)
+    fn current(&self, field: &str) -> Option<i32> {
+        let states: Vec<&Value> = self
+            .targets()
+            .iter()
+            .filter_map(|id| self.home.lights.get(id))
+            .map(|light| &light["state"])
+            .collect();
+        if field == "on" {
+            return Some(states.iter().filter(|s| s["on"] == true).count() as i32);
+        }
+        let values: Vec<i64> = states
+            .iter()
+            .filter(|s| s["on"] == true || states.len() == 1)
+            .filter_map(|s| s[field].as_i64())
+            .collect();
+        if values.is_empty() {
+            return None;
+        }
+        Some((values.iter().sum::<i64>() / values.len() as i64) as i32)
+    }
+
+    fn step_brightness(&mut self, step: i32) {
+        let bri = self.current("bri").unwrap_or(0);
+        let bri = (bri + step).clamp(1, 254) as u8;
+        self.send(state!(on: true, bri: bri));
+    }
+
+    fn step_mired(&mut self, step: i32) {
+        let ct = match self.current("ct") {
+            Some(ct) => ct,
+            None => {
+                self.message = Some("no colour temperature to change".to_owned());
+                return;
+            }
+        };
+        let ct = (ct + step).clamp(i32::from(MIRED_RANGE.0), i32::from(MIRED_RANGE.1)) as u16;
+        self.send(state!(on: true, ct: ct));
+    }
+
+    /// Sends a state to the selected light or room
+    fn send(&mut self, state: SendableState) {
+        let request = match self.selected() {
+            Some(Row::Light(_, light)) => Request::Light(light, state.clone()),
+            Some(Row::Room(_)) => match self.room().and_then(|room| room.id) {
+                Some(id) => Request::Room(id, state.clone()),
+                None => {
+                    self.message = Some("select a light, these aren't in a room".to_owned());
+                    return;
+                }
+            },
+            None => return,
+        };
+        for light in self.targets() {
+            self.home.apply(light, &state);
+        }
+        self.message = None;
+        let _ = self.requests.send(request);
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, status] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        let [list, details] =
+            Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+                .areas(main);
+
+        let items: Vec<ListItem> = self.rows.iter().map(|row| self.row(*row)).collect();
+        let title = if self.loaded {
+            " Lights "
+        } else {
+            " Lights (loading) "
+        };
+        frame.render_stateful_widget(
+            List::new(items)
+                .block(Block::bordered().title(title))
+                .highlight_style(Style::new().reversed()),
+            list,
+            &mut self.list,
+        );
+        frame.render_widget(
+            Paragraph::new(self.details()).block(Block::bordered().title(" Details ")),
+            details,
+        );
+
+        let status_line = match &self.message {
+            Some(message) => Line::from(message.as_str()).red(),
+            None => Line::from(
+                "↑↓ select  space switch  ←→ brightness  shift ←→ or [ ] warmer/cooler  c colour  s scenes  r refresh  q quit",
+            )
+            .dark_gray(),
+        };
+        frame.render_widget(Paragraph::new(status_line), status);
+
+        if let Some(popup) = &self.popup {
+            let (title, items, selected): (&str, Vec<ListItem>, usize) = match popup {
+                Popup::Palette(selected) => (
+                    " Colour ",
+                    NAMED
+                        .iter()
+                        .map(|(name, rgb)| {
+                            ListItem::new(Line::from(vec![
+                                Span::styled(
+                                    "██ ",
+                                    Style::new().fg(Color::Rgb(rgb.r, rgb.g, rgb.b)),
+                                ),
+                                Span::raw(*name),
+                            ]))
+                        })
+                        .collect(),
+                    *selected,
+                ),
+                Popup::Scenes(_, scenes, selected) => (
+                    " Scene ",
+                    scenes
+                        .iter()
+                        .map(|(_, name)| ListItem::new(name.as_str()))
+                        .collect(),
+                    *selected,
+                ),
+            };
+            let area = centered(frame.area(), 30, items.len() as u16 + 2);
+            let mut state = ListState::default().with_selected(Some(selected));
+            frame.render_widget(Clear, area);
+            frame.render_stateful_widget(
+                List::new(items)
+                    .block(Block::bordered().title(title))
+                    .highlight_style(Style::new().reversed()),
+                area,
+                &mut state,
+            );
+        }
+    }
+
+    fn row(&self, row: Row) -> ListItem<'static> {
+        match row {
+            Row::Room(i) => {
+                let room = &self.home.rooms[i];
+                let on = room
+                    .lights
+                    .iter()
+                    .filter(|id| {
+                        self.home
+                            .lights
+                            .get(id)
+                            .is_some_and(|l| l["state"]["on"] == true)
+                    })
+                    .count();
+                ListItem::new(Line::from(vec![
+                    Span::styled(room.name.clone(), Style::new().add_modifier(Modifier::BOLD)),
+                    Span::raw(format!("  {}/{} on", on, room.lights.len())).dark_gray(),
+                ]))
+            }
+            Row::Light(_, id) => {
+                let light = &self.home.lights[&id];
+                let state = &light["state"];
+                let dot = if state["reachable"] == false {
+                    Span::raw("  ✕ ").red()
+                } else if state["on"] == true {
+                    Span::raw("  ● ").yellow()
+                } else {
+                    Span::raw("  ○ ").dark_gray()
+                };
+                ListItem::new(Line::from(vec![
+                    dot,
+                    Span::raw(format!(
+                        "{:<24}{}",
+                        light["name"].as_str().unwrap_or_default(),
+                        describe(state)
+                    )),
+                ]))
+            }
+        }
+    }
+
+    /// The state of the selected light, or the lights of the selected room
+    fn details(&self) -> Vec<Line<'static>> {
+        let field = |name: &str, value: String| {
+            Line::from(vec![
+                Span::raw(format!("{:<12}", name)).dark_gray(),
+                Span::raw(value),
+            ])
+        };
+        match self.selected() {
+            Some(Row::Light(_, id)) => {
+                let light = &self.home.lights[&id];
+                let state = &light["state"];
+                let mut lines = vec![
+                    field(
+                        "name",
+                        light["name"].as_str().unwrap_or_default().to_owned(),
+                    ),
+                    field("id", id.to_string()),
+                    field(
+                        "type",
+                        light["type"].as_str().unwrap_or_default().to_owned(),
+                    ),
+                    field(
+                        "model",
+                        light["modelid"].as_str().unwrap_or_default().to_owned(),
+                    ),
+                    field("reachable", state["reachable"].to_string()),
+                    field("state", describe(state)),
+                ];
+                if let Some(xy) = state["xy"].as_array() {
+                    lines.push(field("xy", format!("{:?}", xy)));
+                }
+                lines
+            }
+            Some(Row::Room(i)) => {
+                let room = &self.home.rooms[i];
+                let mut lines = vec![
+                    field("room", room.name.clone()),
+                    field(
+                        "id",
+                        room.id.map_or_else(|| "-".to_owned(), |id| id.to_string()),
+                    ),
+                    field("lights", room.lights.len().to_string()),
+                ];
+                if room.id.is_some() {
+                    lines.push(field("scenes", self.home.scenes(room).len().to_string()));
+                }
+                lines
+            }
+            None => vec![Line::from("No lights found")],
+        }
+    }
+}
+
+/// A short description of a light's state, like `80% 2700K`
+fn describe(state: &Value) -> String {
+    if state["on"] != true {
+        return "off".to_owned();
+    }
+    let mut description = match state["bri"].as_u64() {
+        Some(bri) => format!("{:>3}%", (bri * 100 + 127) / 254),
+        None => "on".to_owned(),
+    };
+    match (state["colormode"].as_str(), state["ct"].as_u64()) {
+        (Some("ct"), Some(ct)) => description += &format!("  {}K", mired_to_kelvin(ct as u16)),
+        (Some("xy"), _) | (Some("hs"), _) => description += "  colour",
+        _ => (),
+    }
+    description
+}
+
+/// Light IDs in a list of strings, as groups and scenes have them
+fn light_ids(lights: &Value) -> Vec<u8> {
+    lights
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|id| id.as_str().and_then(|id| id.parse().ok()))
+        .collect()
+}
+
+/// A rectangle of the given size in the middle of `area`
+fn centered(area: Rect, width: u16, height: u16) -> Rect {
+    let width = width.min(area.width);
+    let height = height.min(area.height);
+    Rect::new(
+        area.x + (area.width - width) / 2,
+        area.y + (area.height - height) / 2,
+        width,
+        height,
+    )
+}
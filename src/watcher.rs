@@ -0,0 +1,301 @@
+use crate::bridge::Bridge;
+use serde::*;
+use serde_json::value::Value;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::thread::sleep;
+use std::time::Duration;
+
+/// The kinds of resources the watcher keeps an eye on
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Light,
+    Group,
+    Sensor,
+}
+
+impl Kind {
+    fn endpoint(self) -> &'static str {
+        match self {
+            Kind::Light => "lights",
+            Kind::Group => "groups",
+            Kind::Sensor => "sensors",
+        }
+    }
+
+    /// The parts of each resource that hold its state
+    fn sections(self) -> &'static [&'static str] {
+        match self {
+            Kind::Light => &["state"],
+            Kind::Group => &["state", "action"],
+            Kind::Sensor => &["state", "config"],
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Kind::Light => write!(f, "light"),
+            Kind::Group => write!(f, "group"),
+            Kind::Sensor => write!(f, "sensor"),
+        }
+    }
+}
+
+/// A change noticed between two polls of the bridge
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "change", rename_all = "lowercase")]
+pub enum Change {
+    /// A single field of a resource changed value. The field is named with its
+    /// section, like `state.bri`, and a field that appeared or went away has a
+    /// null on the other side.
+    Field {
+        kind: Kind,
+        id: u8,
+        field: String,
+        old: Value,
+        new: Value,
+    },
+    /// A resource appeared on the bridge
+    Added { kind: Kind, id: u8 },
+    /// A resource disappeared from the bridge
+    Removed { kind: Kind, id: u8 },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Field {
+                kind,
+                id,
+                field,
+                old,
+                new,
+            } => write!(f, "{} {} {} {}→{}", kind, id, field, old, new),
+            Change::Added { kind, id } => write!(f, "{} {} added", kind, id),
+            Change::Removed { kind, id } => write!(f, "{} {} removed", kind, id),
+        }
+    }
+}
+
+/// The state fields of every watched resource at one point in time, keyed by
+/// section and name
+type Snapshot = BTreeMap<(Kind, u8), BTreeMap<String, Value>>;
+
+/// Watches v1 bridges for changes by polling the lights, groups and sensors and
+/// comparing every state field with the previous poll.
+///
+/// ```no_run
+/// # use huemanity::bridge::Bridge;
+/// # use huemanity::watcher::Watcher;
+/// let bridge = Bridge::link();
+/// let mut watcher = Watcher::new(&bridge);
+/// watcher.interval = std::time::Duration::from_secs(2);
+/// watcher.run(|change| println!("{}", change)).unwrap();
+/// ```
+pub struct Watcher<'a> {
+    bridge: &'a Bridge,
+    /// How long to wait between polls
+    pub interval: Duration,
+    /// Give up after this many failed polls in a row
+    pub max_failures: u32,
+    previous: Option<Snapshot>,
+}
+
+impl<'a> Watcher<'a> {
+    pub fn new(bridge: &'a Bridge) -> Self {
+        Watcher {
+            bridge,
+            interval: Duration::from_secs(1),
+            max_failures: 5,
+            previous: None,
+        }
+    }
+
+    /// Fetches the current state from the bridge
+    fn snapshot(&self) -> Result<Snapshot, Box<dyn Error>> {
+        let mut snapshot = Snapshot::new();
+        for kind in [Kind::Light, Kind::Group, Kind::Sensor].iter() {
+            let resources: BTreeMap<u8, Value> = self.bridge.fetch(kind.endpoint())?;
+            for (id, resource) in resources {
+                let mut fields = BTreeMap::new();
+                for section in kind.sections() {
+                    if let Some(object) = resource[*section].as_object() {
+                        for (field, value) in object {
+                            fields.insert(format!("{}.{}", section, field), value.clone());
+                        }
+                    }
+                }
+                snapshot.insert((*kind, id), fields);
+            }
+        }
+        Ok(snapshot)
+    }
+
+    /// Polls the bridge once and returns what changed since the previous poll.
+    /// The first poll only records the state and returns no changes.
+    pub fn poll(&mut self) -> Result<Vec<Change>, Box<dyn Error>> {
+        let current = self.snapshot()?;
+        let changes = match &self.previous {
+            Some(previous) => diff(previous, &current),
+            None => Vec::new(),
+        };
+        self.previous = Some(current);
+        Ok(changes)
+    }
+
+    /// Keeps polling and hands every change to `on_change`. Only returns once
+    /// `max_failures` polls in a row have failed, with the last error.
+    pub fn run<F: FnMut(Change)>(&mut self, mut on_change: F) -> Result<(), Box<dyn Error>> {
+        let mut failures = 0;
+        loop {
+            match self.poll() {
+                Ok(changes) => {
+                    failures = 0;
+                    changes.into_iter().for_each(&mut on_change);
+                }
+                Err(e) => {
+                    failures += 1;
+                    if failures >= self.max_failures {
+                        return Err(e);
+                    }
+                }
+            }
+            sleep(self.interval);
+        }
+    }
+}
+
+/// Compares two snapshots field by field
+fn diff(previous: &Snapshot, current: &Snapshot) -> Vec<Change> {
+    let mut changes = Vec::new();
+    for (&(kind, id), fields) in current {
+        let old_fields = match previous.get(&(kind, id)) {
+            Some(old_fields) => old_fields,
+            None => {
+                changes.push(Change::Added { kind, id });
+                continue;
+            }
+        };
+        let gone = old_fields
+            .keys()
+            .filter(|field| !fields.contains_key(*field));
+        for field in fields.keys().chain(gone) {
+            let old = old_fields.get(field).unwrap_or(&Value::Null);
+            let new = fields.get(field).unwrap_or(&Value::Null);
+            if old != new {
+                changes.push(Change::Field {
+                    kind,
+                    id,
+                    field: field.to_owned(),
+                    old: old.clone(),
+                    new: new.clone(),
+                });
+            }
+        }
+    }
+    for &(kind, id) in previous.keys() {
+        if !current.contains_key(&(kind, id)) {
+            changes.push(Change::Removed { kind, id });
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn snapshot(resources: &[((Kind, u8), Value)]) -> Snapshot {
+        resources
+            .iter()
+            .map(|(key, fields)| {
+                (
+                    *key,
+                    fields.as_object().unwrap().clone().into_iter().collect(),
+                )
+            })
+            .collect()
+    }
+
+    fn field(kind: Kind, id: u8, field: &str, old: Value, new: Value) -> Change {
+        Change::Field {
+            kind,
+            id,
+            field: field.to_owned(),
+            old,
+            new,
+        }
+    }
+
+    #[test]
+    fn keeps_sections_apart() {
+        let previous = snapshot(&[(
+            (Kind::Group, 1),
+            json!({"state.on": false, "action.on": false}),
+        )]);
+        let current = snapshot(&[(
+            (Kind::Group, 1),
+            json!({"state.on": false, "action.on": true}),
+        )]);
+        assert_eq!(
+            diff(&previous, &current),
+            [field(
+                Kind::Group,
+                1,
+                "action.on",
+                json!(false),
+                json!(true)
+            )]
+        );
+    }
+
+    #[test]
+    fn reports_fields_that_come_and_go() {
+        let previous = snapshot(&[(
+            (Kind::Sensor, 4),
+            json!({"state.presence": true, "config.battery": 80}),
+        )]);
+        let current = snapshot(&[(
+            (Kind::Sensor, 4),
+            json!({"state.presence": true, "config.reachable": true}),
+        )]);
+        assert_eq!(
+            diff(&previous, &current),
+            [
+                field(
+                    Kind::Sensor,
+                    4,
+                    "config.reachable",
+                    Value::Null,
+                    json!(true)
+                ),
+                field(Kind::Sensor, 4, "config.battery", json!(80), Value::Null),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_resources_that_come_and_go() {
+        let previous = snapshot(&[((Kind::Light, 1), json!({"state.on": true}))]);
+        let current = snapshot(&[((Kind::Light, 2), json!({"state.on": true}))]);
+        assert_eq!(
+            diff(&previous, &current),
+            [
+                Change::Added {
+                    kind: Kind::Light,
+                    id: 2
+                },
+                Change::Removed {
+                    kind: Kind::Light,
+                    id: 1
+                },
+            ]
+        );
+        assert!(diff(&current, &current).is_empty());
+    }
+}